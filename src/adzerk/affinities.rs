@@ -0,0 +1,89 @@
+use super::defaults;
+use crate::{errors::ProxyError, reload::content_hash};
+use std::{collections::HashMap, fs, path::Path, sync::Arc};
+
+/// Domains a client must have visited for spocs to be relevant, with their
+/// weight.
+pub type AffinitySet = HashMap<String, u32>;
+
+/// Named sets of domain affinities, which creatives refer to by name.
+#[derive(Clone, Debug)]
+pub struct DomainAffinities {
+    sets: HashMap<String, Arc<AffinitySet>>,
+    hash: String,
+}
+
+impl Default for DomainAffinities {
+    fn default() -> Self {
+        defaults::DOMAIN_AFFINITIES.clone()
+    }
+}
+
+impl DomainAffinities {
+    /// Load and validate domain affinities from a JSON file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, ProxyError> {
+        Self::from_json(&fs::read_to_string(path)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, ProxyError> {
+        let sets: HashMap<String, AffinitySet> = serde_json::from_str(json)?;
+        for (name, set) in &sets {
+            if name.is_empty() || set.keys().any(|domain| domain.is_empty()) {
+                return Err(ProxyError::new(format!(
+                    "Invalid domain affinity set '{}'",
+                    name
+                )));
+            }
+        }
+        Ok(Self {
+            sets: sets
+                .into_iter()
+                .map(|(name, set)| (name, Arc::new(set)))
+                .collect(),
+            hash: content_hash(json.as_bytes()),
+        })
+    }
+
+    /// The set with a name, or an empty set.
+    pub fn get(&self, name: Option<&str>) -> Arc<AffinitySet> {
+        name.and_then(|name| self.sets.get(name))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// SHA-256 of the domain affinities file.
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DomainAffinities;
+
+    #[test]
+    fn test_get() {
+        let affinities =
+            DomainAffinities::from_json(r#"{"publishers": {"example.com": 1}}"#).unwrap();
+        assert_eq!(affinities.get(Some("publishers"))["example.com"], 1);
+        assert!(affinities.get(Some("travel")).is_empty());
+        assert!(affinities.get(None).is_empty());
+    }
+
+    #[test]
+    fn test_invalid_affinities() {
+        let invalid_affinities = [
+            r#"{"publishers": ["example.com"]}"#,
+            r#"{"publishers": {"example.com": -1}}"#,
+            r#"{"publishers": {"": 1}}"#,
+            r#"{"": {"example.com": 1}}"#,
+        ];
+        for json in invalid_affinities {
+            assert!(
+                DomainAffinities::from_json(json).is_err(),
+                "{} should be rejected",
+                json
+            );
+        }
+    }
+}
@@ -0,0 +1,195 @@
+use crate::errors::ProxyError;
+use serde::{Deserialize, Serialize};
+
+/// Largest impression count accepted in a frequency cap.
+const MAX_COUNT: u32 = 1000;
+/// Longest period accepted in a frequency cap: a year, in seconds.
+const MAX_PERIOD: u32 = 365 * 86400;
+
+/// Frequency caps enforced by the client for a spoc.
+#[derive(Clone, Copy, Debug, Serialize, PartialEq, Eq)]
+pub struct Caps {
+    /// Maximum number of impressions of the flight, ever.
+    pub lifetime: u32,
+    pub campaign: Cap,
+    pub flight: Cap,
+}
+
+/// At most `count` impressions every `period` seconds.
+#[derive(Clone, Copy, Debug, Serialize, PartialEq, Eq)]
+pub struct Cap {
+    pub count: u32,
+    pub period: u32,
+}
+
+/// Caps that replace some of the default caps. Missing values are inherited.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct CapsOverride {
+    lifetime: Option<u32>,
+    #[serde(default)]
+    campaign: CapOverride,
+    #[serde(default)]
+    flight: CapOverride,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct CapOverride {
+    count: Option<u32>,
+    period: Option<u32>,
+}
+
+/// Cap overrides of a creative, as strings from Kevel custom data.
+#[derive(Debug, Default)]
+pub struct CreativeCaps {
+    pub lifetime: Option<String>,
+    pub campaign_count: Option<String>,
+    pub campaign_period: Option<String>,
+    pub flight_count: Option<String>,
+    pub flight_period: Option<String>,
+}
+
+impl CapsOverride {
+    pub fn validate(&self) -> Result<(), ProxyError> {
+        let counts = [self.lifetime, self.campaign.count, self.flight.count];
+        let periods = [self.campaign.period, self.flight.period];
+        if counts.iter().flatten().any(|&c| !valid(c, MAX_COUNT))
+            || periods.iter().flatten().any(|&p| !valid(p, MAX_PERIOD))
+        {
+            return Err(ProxyError::new(format!(
+                "Caps must have counts from 1 to {} and periods from 1 to {} seconds",
+                MAX_COUNT, MAX_PERIOD
+            )));
+        }
+        Ok(())
+    }
+
+    /// Parse the cap overrides of a creative. Values that are not valid are
+    /// left out, and their number is returned with the overrides.
+    pub fn from_creative(caps: CreativeCaps) -> (Self, usize) {
+        let mut invalid = 0;
+        let mut parse = |value: Option<String>, max: u32| {
+            let value = value?;
+            match value.trim().parse() {
+                Ok(value) if valid(value, max) => Some(value),
+                _ => {
+                    invalid += 1;
+                    None
+                }
+            }
+        };
+        let overrides = CapsOverride {
+            lifetime: parse(caps.lifetime, MAX_COUNT),
+            campaign: CapOverride {
+                count: parse(caps.campaign_count, MAX_COUNT),
+                period: parse(caps.campaign_period, MAX_PERIOD),
+            },
+            flight: CapOverride {
+                count: parse(caps.flight_count, MAX_COUNT),
+                period: parse(caps.flight_period, MAX_PERIOD),
+            },
+        };
+        (overrides, invalid)
+    }
+
+    /// The caps with these overrides applied.
+    pub fn apply(&self, caps: Caps) -> Caps {
+        Caps {
+            lifetime: self.lifetime.unwrap_or(caps.lifetime),
+            campaign: self.campaign.apply(caps.campaign),
+            flight: self.flight.apply(caps.flight),
+        }
+    }
+}
+
+impl CapOverride {
+    fn apply(&self, cap: Cap) -> Cap {
+        Cap {
+            count: self.count.unwrap_or(cap.count),
+            period: self.period.unwrap_or(cap.period),
+        }
+    }
+}
+
+fn valid(value: u32, max: u32) -> bool {
+    (1..=max).contains(&value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cap, Caps, CapsOverride, CreativeCaps};
+    use crate::adzerk::defaults;
+    use serde_json::{from_value, json};
+
+    #[test]
+    fn test_overrides_are_merged() {
+        let placement: CapsOverride = from_value(json!({
+            "lifetime": 20,
+            "flight": {"count": 3}
+        }))
+        .unwrap();
+        let (creative, invalid) = CapsOverride::from_creative(CreativeCaps {
+            flight_count: Some("5".to_owned()),
+            flight_period: Some(" 3600 ".to_owned()),
+            ..CreativeCaps::default()
+        });
+        assert_eq!(invalid, 0);
+        assert_eq!(
+            creative.apply(placement.apply(defaults::CAPS)),
+            Caps {
+                lifetime: 20,
+                campaign: Cap {
+                    count: 10,
+                    period: 86400
+                },
+                flight: Cap {
+                    count: 5,
+                    period: 3600
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn test_invalid_creative_caps_are_ignored() {
+        let (creative, invalid) = CapsOverride::from_creative(CreativeCaps {
+            lifetime: Some("lots".to_owned()),
+            campaign_count: Some("0".to_owned()),
+            campaign_period: Some("-1".to_owned()),
+            flight_count: Some("2".to_owned()),
+            flight_period: Some("100000000".to_owned()),
+        });
+        assert_eq!(invalid, 4);
+        assert_eq!(
+            creative.apply(defaults::CAPS),
+            Caps {
+                flight: Cap {
+                    count: 2,
+                    period: 86400
+                },
+                ..defaults::CAPS
+            }
+        );
+    }
+
+    #[test]
+    fn test_invalid_overrides() {
+        let invalid_overrides = [
+            json!({"lifetime": 0}),
+            json!({"campaign": {"count": 1001}}),
+            json!({"flight": {"period": 0}}),
+        ];
+        for overrides in invalid_overrides {
+            assert!(
+                from_value::<CapsOverride>(overrides.clone())
+                    .unwrap()
+                    .validate()
+                    .is_err(),
+                "{} should be rejected",
+                overrides
+            );
+        }
+        assert!(from_value::<CapsOverride>(json!({"impressions": 1})).is_err());
+    }
+}
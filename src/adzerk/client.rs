@@ -2,9 +2,10 @@ use std::{collections::HashMap, time::Duration};
 
 use actix_web::http::StatusCode;
 use awc::Client;
+use serde_json::Value;
 
 use crate::{
-    endpoints::spocs::{SpocsRequest, SpocsResponse},
+    endpoints::spocs::{ResponseOptions, SpocsResponse},
     errors::ProxyError,
 };
 
@@ -36,10 +37,10 @@ impl AdzerkClient {
         self
     }
 
-    pub async fn delete_user(&self, pocket_id: &str) -> Result<StatusCode, ProxyError> {
-        let user_key = UserKey {
-            user_key: pocket_id,
-        };
+    /// Delete a user from Kevel's UserDB. The user key must already be
+    /// pseudonymized.
+    pub async fn delete_user(&self, user_key: &str) -> Result<StatusCode, ProxyError> {
+        let user_key = UserKey { user_key };
         let status = self
             .http_client
             .delete(format!("{}/udb/{}/", self.base_url, defaults::NETWORK_ID))
@@ -52,12 +53,57 @@ impl AdzerkClient {
         Ok(status)
     }
 
+    /// Read a user's record from Kevel's UserDB, if there is one. The user key
+    /// must already be pseudonymized.
+    pub async fn read_user(&self, user_key: &str) -> Result<Option<Value>, ProxyError> {
+        let user_key = UserKey { user_key };
+        let mut http_response = self
+            .http_client
+            .get(format!(
+                "{}/udb/{}/read",
+                self.base_url,
+                defaults::NETWORK_ID
+            ))
+            .insert_header(("X-Adzerk-ApiKey", self.adzerk_api_key.as_str()))
+            .query(&user_key)
+            .unwrap()
+            .send()
+            .await?;
+        match http_response.status() {
+            StatusCode::OK => Ok(Some(http_response.json().await?)),
+            StatusCode::NOT_FOUND => Ok(None),
+            status => Err(ProxyError::new(format!(
+                "Kevel UserDB read failed with status {}",
+                status
+            ))),
+        }
+    }
+
+    /// Opt a user out of tracking in Kevel's UserDB. The user key must already
+    /// be pseudonymized.
+    pub async fn opt_out_user(&self, user_key: &str) -> Result<StatusCode, ProxyError> {
+        let user_key = UserKey { user_key };
+        let status = self
+            .http_client
+            .get(format!(
+                "{}/udb/{}/optout/i.gif",
+                self.base_url,
+                defaults::NETWORK_ID
+            ))
+            .insert_header(("X-Adzerk-ApiKey", self.adzerk_api_key.as_str()))
+            .query(&user_key)
+            .unwrap()
+            .send()
+            .await?
+            .status();
+        Ok(status)
+    }
+
     pub async fn get_decisions(
         &self,
-        spocs_request: SpocsRequest,
+        decision_request: DecisionRequest,
+        response_options: &ResponseOptions,
     ) -> Result<SpocsResponse, ProxyError> {
-        let version = spocs_request.version;
-        let decision_request = DecisionRequest::from(spocs_request);
         let mut http_response = self
             .http_client
             .post(format!("{}/api/v2", self.base_url))
@@ -72,6 +118,6 @@ impl AdzerkClient {
         } else {
             http_response.json::<DecisionResponse>().await?
         };
-        SpocsResponse::from_decision_response(decision_response, version)
+        SpocsResponse::from_decision_response(decision_response, response_options)
     }
 }
@@ -0,0 +1,168 @@
+use super::defaults;
+use crate::{errors::ProxyError, reload::content_hash};
+use serde::Deserialize;
+use serde_json::Value;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::Path,
+    sync::Arc,
+};
+
+/// The `settings` sent to clients with spocs.
+///
+/// Settings are validated against [`Schema`] when loaded, but sent as they
+/// are, so new keys can be added without changing the proxy.
+#[derive(Clone, Debug)]
+pub struct ClientSettings {
+    value: Arc<Value>,
+    parameter_sets: HashSet<String>,
+    hash: String,
+}
+
+/// The settings clients rely on.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Schema {
+    #[serde(rename = "feature_flags")]
+    _feature_flags: HashMap<String, bool>,
+    #[serde(rename = "spocsPerNewTabs")]
+    _spocs_per_new_tabs: u32,
+    domain_affinity_parameter_sets: HashMap<String, HashMap<String, f64>>,
+    time_segments: Vec<TimeSegment>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TimeSegment {
+    id: String,
+    start_time: u64,
+    end_time: u64,
+    #[serde(rename = "weightPosition")]
+    _weight_position: f64,
+}
+
+impl Default for ClientSettings {
+    fn default() -> Self {
+        defaults::SETTINGS.clone()
+    }
+}
+
+impl ClientSettings {
+    /// Load and validate settings from a JSON file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, ProxyError> {
+        Self::from_json(&fs::read_to_string(path)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, ProxyError> {
+        Self::new(serde_json::from_str(json)?, content_hash(json.as_bytes()))
+    }
+
+    /// Validate settings derived from others, e.g. with overlays applied.
+    pub fn from_value(value: Value) -> Result<Self, ProxyError> {
+        let hash = content_hash(value.to_string().as_bytes());
+        Self::new(value, hash)
+    }
+
+    fn new(value: Value, hash: String) -> Result<Self, ProxyError> {
+        let schema = Self::schema(&value)?;
+        Ok(Self {
+            parameter_sets: schema.domain_affinity_parameter_sets.into_keys().collect(),
+            value: Arc::new(value),
+            hash,
+        })
+    }
+
+    /// Validate settings against the schema.
+    fn schema(value: &Value) -> Result<Schema, ProxyError> {
+        let schema: Schema = serde_json::from_value(value.clone())
+            .map_err(|err| ProxyError::new(format!("Invalid settings: {}", err)))?;
+        if !schema
+            .domain_affinity_parameter_sets
+            .contains_key(defaults::PARAMETER_SET)
+        {
+            return Err(ProxyError::new(format!(
+                "Settings must have the '{}' domain affinity parameter set",
+                defaults::PARAMETER_SET
+            )));
+        }
+        let mut segments = HashSet::new();
+        for segment in &schema.time_segments {
+            if segment.end_time > segment.start_time || !segments.insert(&segment.id) {
+                return Err(ProxyError::new(format!(
+                    "Invalid time segment '{}'",
+                    segment.id
+                )));
+            }
+        }
+        Ok(schema)
+    }
+
+    pub fn value(&self) -> Arc<Value> {
+        Arc::clone(&self.value)
+    }
+
+    pub fn has_parameter_set(&self, name: &str) -> bool {
+        self.parameter_sets.contains(name)
+    }
+
+    /// SHA-256 of the settings file, or of the settings derived from it.
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ClientSettings;
+    use serde_json::{json, Value};
+
+    fn settings() -> Value {
+        json!({
+            "feature_flags": {"spoc_v2": true},
+            "spocsPerNewTabs": 1,
+            "domainAffinityParameterSets": {"default": {"recencyFactor": 0.5}},
+            "timeSegments": [{"id": "week-1", "startTime": 432000, "endTime": 0, "weightPosition": 1}],
+            "newSetting": "passed through"
+        })
+    }
+
+    #[test]
+    fn test_settings_are_sent_as_is() {
+        let json = settings().to_string();
+        let settings = ClientSettings::from_json(&json).unwrap();
+        assert_eq!(*settings.value(), self::settings());
+        assert!(settings.has_parameter_set("default"));
+        assert!(!settings.has_parameter_set("fully-personalized"));
+        assert_eq!(settings.hash().len(), 64);
+        assert_ne!(settings.hash(), ClientSettings::default().hash());
+    }
+
+    #[test]
+    fn test_invalid_settings() {
+        let invalid_changes = [
+            ("spocsPerNewTabs", json!("one")),
+            ("feature_flags", json!({"spoc_v2": "yes"})),
+            (
+                "domainAffinityParameterSets",
+                json!({"other": {"recencyFactor": 0.5}}),
+            ),
+            (
+                "timeSegments",
+                json!([{"id": "week-1", "startTime": 0, "endTime": 432000, "weightPosition": 1}]),
+            ),
+        ];
+        for (key, value) in invalid_changes {
+            let mut settings = settings();
+            settings[key] = value;
+            assert!(
+                ClientSettings::from_json(&settings.to_string()).is_err(),
+                "{} should be rejected",
+                settings
+            );
+        }
+        let mut settings = settings();
+        settings.as_object_mut().unwrap().remove("timeSegments");
+        assert!(ClientSettings::from_json(&settings.to_string()).is_err());
+    }
+}
@@ -0,0 +1,269 @@
+use crate::{endpoints::spocs::Spoc, errors::ProxyError};
+use serde::Deserialize;
+
+/// How the items of a sponsored collection are recognized in a div.
+///
+/// Spocs with a collection title are grouped, and the first group, in ranked
+/// order, that is consistent and has enough items becomes the div's
+/// collection. The div's other spocs, including items beyond `max_items`, are
+/// sent along with the collection as a standard list. If no group qualifies,
+/// the div gets a standard list of all its spocs instead.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct CollectionRules {
+    #[serde(default = "default_group_by")]
+    group_by: GroupBy,
+    /// Fewest items of a collection.
+    #[serde(default = "default_min_items")]
+    min_items: usize,
+    /// Most items of a collection. Further items join the other spocs of the
+    /// div.
+    max_items: Option<usize>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum GroupBy {
+    /// Items of a collection belong to the same flight.
+    Flight,
+    /// Items of a collection have the same collection title.
+    Title,
+}
+
+fn default_group_by() -> GroupBy {
+    GroupBy::Title
+}
+
+fn default_min_items() -> usize {
+    1
+}
+
+impl Default for CollectionRules {
+    fn default() -> Self {
+        Self {
+            group_by: default_group_by(),
+            min_items: default_min_items(),
+            max_items: None,
+        }
+    }
+}
+
+/// What happened while assembling collections, for metrics.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CollectionStats {
+    /// Number of divs that got a collection.
+    pub assembled: usize,
+    /// Number of divs with collection items that got a standard list, because
+    /// no group was usable.
+    pub fallbacks: usize,
+    /// Number of groups whose items disagree on the title or sponsor.
+    pub inconsistent: usize,
+    /// Number of spocs sent as a standard list along with a collection.
+    pub remainder: usize,
+}
+
+impl CollectionRules {
+    pub fn validate(&self) -> Result<(), ProxyError> {
+        if self.min_items == 0 || self.max_items.map_or(false, |max| max < self.min_items) {
+            return Err(ProxyError::new(
+                "Collections must allow at least one item, and no fewer than min_items",
+            ));
+        }
+        Ok(())
+    }
+
+    /// The indices of the spocs that make up the div's collection, in ranked
+    /// order, or `None` if the div should get a standard list.
+    pub fn select(&self, spocs: &[Spoc], stats: &mut CollectionStats) -> Option<Vec<usize>> {
+        let mut groups: Vec<Vec<usize>> = Vec::new();
+        for (i, spoc) in spocs.iter().enumerate() {
+            if spoc.collection_title.is_none() {
+                continue;
+            }
+            match groups
+                .iter_mut()
+                .find(|group| self.same_group(&spocs[group[0]], spoc))
+            {
+                Some(group) => group.push(i),
+                None => groups.push(vec![i]),
+            }
+        }
+        if groups.is_empty() {
+            return None;
+        }
+
+        let mut selected = None;
+        for group in groups {
+            let first = &spocs[group[0]];
+            let consistent = group.iter().all(|&i| {
+                spocs[i].collection_title == first.collection_title
+                    && spocs[i].sponsor == first.sponsor
+            });
+            if !consistent {
+                stats.inconsistent += 1;
+            } else if group.len() >= self.min_items && selected.is_none() {
+                selected = Some(group);
+            }
+        }
+        match selected {
+            Some(mut group) => {
+                group.truncate(self.max_items.unwrap_or(usize::MAX));
+                stats.assembled += 1;
+                stats.remainder += spocs.len() - group.len();
+                Some(group)
+            }
+            None => {
+                stats.fallbacks += 1;
+                None
+            }
+        }
+    }
+
+    fn same_group(&self, a: &Spoc, b: &Spoc) -> bool {
+        match self.group_by {
+            GroupBy::Flight => a.flight_id == b.flight_id,
+            GroupBy::Title => a.collection_title == b.collection_title,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CollectionRules, CollectionStats};
+    use crate::{
+        adzerk::defaults,
+        endpoints::spocs::{Shim, Spoc},
+    };
+    use serde_json::{from_value, json};
+    use std::{collections::HashMap, sync::Arc};
+
+    fn spoc(id: u32, flight_id: u32, collection_title: Option<&str>, sponsor: &str) -> Spoc {
+        Spoc {
+            id,
+            flight_id,
+            campaign_id: 1,
+            title: String::new(),
+            url: String::new(),
+            domain: String::new(),
+            excerpt: String::new(),
+            priority: 1,
+            context: String::new(),
+            raw_image_src: String::new(),
+            image_src: String::new(),
+            shim: Shim {
+                click: String::new(),
+                impression: String::new(),
+                delete: String::new(),
+                save: String::new(),
+            },
+            parameter_set: defaults::PARAMETER_SET.to_owned(),
+            caps: defaults::CAPS,
+            domain_affinities: Arc::default(),
+            personalization_models: HashMap::new(),
+            min_score: 0.1,
+            item_score: 0.2,
+            cta: None,
+            collection_title: collection_title.map(str::to_owned),
+            sponsor: Some(sponsor.to_owned()),
+            sponsored_by_override: None,
+            is_video: None,
+        }
+    }
+
+    fn rules(rules: serde_json::Value) -> CollectionRules {
+        let rules: CollectionRules = from_value(rules).unwrap();
+        rules.validate().unwrap();
+        rules
+    }
+
+    #[test]
+    fn test_standard_spocs_are_kept() {
+        let spocs = vec![
+            spoc(1, 10, None, "Acme"),
+            spoc(2, 20, Some("Best of the Web"), "Acme"),
+            spoc(3, 20, Some("Best of the Web"), "Acme"),
+            spoc(4, 30, None, "Acme"),
+        ];
+        let mut stats = CollectionStats::default();
+        let selected = CollectionRules::default().select(&spocs, &mut stats);
+        assert_eq!(selected, Some(vec![1, 2]));
+        assert_eq!(
+            stats,
+            CollectionStats {
+                assembled: 1,
+                remainder: 2,
+                ..CollectionStats::default()
+            },
+            "the standard spocs are the remainder"
+        );
+
+        let spocs = vec![spoc(1, 10, None, "Acme")];
+        let mut stats = CollectionStats::default();
+        assert_eq!(CollectionRules::default().select(&spocs, &mut stats), None);
+        assert_eq!(stats, CollectionStats::default(), "no collection items");
+    }
+
+    #[test]
+    fn test_item_counts() {
+        let spocs = vec![
+            spoc(1, 20, Some("Small"), "Acme"),
+            spoc(2, 30, Some("Large"), "Initech"),
+            spoc(3, 30, Some("Large"), "Initech"),
+            spoc(4, 30, Some("Large"), "Initech"),
+        ];
+        let mut stats = CollectionStats::default();
+        let rules = rules(json!({"min_items": 2, "max_items": 2}));
+        assert_eq!(rules.select(&spocs, &mut stats), Some(vec![1, 2]));
+        assert_eq!(stats.remainder, 2, "spoc 1 and the item beyond max_items");
+
+        let mut stats = CollectionStats::default();
+        let rules = self::rules(json!({"min_items": 4}));
+        assert_eq!(rules.select(&spocs, &mut stats), None);
+        assert_eq!(stats.fallbacks, 1);
+    }
+
+    #[test]
+    fn test_consistency() {
+        let spocs = vec![
+            spoc(1, 20, Some("Best of the Web"), "Acme"),
+            spoc(2, 20, Some("Best of the Web"), "Initech"),
+            spoc(3, 30, Some("Best of the Web"), "Acme"),
+            spoc(4, 40, Some("Gift Guide"), "Acme"),
+            spoc(5, 40, Some("Holiday Guide"), "Acme"),
+        ];
+        // By title, the first group has items from several sponsors.
+        let mut stats = CollectionStats::default();
+        let selected = CollectionRules::default().select(&spocs, &mut stats);
+        assert_eq!(selected, Some(vec![3]));
+        assert_eq!(stats.inconsistent, 1);
+
+        let mut stats = CollectionStats::default();
+        let selected = rules(json!({"group_by": "flight"})).select(&spocs, &mut stats);
+        assert_eq!(selected, Some(vec![2]), "flights 20 and 40 disagree");
+        assert_eq!(stats.inconsistent, 2);
+
+        // Several flights with the same title and sponsor form one collection
+        // by title, but not by flight.
+        let spocs = [
+            spoc(3, 30, Some("Best of the Web"), "Acme"),
+            spoc(6, 50, Some("Best of the Web"), "Acme"),
+        ];
+        let mut stats = CollectionStats::default();
+        let selected = CollectionRules::default().select(&spocs, &mut stats);
+        assert_eq!(selected, Some(vec![0, 1]));
+        let selected = rules(json!({"group_by": "flight"})).select(&spocs, &mut stats);
+        assert_eq!(selected, Some(vec![0]));
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        for rules in [
+            json!({"min_items": 0}),
+            json!({"min_items": 3, "max_items": 2}),
+        ] {
+            let rules: CollectionRules = from_value(rules).unwrap();
+            assert!(rules.validate().is_err());
+        }
+        assert!(from_value::<CollectionRules>(json!({"group_by": "sponsor"})).is_err());
+    }
+}
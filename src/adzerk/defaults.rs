@@ -1,12 +1,34 @@
-use super::request_models::Placement;
+use super::{
+    affinities::DomainAffinities,
+    caps::{Cap, Caps},
+    client_settings::ClientSettings,
+    keywords::KeywordTemplates,
+    priorities::PriorityMap,
+    request_models::Placement,
+    topics::TopicTaxonomy,
+};
 use lazy_static::lazy_static;
-use serde_json::{from_str, from_value, json, Value};
-use std::collections::HashMap;
+use serde_json::from_str;
 
 pub const NETWORK_ID: u32 = 10250;
 
 pub const PRIORITY: u32 = 100;
 
+/// Domain affinity parameter set of spocs that don't choose one.
+pub const PARAMETER_SET: &str = "default";
+
+pub const CAPS: Caps = Caps {
+    lifetime: 50,
+    campaign: Cap {
+        count: 10,
+        period: 86400,
+    },
+    flight: Cap {
+        count: 10,
+        period: 86400,
+    },
+};
+
 lazy_static! {
     pub static ref BASE_URL: String = format!("https://e-{0}.adzerk.net", NETWORK_ID);
     pub static ref PLACEMENT: Placement = Placement {
@@ -18,38 +40,34 @@ lazy_static! {
         count: 10,
         event_ids: [17, 20],
     };
-    pub static ref CAPS: Value = json!({
-        "lifetime": 50,
-        "campaign": {
-            "count": 10,
-            "period": 86400,
-        },
-        "flight": {
-            "count": 10,
-            "period": 86400,
-        },
-    });
-    pub static ref SETTINGS: Value = from_str(include_str!("settings.json")).unwrap();
-    pub static ref DOMAIN_AFFINITIES: HashMap<String, HashMap<String, u32>> =
-        from_str(include_str!("domain_affinities.json")).unwrap();
-    pub static ref EMPTY_DOMAIN_AFFINITIES: HashMap<String, u32> = HashMap::new();
-    pub static ref TEST_DOMAIN_AFFINITIES: HashMap<String, HashMap<String, u32>> =
-        from_value(json!({
-            "publishers": {
-                "example.com": 1
-            }
-        })).unwrap();
+    pub static ref SETTINGS: ClientSettings =
+        ClientSettings::from_json(include_str!("settings.json")).unwrap();
+    pub static ref DOMAIN_AFFINITIES: DomainAffinities =
+        DomainAffinities::from_json(include_str!("domain_affinities.json")).unwrap();
+    pub static ref KEYWORD_TEMPLATES: KeywordTemplates =
+        from_str(include_str!("keyword_templates.json")).unwrap();
+    pub static ref TOPIC_TAXONOMY: TopicTaxonomy =
+        from_str(include_str!("topic_taxonomy.json")).unwrap();
+    pub static ref PRIORITY_MAP: PriorityMap = from_str(include_str!("priorities.json")).unwrap();
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{DOMAIN_AFFINITIES, SETTINGS};
-    use serde_json::Value;
-    use std::collections::HashMap;
+    use super::{
+        DOMAIN_AFFINITIES, KEYWORD_TEMPLATES, PARAMETER_SET, PRIORITY_MAP, SETTINGS, TOPIC_TAXONOMY,
+    };
+    use crate::adzerk::{
+        affinities::DomainAffinities, client_settings::ClientSettings, keywords::KeywordTemplates,
+        priorities::PriorityMap, topics::TopicTaxonomy,
+    };
 
     #[test]
     fn test_parse_json_files() {
-        let _: &Value = &SETTINGS;
-        let _: &HashMap<_, _> = &DOMAIN_AFFINITIES;
+        let _: &ClientSettings = &SETTINGS;
+        let _: &DomainAffinities = &DOMAIN_AFFINITIES;
+        let _: &KeywordTemplates = &KEYWORD_TEMPLATES;
+        let _: &TopicTaxonomy = &TOPIC_TAXONOMY;
+        let _: &PriorityMap = &PRIORITY_MAP;
+        assert!(SETTINGS.has_parameter_set(PARAMETER_SET));
     }
 }
@@ -0,0 +1,332 @@
+use crate::{endpoints::spocs::Spoc, errors::ProxyError};
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::Path,
+};
+
+/// Rules applied to the spocs of all divs of a response together, so the same
+/// ad, flight, sponsor or domain doesn't fill the new tab.
+///
+/// The default rules don't remove anything.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DiversityRules {
+    /// Remove spocs of ads that an earlier div already has.
+    #[serde(default)]
+    dedupe_ads: bool,
+    /// Remove spocs of flights that an earlier div already has. Spocs of the
+    /// same flight within a div, e.g. the items of a collection, are kept.
+    #[serde(default)]
+    dedupe_flights: bool,
+    /// Maximum number of spocs per sponsor in a response.
+    max_per_sponsor: Option<usize>,
+    /// Maximum number of spocs per domain in a response.
+    max_per_domain: Option<usize>,
+    /// Order in which divs pick their spocs, so the spocs of divs listed first
+    /// are kept over duplicates in later divs, and are backfilled first. Other
+    /// divs come last, in alphabetical order.
+    #[serde(default)]
+    div_order: Vec<String>,
+    /// Number of spare spocs requested from Kevel for each div, which replace
+    /// the spocs these rules remove.
+    #[serde(default)]
+    backfill: u32,
+}
+
+/// Most spare spocs requested for each div.
+pub const MAX_BACKFILL: u32 = 10;
+
+/// Number of spocs removed by each rule.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct DiversityStats {
+    pub duplicate_ads: usize,
+    pub duplicate_flights: usize,
+    pub sponsor_limit: usize,
+    pub domain_limit: usize,
+}
+
+impl DiversityRules {
+    /// Load and validate diversity rules from a JSON file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, ProxyError> {
+        let rules: Self = serde_json::from_str(&fs::read_to_string(path)?)?;
+        rules.validate()
+    }
+
+    fn validate(self) -> Result<Self, ProxyError> {
+        if self.max_per_sponsor == Some(0) || self.max_per_domain == Some(0) {
+            return Err(ProxyError::new(
+                "Diversity limits must allow at least one spoc",
+            ));
+        }
+        if self.backfill > MAX_BACKFILL {
+            return Err(ProxyError::new(format!(
+                "At most {} spare spocs can be requested for backfill",
+                MAX_BACKFILL
+            )));
+        }
+        let divs: HashSet<&String> = self.div_order.iter().collect();
+        if divs.len() != self.div_order.len() {
+            return Err(ProxyError::new("Divs must be listed once in div_order"));
+        }
+        Ok(self)
+    }
+
+    /// Number of spare spocs to request for each div.
+    pub fn backfill(&self) -> u32 {
+        self.backfill
+    }
+
+    /// Remove spocs that break the rules, keeping the order of spocs within
+    /// each div. Each div keeps at most the number of spocs given by `count`,
+    /// so spare spocs fill the place of removed ones, and unused spares don't
+    /// count against later divs.
+    pub fn apply(
+        &self,
+        divs: &mut [(String, Vec<Spoc>)],
+        count: impl Fn(&str) -> Option<usize>,
+    ) -> DiversityStats {
+        let mut stats = DiversityStats::default();
+        let mut order: Vec<usize> = (0..divs.len()).collect();
+        order.sort_by_key(|&i| {
+            let name = &divs[i].0;
+            let position = self.div_order.iter().position(|div| div == name);
+            (position.unwrap_or(usize::MAX), name.clone())
+        });
+
+        // Ads and flights claimed by earlier divs.
+        let mut ads = HashSet::new();
+        let mut flights = HashSet::new();
+        let mut sponsors: HashMap<String, usize> = HashMap::new();
+        let mut domains: HashMap<String, usize> = HashMap::new();
+        for i in order {
+            let (div, spocs) = &mut divs[i];
+            let max = count(div).unwrap_or(usize::MAX);
+            let mut div_ads = Vec::new();
+            let mut div_flights = Vec::new();
+            spocs.retain(|spoc| {
+                if div_ads.len() >= max {
+                    return false;
+                }
+                if self.dedupe_ads && ads.contains(&spoc.id) {
+                    stats.duplicate_ads += 1;
+                    return false;
+                }
+                if self.dedupe_flights && flights.contains(&spoc.flight_id) {
+                    stats.duplicate_flights += 1;
+                    return false;
+                }
+                if let (Some(max), Some(sponsor)) = (self.max_per_sponsor, &spoc.sponsor) {
+                    if sponsors.get(sponsor).copied().unwrap_or_default() >= max {
+                        stats.sponsor_limit += 1;
+                        return false;
+                    }
+                }
+                if let Some(max) = self.max_per_domain {
+                    if domains.get(&spoc.domain).copied().unwrap_or_default() >= max {
+                        stats.domain_limit += 1;
+                        return false;
+                    }
+                }
+                div_ads.push(spoc.id);
+                div_flights.push(spoc.flight_id);
+                if let Some(sponsor) = &spoc.sponsor {
+                    *sponsors.entry(sponsor.clone()).or_default() += 1;
+                }
+                *domains.entry(spoc.domain.clone()).or_default() += 1;
+                true
+            });
+            ads.extend(div_ads);
+            flights.extend(div_flights);
+        }
+        stats
+    }
+}
+
+impl DiversityStats {
+    /// Removed spocs by rule, for metrics.
+    pub fn removed(&self) -> [(&'static str, usize); 4] {
+        [
+            ("duplicate_ad", self.duplicate_ads),
+            ("duplicate_flight", self.duplicate_flights),
+            ("sponsor_limit", self.sponsor_limit),
+            ("domain_limit", self.domain_limit),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DiversityRules, DiversityStats};
+    use crate::{
+        adzerk::defaults,
+        endpoints::spocs::{Shim, Spoc},
+    };
+    use serde_json::{from_value, json};
+    use std::{collections::HashMap, sync::Arc};
+
+    fn spoc(id: u32, flight_id: u32, sponsor: &str, domain: &str) -> Spoc {
+        Spoc {
+            id,
+            flight_id,
+            campaign_id: 1,
+            title: String::new(),
+            url: String::new(),
+            domain: domain.to_owned(),
+            excerpt: String::new(),
+            priority: defaults::PRIORITY,
+            context: String::new(),
+            raw_image_src: String::new(),
+            image_src: String::new(),
+            shim: Shim {
+                click: String::new(),
+                impression: String::new(),
+                delete: String::new(),
+                save: String::new(),
+            },
+            parameter_set: defaults::PARAMETER_SET.to_owned(),
+            caps: defaults::CAPS,
+            domain_affinities: Arc::default(),
+            personalization_models: HashMap::new(),
+            min_score: 0.1,
+            item_score: 0.2,
+            cta: None,
+            collection_title: None,
+            sponsor: Some(sponsor.to_owned()),
+            sponsored_by_override: None,
+            is_video: None,
+        }
+    }
+
+    fn divs() -> Vec<(String, Vec<Spoc>)> {
+        vec![
+            (
+                "sponsored-topics".to_owned(),
+                vec![
+                    spoc(1, 10, "Acme", "acme.com"),
+                    spoc(4, 40, "Globex", "globex.com"),
+                ],
+            ),
+            (
+                "spocs".to_owned(),
+                vec![
+                    spoc(1, 10, "Acme", "acme.com"),
+                    spoc(2, 10, "Acme", "acme.com"),
+                    spoc(3, 30, "Acme", "acme.org"),
+                ],
+            ),
+        ]
+    }
+
+    fn ids(divs: &[(String, Vec<Spoc>)], div: &str) -> Vec<u32> {
+        let (_, spocs) = divs.iter().find(|(name, _)| name == div).unwrap();
+        spocs.iter().map(|spoc| spoc.id).collect()
+    }
+
+    #[test]
+    fn test_default_rules_keep_everything() {
+        let mut divs = divs();
+        let stats = DiversityRules::default().apply(&mut divs, |_| None);
+        assert_eq!(stats, DiversityStats::default());
+        assert_eq!(ids(&divs, "spocs"), vec![1, 2, 3]);
+        assert_eq!(ids(&divs, "sponsored-topics"), vec![1, 4]);
+    }
+
+    #[test]
+    fn test_dedupe_in_div_order() {
+        let rules: DiversityRules = from_value(json!({
+            "dedupe_ads": true,
+            "dedupe_flights": true,
+            "div_order": ["spocs"]
+        }))
+        .unwrap();
+        let mut divs = divs();
+        divs[0].1.push(spoc(5, 30, "Globex", "globex.org"));
+        let stats = rules.apply(&mut divs, |_| None);
+        // Spocs of the same flight within a div are kept.
+        assert_eq!(ids(&divs, "spocs"), vec![1, 2, 3]);
+        assert_eq!(ids(&divs, "sponsored-topics"), vec![4]);
+        assert_eq!(
+            stats,
+            DiversityStats {
+                duplicate_ads: 1,
+                duplicate_flights: 1,
+                ..DiversityStats::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_backfill() {
+        let rules: DiversityRules = from_value(json!({
+            "dedupe_ads": true,
+            "div_order": ["sponsored-topics"],
+            "backfill": 2
+        }))
+        .unwrap();
+        let rules = rules.validate().unwrap();
+        assert_eq!(rules.backfill(), 2);
+        let count = |div: &str| match div {
+            "sponsored-topics" => Some(1),
+            _ => Some(2),
+        };
+
+        // Spocs is backfilled after its duplicate of ad 1 is removed.
+        let mut backfilled = divs();
+        let stats = rules.apply(&mut backfilled, count);
+        assert_eq!(ids(&backfilled, "sponsored-topics"), vec![1]);
+        assert_eq!(ids(&backfilled, "spocs"), vec![2, 3]);
+        assert_eq!(stats.duplicate_ads, 1);
+
+        // The unused spare of sponsored-topics doesn't remove ad 1 from spocs.
+        let mut spare = divs();
+        spare[0].1.reverse();
+        let stats = rules.apply(&mut spare, count);
+        assert_eq!(ids(&spare, "sponsored-topics"), vec![4]);
+        assert_eq!(ids(&spare, "spocs"), vec![1, 2]);
+        assert_eq!(stats, DiversityStats::default());
+    }
+
+    #[test]
+    fn test_limits() {
+        let rules: DiversityRules = from_value(json!({
+            "max_per_sponsor": 2,
+            "max_per_domain": 1
+        }))
+        .unwrap();
+        let mut divs = divs();
+        let stats = rules.apply(&mut divs, |_| None);
+        // Without div_order, divs pick in alphabetical order.
+        assert_eq!(ids(&divs, "spocs"), vec![1, 3]);
+        assert_eq!(ids(&divs, "sponsored-topics"), vec![4]);
+        assert_eq!(
+            stats,
+            DiversityStats {
+                sponsor_limit: 1,
+                domain_limit: 1,
+                ..DiversityStats::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        let invalid_rules = [
+            json!({"max_per_sponsor": 0}),
+            json!({"div_order": ["spocs", "spocs"]}),
+            json!({"backfill": 11}),
+        ];
+        for rules in invalid_rules {
+            assert!(
+                from_value::<DiversityRules>(rules.clone())
+                    .unwrap()
+                    .validate()
+                    .is_err(),
+                "{} should be rejected",
+                rules
+            );
+        }
+        assert!(from_value::<DiversityRules>(json!({"max_per_advertiser": 1})).is_err());
+    }
+}
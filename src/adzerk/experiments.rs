@@ -0,0 +1,362 @@
+use super::{client_settings::ClientSettings, placements::Placements, ranking::RankerName};
+use crate::errors::ProxyError;
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    path::Path,
+};
+
+/// Market allocation of countries without their own.
+const OTHER_MARKETS: &str = "*";
+/// Number of buckets clients are divided into. Allocations are in percent, so
+/// each percent is 100 buckets.
+const BUCKETS: u64 = 10_000;
+
+/// Server-side experiments. Clients are assigned to experiments and branches
+/// deterministically, by a hash of their `pocket_id` and the experiment's
+/// salt, so they stay in the same branch across requests.
+///
+/// A client can be in several experiments. Their overrides are applied in the
+/// order the experiments are listed, so later experiments take precedence.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Experiments {
+    #[serde(default)]
+    experiments: Vec<Experiment>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Experiment {
+    name: String,
+    /// Changing the salt reassigns clients.
+    salt: String,
+    /// Percent of clients enrolled, by country, or `*` for other countries.
+    /// Clients in markets without an allocation are not enrolled.
+    allocation: HashMap<String, f64>,
+    branches: Vec<Branch>,
+}
+
+/// A branch of an experiment, and how it changes the response.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Branch {
+    name: String,
+    /// Share of the experiment's clients, relative to the other branches.
+    #[serde(default = "default_weight")]
+    weight: u32,
+    /// Orders the spocs of each div.
+    pub ranker: Option<RankerName>,
+    /// Domain affinity parameter set of spocs whose creative doesn't choose
+    /// one, instead of the div's.
+    pub parameter_set: Option<String>,
+    /// Settings of divs, instead of the configured ones.
+    pub placements: Option<Placements>,
+    /// Kevel keywords added to the request.
+    #[serde(default)]
+    pub keywords: Vec<String>,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+/// The branches a client is assigned to.
+#[derive(Debug, Default)]
+pub struct Assignment<'a> {
+    branches: Vec<(&'a str, &'a Branch)>,
+}
+
+impl Experiments {
+    /// Load experiments from a JSON file, and validate them against the
+    /// client settings.
+    pub fn from_file<P: AsRef<Path>>(
+        path: P,
+        client_settings: &ClientSettings,
+    ) -> Result<Self, ProxyError> {
+        Self::from_json(&fs::read_to_string(path)?, client_settings)
+    }
+
+    pub fn from_json(json: &str, client_settings: &ClientSettings) -> Result<Self, ProxyError> {
+        let experiments: Self = serde_json::from_str(json)?;
+        experiments.validate(client_settings)
+    }
+
+    fn validate(self, client_settings: &ClientSettings) -> Result<Self, ProxyError> {
+        let mut names = HashSet::new();
+        for experiment in &self.experiments {
+            let invalid = |reason: &str| {
+                ProxyError::new(format!("Experiment {}: {}", experiment.name, reason))
+            };
+            if experiment.name.is_empty() || !names.insert(&experiment.name) {
+                return Err(invalid("names must be unique"));
+            }
+            if experiment.salt.is_empty() {
+                return Err(invalid("the salt must not be empty"));
+            }
+            let is_market = |market: &str| {
+                market == OTHER_MARKETS
+                    || (market.len() == 2 && market.bytes().all(|b| b.is_ascii_uppercase()))
+            };
+            if experiment
+                .allocation
+                .iter()
+                .any(|(market, &percent)| !is_market(market) || !(0.0..=100.0).contains(&percent))
+            {
+                return Err(invalid("invalid allocation"));
+            }
+            if experiment.branches.is_empty()
+                || experiment.branches.iter().all(|branch| branch.weight == 0)
+            {
+                return Err(invalid("there must be a branch with a weight"));
+            }
+            let mut branches = HashSet::new();
+            for branch in &experiment.branches {
+                if branch.name.is_empty() || !branches.insert(&branch.name) {
+                    return Err(invalid("branch names must be unique"));
+                }
+                if let Some(parameter_set) = &branch.parameter_set {
+                    if !client_settings.has_parameter_set(parameter_set) {
+                        return Err(invalid(&format!("unknown parameter set {}", parameter_set)));
+                    }
+                }
+                if let Some(placements) = &branch.placements {
+                    placements
+                        .validate(client_settings)
+                        .map_err(|err| invalid(&err.to_string()))?;
+                }
+                if branch.keywords.iter().any(|keyword| keyword.is_empty()) {
+                    return Err(invalid("keywords must not be empty"));
+                }
+            }
+        }
+        Ok(self)
+    }
+
+    /// The branches of a client, in the market of `country`.
+    pub fn assign(&self, pocket_id: &str, country: Option<&str>) -> Assignment<'_> {
+        let branches = self
+            .experiments
+            .iter()
+            .filter_map(|experiment| {
+                let branch = experiment.assign(pocket_id, country)?;
+                Some((experiment.name.as_str(), branch))
+            })
+            .collect();
+        Assignment { branches }
+    }
+}
+
+impl Experiment {
+    fn assign(&self, pocket_id: &str, country: Option<&str>) -> Option<&Branch> {
+        let allocation = country
+            .and_then(|country| self.allocation.get(country))
+            .or_else(|| self.allocation.get(OTHER_MARKETS))?;
+        let digest = openssl::sha::sha256(format!("{}:{}", self.salt, pocket_id).as_bytes());
+        let enrollment = u64::from_be_bytes(digest[0..8].try_into().unwrap());
+        if (enrollment % BUCKETS) as f64 >= allocation * (BUCKETS / 100) as f64 {
+            return None;
+        }
+        // Branches use other bits of the hash, so they are independent of
+        // the allocation, which can then grow without moving clients.
+        let total: u64 = self.branches.iter().map(|b| u64::from(b.weight)).sum();
+        let mut point = u64::from_be_bytes(digest[8..16].try_into().unwrap()) % total;
+        self.branches.iter().find(|branch| {
+            let weight = u64::from(branch.weight);
+            if point < weight {
+                return true;
+            }
+            point -= weight;
+            false
+        })
+    }
+}
+
+impl<'a> Assignment<'a> {
+    /// The branch of each experiment the client is in.
+    pub fn names(&self) -> BTreeMap<String, String> {
+        self.branches
+            .iter()
+            .map(|(experiment, branch)| ((*experiment).to_owned(), branch.name.clone()))
+            .collect()
+    }
+
+    pub fn ranker(&self) -> Option<RankerName> {
+        self.branches.iter().rev().find_map(|(_, b)| b.ranker)
+    }
+
+    pub fn parameter_set(&self) -> Option<&'a str> {
+        self.branches
+            .iter()
+            .rev()
+            .find_map(|(_, b)| b.parameter_set.as_deref())
+    }
+
+    /// The placements with those of the branches replacing them.
+    pub fn placements(&self, placements: &Placements) -> Option<Placements> {
+        let mut overrides = self
+            .branches
+            .iter()
+            .filter_map(|(_, b)| b.placements.as_ref())
+            .peekable();
+        overrides.peek()?;
+        let mut placements = placements.clone();
+        for branch_placements in overrides {
+            placements.extend(branch_placements);
+        }
+        Some(placements)
+    }
+
+    pub fn keywords(&self) -> impl Iterator<Item = &'a String> + '_ {
+        self.branches.iter().flat_map(|(_, b)| &b.keywords)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Experiments;
+    use crate::adzerk::{client_settings::ClientSettings, defaults, ranking::RankerName};
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+
+    fn experiments(experiments: Value) -> Experiments {
+        Experiments::from_json(
+            &json!({ "experiments": experiments }).to_string(),
+            &ClientSettings::default(),
+        )
+        .unwrap()
+    }
+
+    fn pocket_id(i: usize) -> String {
+        format!("{{00000000-0000-4000-8000-{:012}}}", i)
+    }
+
+    #[test]
+    fn test_assignment_is_deterministic() {
+        let experiments = experiments(json!([{
+            "name": "ranking",
+            "salt": "2026-10",
+            "allocation": {"US": 50, "*": 10},
+            "branches": [
+                {"name": "control"},
+                {"name": "kevel", "weight": 3, "ranker": "kevel", "keywords": ["exp_ranking"]}
+            ]
+        }]));
+
+        let mut counts: HashMap<(&str, String), usize> = HashMap::new();
+        for i in 0..2000 {
+            for country in ["US", "DE"] {
+                let assignment = experiments.assign(&pocket_id(i), Some(country));
+                let names = assignment.names();
+                assert_eq!(
+                    names,
+                    experiments.assign(&pocket_id(i), Some(country)).names(),
+                    "the same client gets the same branch"
+                );
+                let branch = names.get("ranking").cloned().unwrap_or_default();
+                match branch.as_str() {
+                    "kevel" => {
+                        assert_eq!(assignment.ranker(), Some(RankerName::Kevel));
+                        assert_eq!(assignment.keywords().collect::<Vec<_>>(), ["exp_ranking"]);
+                    }
+                    _ => assert_eq!(assignment.ranker(), None),
+                }
+                *counts.entry((country, branch)).or_default() += 1;
+            }
+        }
+        let share = |country, branch: &str| counts[&(country, branch.to_owned())] as f64 / 2000.0;
+        assert!((share("US", "control") - 0.125).abs() < 0.03);
+        assert!((share("US", "kevel") - 0.375).abs() < 0.03);
+        assert!((share("DE", "") - 0.9).abs() < 0.03);
+
+        assert!(
+            (0..100).all(|i| experiments.assign(&pocket_id(i), None).names().len() <= 1),
+            "unknown countries use the allocation of other markets"
+        );
+    }
+
+    #[test]
+    fn test_overrides_and_precedence() {
+        let experiments = experiments(json!([
+            {
+                "name": "first",
+                "salt": "a",
+                "allocation": {"*": 100},
+                "branches": [{
+                    "name": "treatment",
+                    "ranker": "kevel",
+                    "parameter_set": "fully-personalized",
+                    "placements": {"divs": {"spocs": {"caps": {"lifetime": 5}}}}
+                }]
+            },
+            {
+                "name": "second",
+                "salt": "b",
+                "allocation": {"*": 100},
+                "branches": [{"name": "treatment", "ranker": "priority"}]
+            },
+            {
+                "name": "unallocated",
+                "salt": "c",
+                "allocation": {"US": 100},
+                "branches": [{"name": "treatment", "ranker": "kevel"}]
+            }
+        ]));
+        let assignment = experiments.assign(&pocket_id(1), Some("DE"));
+        assert_eq!(assignment.names().len(), 2);
+        assert_eq!(assignment.ranker(), Some(RankerName::Priority));
+        assert_eq!(assignment.parameter_set(), Some("fully-personalized"));
+        let placements = assignment.placements(&Default::default()).unwrap();
+        assert_eq!(
+            placements.get("spocs").caps.apply(defaults::CAPS).lifetime,
+            5
+        );
+
+        let experiments = Experiments::default();
+        let assignment = experiments.assign(&pocket_id(1), Some("DE"));
+        assert!(assignment.names().is_empty());
+        assert!(assignment.placements(&Default::default()).is_none());
+    }
+
+    #[test]
+    fn test_invalid_experiments() {
+        let experiment = json!({
+            "name": "ranking",
+            "salt": "2026-10",
+            "allocation": {"US": 50},
+            "branches": [{"name": "control"}]
+        });
+        let invalid_changes = [
+            ("name", json!("")),
+            ("salt", json!("")),
+            ("allocation", json!({"US": 150})),
+            ("allocation", json!({"usa": 50})),
+            ("branches", json!([])),
+            ("branches", json!([{"name": "control", "weight": 0}])),
+            ("branches", json!([{"name": "a"}, {"name": "a"}])),
+            (
+                "branches",
+                json!([{"name": "a", "parameter_set": "unknown"}]),
+            ),
+            ("branches", json!([{"name": "a", "ranker": "random"}])),
+            ("branches", json!([{"name": "a", "keywords": [""]}])),
+            (
+                "branches",
+                json!([{"name": "a", "placements": {"divs": {"spocs": {"caps": {"lifetime": 5000}}}}}]),
+            ),
+        ];
+        for (key, value) in invalid_changes {
+            let mut experiment = experiment.clone();
+            experiment[key] = value;
+            let json = json!({ "experiments": [experiment] }).to_string();
+            assert!(
+                Experiments::from_json(&json, &ClientSettings::default()).is_err(),
+                "{} should be rejected",
+                json
+            );
+        }
+        let json = json!({ "experiments": [experiment.clone(), experiment] }).to_string();
+        assert!(Experiments::from_json(&json, &ClientSettings::default()).is_err());
+    }
+}
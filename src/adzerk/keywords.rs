@@ -0,0 +1,295 @@
+use super::defaults;
+use crate::{errors::ProxyError, targeting::TargetingContext};
+use chrono::{DateTime, Timelike, Utc};
+use chrono_tz::Tz;
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write,
+    fs,
+    path::Path,
+};
+
+/// Declarative configuration of the keywords sent to Kevel with each decision
+/// request.
+///
+/// Each template produces at most one keyword. A template is skipped if any of
+/// the attributes it refers to is unknown for the client, or if it is listed in
+/// `disabled_by_country` for the client's country.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "KeywordTemplatesConfig")]
+pub struct KeywordTemplates {
+    templates: Vec<KeywordTemplate>,
+    disabled_by_country: HashMap<String, HashSet<String>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeywordTemplatesConfig {
+    templates: Vec<KeywordTemplateConfig>,
+    #[serde(default)]
+    disabled_by_country: HashMap<String, HashSet<String>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeywordTemplateConfig {
+    name: String,
+    format: String,
+}
+
+#[derive(Clone, Debug)]
+struct KeywordTemplate {
+    name: String,
+    segments: Vec<Segment>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Attribute(Attribute),
+}
+
+/// The attributes that can be used as placeholders in keyword templates, e.g.
+/// `"{country}-{region}"`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Attribute {
+    Country,
+    Region,
+    Continent,
+    Dma,
+    Locale,
+    ClientVersion,
+    LocalHour,
+}
+
+impl Attribute {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "country" => Attribute::Country,
+            "region" => Attribute::Region,
+            "continent" => Attribute::Continent,
+            "dma" => Attribute::Dma,
+            "locale" => Attribute::Locale,
+            "client_version" => Attribute::ClientVersion,
+            "local_hour" => Attribute::LocalHour,
+            _ => return None,
+        })
+    }
+
+    fn value(self, targeting: &TargetingContext, now: DateTime<Utc>) -> Option<String> {
+        match self {
+            Attribute::Country => targeting.country.clone(),
+            Attribute::Region => targeting.region.clone(),
+            Attribute::Continent => targeting.continent.clone(),
+            Attribute::Dma => targeting.metro_code.map(|code| code.to_string()),
+            Attribute::Locale => targeting.locale.clone(),
+            Attribute::ClientVersion => targeting.client_version.map(|v| v.to_string()),
+            Attribute::LocalHour => {
+                let time_zone: Tz = targeting.time_zone.as_ref()?.parse().ok()?;
+                Some(format!("{:02}", now.with_timezone(&time_zone).hour()))
+            }
+        }
+    }
+}
+
+impl KeywordTemplate {
+    fn parse(name: String, format: &str) -> Result<Self, String> {
+        let mut segments = Vec::new();
+        let mut rest = format;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                segments.push(Segment::Literal(rest[..start].to_owned()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("unclosed placeholder in keyword template '{}'", name))?;
+            let placeholder = &rest[start + 1..start + end];
+            let attribute = Attribute::from_name(placeholder).ok_or_else(|| {
+                format!(
+                    "unknown placeholder '{}' in keyword template '{}'",
+                    placeholder, name
+                )
+            })?;
+            segments.push(Segment::Attribute(attribute));
+            rest = &rest[start + end + 1..];
+        }
+        if rest.contains('}') {
+            return Err(format!("unmatched '}}' in keyword template '{}'", name));
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Literal(rest.to_owned()));
+        }
+        if segments.is_empty() {
+            return Err(format!("keyword template '{}' is empty", name));
+        }
+        Ok(Self { name, segments })
+    }
+
+    fn render(&self, targeting: &TargetingContext, now: DateTime<Utc>) -> Option<String> {
+        let mut keyword = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(literal) => keyword.push_str(literal),
+                Segment::Attribute(attribute) => {
+                    write!(keyword, "{}", attribute.value(targeting, now)?).ok()?
+                }
+            }
+        }
+        Some(keyword)
+    }
+}
+
+impl TryFrom<KeywordTemplatesConfig> for KeywordTemplates {
+    type Error = String;
+
+    fn try_from(config: KeywordTemplatesConfig) -> Result<Self, Self::Error> {
+        let templates = config
+            .templates
+            .into_iter()
+            .map(|template| KeywordTemplate::parse(template.name, &template.format))
+            .collect::<Result<Vec<_>, _>>()?;
+        let names: HashSet<&str> = templates.iter().map(|t| t.name.as_str()).collect();
+        if names.len() != templates.len() {
+            return Err("keyword template names must be unique".to_owned());
+        }
+        for (country, disabled) in &config.disabled_by_country {
+            if let Some(name) = disabled.iter().find(|name| !names.contains(name.as_str())) {
+                return Err(format!(
+                    "unknown keyword template '{}' disabled for country '{}'",
+                    name, country
+                ));
+            }
+        }
+        Ok(Self {
+            templates,
+            disabled_by_country: config.disabled_by_country,
+        })
+    }
+}
+
+impl Default for KeywordTemplates {
+    fn default() -> Self {
+        defaults::KEYWORD_TEMPLATES.clone()
+    }
+}
+
+impl KeywordTemplates {
+    /// Load and validate keyword templates from a JSON file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, ProxyError> {
+        let templates = serde_json::from_str(&fs::read_to_string(path)?)?;
+        Ok(templates)
+    }
+
+    /// Produce the list of Kevel keywords for a client.
+    pub fn render(&self, targeting: &TargetingContext, now: DateTime<Utc>) -> Vec<String> {
+        let disabled = targeting
+            .country
+            .as_ref()
+            .and_then(|country| self.disabled_by_country.get(country));
+        self.templates
+            .iter()
+            .filter(|template| !disabled.map_or(false, |d| d.contains(&template.name)))
+            .filter_map(|template| template.render(targeting, now))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::KeywordTemplates;
+    use crate::targeting::TargetingContext;
+    use chrono::{TimeZone, Utc};
+    use serde_json::{from_value, json};
+
+    fn targeting() -> TargetingContext {
+        TargetingContext {
+            country: Some("US".to_owned()),
+            region: Some("IL".to_owned()),
+            continent: Some("NA".to_owned()),
+            metro_code: Some(602),
+            time_zone: Some("America/Chicago".to_owned()),
+            locale: Some("en-US".to_owned()),
+            client_version: Some(102),
+        }
+    }
+
+    #[test]
+    fn test_default_templates() {
+        let templates = KeywordTemplates::default();
+        let now = Utc::now();
+        assert_eq!(templates.render(&targeting(), now), ["US", "US-IL"]);
+
+        let country_only = TargetingContext {
+            region: None,
+            ..targeting()
+        };
+        assert_eq!(templates.render(&country_only, now), ["US"]);
+        assert!(templates
+            .render(&TargetingContext::default(), now)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_all_attributes() {
+        let templates: KeywordTemplates = from_value(json!({
+            "templates": [
+                {"name": "dma", "format": "dma-{dma}"},
+                {"name": "continent", "format": "continent_{continent}"},
+                {"name": "locale", "format": "{locale}"},
+                {"name": "version", "format": "fx{client_version}"},
+                {"name": "hour", "format": "{country}:hour-{local_hour}"},
+            ]
+        }))
+        .unwrap();
+        let now = Utc.ymd(2022, 6, 1).and_hms(14, 30, 0);
+        assert_eq!(
+            templates.render(&targeting(), now),
+            ["dma-602", "continent_NA", "en-US", "fx102", "US:hour-09"]
+        );
+    }
+
+    #[test]
+    fn test_disabled_by_country() {
+        let templates: KeywordTemplates = from_value(json!({
+            "templates": [
+                {"name": "country", "format": "{country}"},
+                {"name": "country-region", "format": "{country}-{region}"},
+            ],
+            "disabled_by_country": {"DE": ["country-region"]}
+        }))
+        .unwrap();
+        let german = TargetingContext {
+            country: Some("DE".to_owned()),
+            region: Some("BE".to_owned()),
+            ..TargetingContext::default()
+        };
+        assert_eq!(templates.render(&german, Utc::now()), ["DE"]);
+        assert_eq!(templates.render(&targeting(), Utc::now()), ["US", "US-IL"]);
+    }
+
+    #[test]
+    fn test_invalid_templates() {
+        let invalid_configs = [
+            json!({"templates": [{"name": "a", "format": "{city}"}]}),
+            json!({"templates": [{"name": "a", "format": "{country"}]}),
+            json!({"templates": [{"name": "a", "format": "country}"}]}),
+            json!({"templates": [{"name": "a", "format": ""}]}),
+            json!({"templates": [
+                {"name": "a", "format": "{country}"},
+                {"name": "a", "format": "{region}"},
+            ]}),
+            json!({
+                "templates": [{"name": "a", "format": "{country}"}],
+                "disabled_by_country": {"DE": ["b"]}
+            }),
+        ];
+        for config in invalid_configs {
+            assert!(
+                from_value::<KeywordTemplates>(config.clone()).is_err(),
+                "{} should be rejected",
+                config
+            );
+        }
+    }
+}
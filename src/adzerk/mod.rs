@@ -1,4 +1,17 @@
+pub mod affinities;
+pub mod caps;
 pub mod client;
+pub mod client_settings;
+pub mod collections;
 pub mod defaults;
-mod request_models;
+pub mod diversity;
+pub mod experiments;
+pub mod keywords;
+pub mod overlays;
+pub mod personalization;
+pub mod placements;
+pub mod priorities;
+pub mod ranking;
+pub mod request_models;
 mod response_models;
+pub mod topics;
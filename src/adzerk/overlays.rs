@@ -0,0 +1,432 @@
+use super::client_settings::ClientSettings;
+use crate::{errors::ProxyError, targeting::TargetingContext};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+/// Maximum number of merged settings kept in the cache.
+const MAX_CACHED: usize = 1024;
+
+/// Changes to the client settings for some clients, as JSON merge patches
+/// (RFC 7386).
+///
+/// Overlays are applied in the order they are listed, so later overlays take
+/// precedence over earlier ones.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SettingsOverlays {
+    #[serde(default)]
+    overlays: Vec<Overlay>,
+    #[serde(skip)]
+    cache: Mutex<Cache>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Overlay {
+    name: String,
+    /// Clients the overlay applies to. Without conditions, it applies to all
+    /// clients.
+    #[serde(default, rename = "match")]
+    conditions: Conditions,
+    /// Merge patch applied to the settings.
+    settings: Value,
+}
+
+/// Every condition that is set must match. Clients for which a condition
+/// can't be checked, e.g. because their version is unknown, don't match.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Conditions {
+    /// ISO country codes.
+    countries: Option<HashSet<String>>,
+    /// Locales, such as `de-DE`, or languages, such as `de`, which match all
+    /// locales of the language.
+    locales: Option<HashSet<String>>,
+    /// Oldest Firefox major version.
+    min_version: Option<u32>,
+    /// Newest Firefox major version.
+    max_version: Option<u32>,
+    /// Divs, of which the client must request at least one.
+    placements: Option<HashSet<String>>,
+    /// Branches by experiment, of which the client must be in at least one.
+    experiments: Option<HashMap<String, HashSet<String>>>,
+}
+
+/// Merged settings by the overlays applied, for one version of the base
+/// settings.
+#[derive(Debug, Default)]
+struct Cache {
+    base_hash: String,
+    merged: HashMap<Vec<usize>, Arc<ClientSettings>>,
+}
+
+impl SettingsOverlays {
+    /// Load and validate settings overlays from a JSON file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, ProxyError> {
+        Self::from_json(&fs::read_to_string(path)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, ProxyError> {
+        let overlays: Self = serde_json::from_str(json)?;
+        overlays.validate()
+    }
+
+    fn validate(self) -> Result<Self, ProxyError> {
+        let mut names = HashSet::new();
+        for overlay in &self.overlays {
+            if overlay.name.is_empty() || !names.insert(&overlay.name) {
+                return Err(ProxyError::new(format!(
+                    "Settings overlays must have unique names: '{}'",
+                    overlay.name
+                )));
+            }
+            let conditions = &overlay.conditions;
+            if !overlay.settings.is_object()
+                || matches!(
+                    (conditions.min_version, conditions.max_version),
+                    (Some(min), Some(max)) if min > max
+                )
+            {
+                return Err(ProxyError::new(format!(
+                    "Invalid settings overlay '{}'",
+                    overlay.name
+                )));
+            }
+        }
+        Ok(self)
+    }
+
+    /// The settings for a client, in the experiment `branches`. If the
+    /// overlays that match the client make the settings invalid, an error is
+    /// returned once, and the base settings are used for such clients from
+    /// then on.
+    pub fn apply(
+        &self,
+        base: &Arc<ClientSettings>,
+        targeting: &TargetingContext,
+        placements: &[&str],
+        branches: &BTreeMap<String, String>,
+    ) -> Result<Arc<ClientSettings>, ProxyError> {
+        let matched: Vec<usize> = (0..self.overlays.len())
+            .filter(|&i| {
+                self.overlays[i]
+                    .conditions
+                    .matches(targeting, placements, branches)
+            })
+            .collect();
+        if matched.is_empty() {
+            return Ok(Arc::clone(base));
+        }
+
+        let mut cache = self.cache.lock().unwrap();
+        if cache.base_hash != base.hash() || cache.merged.len() >= MAX_CACHED {
+            cache.base_hash = base.hash().to_owned();
+            cache.merged.clear();
+        }
+        if let Some(settings) = cache.merged.get(&matched) {
+            return Ok(Arc::clone(settings));
+        }
+        let mut settings = (*base.value()).clone();
+        for &i in &matched {
+            merge(&mut settings, &self.overlays[i].settings);
+        }
+        match ClientSettings::from_value(settings) {
+            Ok(settings) => {
+                let settings = Arc::new(settings);
+                cache.merged.insert(matched, Arc::clone(&settings));
+                Ok(settings)
+            }
+            Err(err) => {
+                let names: Vec<&str> = matched
+                    .iter()
+                    .map(|&i| self.overlays[i].name.as_str())
+                    .collect();
+                cache.merged.insert(matched, Arc::clone(base));
+                Err(ProxyError::new(format!(
+                    "Settings overlays {} make the settings invalid: {}",
+                    names.join(", "),
+                    err
+                )))
+            }
+        }
+    }
+}
+
+impl Conditions {
+    fn matches(
+        &self,
+        targeting: &TargetingContext,
+        placements: &[&str],
+        branches: &BTreeMap<String, String>,
+    ) -> bool {
+        let country = |countries: &HashSet<String>| {
+            targeting
+                .country
+                .as_ref()
+                .map_or(false, |country| countries.contains(country))
+        };
+        let locale = |locales: &HashSet<String>| {
+            targeting.locale.as_deref().map_or(false, |locale| {
+                locales.contains(locale)
+                    || locale
+                        .split_once('-')
+                        .map_or(false, |(language, _)| locales.contains(language))
+            })
+        };
+        let min_version = |min: u32| {
+            targeting
+                .client_version
+                .map_or(false, |version| version >= min)
+        };
+        let max_version = |max: u32| {
+            targeting
+                .client_version
+                .map_or(false, |version| version <= max)
+        };
+        let placement = |divs: &HashSet<String>| placements.iter().any(|&div| divs.contains(div));
+        let experiment = |experiments: &HashMap<String, HashSet<String>>| {
+            branches.iter().any(|(experiment, branch)| {
+                experiments
+                    .get(experiment)
+                    .map_or(false, |branches| branches.contains(branch))
+            })
+        };
+        self.countries.as_ref().map_or(true, country)
+            && self.locales.as_ref().map_or(true, locale)
+            && self.min_version.map_or(true, min_version)
+            && self.max_version.map_or(true, max_version)
+            && self.placements.as_ref().map_or(true, placement)
+            && self.experiments.as_ref().map_or(true, experiment)
+    }
+}
+
+/// Apply a JSON merge patch (RFC 7386).
+fn merge(target: &mut Value, patch: &Value) {
+    match patch {
+        Value::Object(patch) => {
+            if !target.is_object() {
+                *target = Value::Object(Map::new());
+            }
+            let target = target.as_object_mut().unwrap();
+            for (key, value) in patch {
+                if value.is_null() {
+                    target.remove(key);
+                } else {
+                    merge(target.entry(key.as_str()).or_insert(Value::Null), value);
+                }
+            }
+        }
+        patch => *target = patch.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SettingsOverlays;
+    use crate::{adzerk::client_settings::ClientSettings, targeting::TargetingContext};
+    use serde_json::{json, Value};
+    use std::{collections::BTreeMap, sync::Arc};
+
+    fn base() -> Arc<ClientSettings> {
+        let settings = json!({
+            "feature_flags": {"spoc_v2": true, "collections": false},
+            "spocsPerNewTabs": 1,
+            "domainAffinityParameterSets": {"default": {"recencyFactor": 0.5}},
+            "timeSegments": [{"id": "week", "startTime": 604800, "endTime": 0, "weightPosition": 1}]
+        });
+        Arc::new(ClientSettings::from_json(&settings.to_string()).unwrap())
+    }
+
+    fn overlays(overlays: Value) -> SettingsOverlays {
+        SettingsOverlays::from_json(&json!({ "overlays": overlays }).to_string()).unwrap()
+    }
+
+    fn client(country: &str, locale: &str, version: Option<u32>) -> TargetingContext {
+        TargetingContext {
+            country: Some(country.to_owned()),
+            locale: Some(locale.to_owned()),
+            client_version: version,
+            ..TargetingContext::default()
+        }
+    }
+
+    #[test]
+    fn test_merge_and_precedence() {
+        let overlays = overlays(json!([
+            {
+                "name": "germany",
+                "match": {"countries": ["DE"]},
+                "settings": {"spocsPerNewTabs": 2, "feature_flags": {"collections": true}}
+            },
+            {
+                "name": "new-clients",
+                "match": {"min_version": 110},
+                "settings": {"spocsPerNewTabs": 3, "newSetting": "new"}
+            },
+            {
+                "name": "french",
+                "match": {"locales": ["fr"], "placements": ["sponsored-topics"]},
+                "settings": {"feature_flags": {"spoc_v2": null}}
+            }
+        ]));
+        let base = base();
+
+        let settings = overlays
+            .apply(
+                &base,
+                &client("DE", "de-DE", Some(120)),
+                &["spocs"],
+                &BTreeMap::new(),
+            )
+            .unwrap();
+        let value = settings.value();
+        assert_eq!(
+            value["spocsPerNewTabs"], 3,
+            "later overlays take precedence"
+        );
+        assert_eq!(value["newSetting"], "new");
+        assert_eq!(
+            value["feature_flags"],
+            json!({"spoc_v2": true, "collections": true}),
+            "objects are merged"
+        );
+        assert_ne!(settings.hash(), base.hash());
+
+        let value = overlays
+            .apply(
+                &base,
+                &client("DE", "de-DE", None),
+                &["spocs"],
+                &BTreeMap::new(),
+            )
+            .unwrap()
+            .value();
+        assert_eq!(value["spocsPerNewTabs"], 2, "unknown versions don't match");
+
+        let value = overlays
+            .apply(
+                &base,
+                &client("CA", "fr-CA", Some(100)),
+                &["spocs", "sponsored-topics"],
+                &BTreeMap::new(),
+            )
+            .unwrap()
+            .value();
+        assert_eq!(value["feature_flags"], json!({"collections": false}));
+
+        let settings = overlays
+            .apply(
+                &base,
+                &client("CA", "fr-CA", Some(100)),
+                &["spocs"],
+                &BTreeMap::new(),
+            )
+            .unwrap();
+        assert!(Arc::ptr_eq(&settings, &base), "no overlay matches");
+    }
+
+    #[test]
+    fn test_experiment_branches() {
+        let overlays = overlays(json!([{
+            "name": "more-spocs",
+            "match": {"experiments": {"spocs-per-tab": ["two", "three"]}},
+            "settings": {"spocsPerNewTabs": 2}
+        }]));
+        let base = base();
+        let targeting = client("US", "en-US", None);
+        let branches = |experiment: &str, branch: &str| {
+            BTreeMap::from([(experiment.to_owned(), branch.to_owned())])
+        };
+        let settings = overlays
+            .apply(&base, &targeting, &[], &branches("spocs-per-tab", "two"))
+            .unwrap();
+        assert_eq!(settings.value()["spocsPerNewTabs"], 2);
+        for branches in [
+            branches("spocs-per-tab", "control"),
+            branches("ranking", "two"),
+            BTreeMap::new(),
+        ] {
+            let settings = overlays.apply(&base, &targeting, &[], &branches).unwrap();
+            assert!(Arc::ptr_eq(&settings, &base));
+        }
+    }
+
+    #[test]
+    fn test_merged_settings_are_cached() {
+        let overlays = overlays(json!([
+            {"name": "germany", "match": {"countries": ["DE"]}, "settings": {"spocsPerNewTabs": 2}}
+        ]));
+        let base = base();
+        let first = overlays
+            .apply(&base, &client("DE", "de-DE", None), &[], &BTreeMap::new())
+            .unwrap();
+        let second = overlays
+            .apply(
+                &base,
+                &client("DE", "en-US", Some(100)),
+                &[],
+                &BTreeMap::new(),
+            )
+            .unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+
+        // Changed base settings are merged again.
+        let mut changed = (*base.value()).clone();
+        changed["spocsPerNewTabs"] = json!(5);
+        let changed = Arc::new(ClientSettings::from_json(&changed.to_string()).unwrap());
+        let third = overlays
+            .apply(
+                &changed,
+                &client("DE", "de-DE", None),
+                &[],
+                &BTreeMap::new(),
+            )
+            .unwrap();
+        assert!(!Arc::ptr_eq(&first, &third));
+        assert_eq!(third.value()["spocsPerNewTabs"], 2);
+    }
+
+    #[test]
+    fn test_invalid_merge_falls_back_to_base() {
+        let overlays = overlays(json!([
+            {"name": "broken", "settings": {"spocsPerNewTabs": "two"}}
+        ]));
+        let base = base();
+        let targeting = client("US", "en-US", None);
+        assert!(overlays
+            .apply(&base, &targeting, &[], &BTreeMap::new())
+            .is_err());
+        let settings = overlays
+            .apply(&base, &targeting, &[], &BTreeMap::new())
+            .unwrap();
+        assert!(Arc::ptr_eq(&settings, &base), "the error is reported once");
+    }
+
+    #[test]
+    fn test_invalid_overlays() {
+        let invalid_overlays = [
+            json!([{"name": "", "settings": {}}]),
+            json!([
+                {"name": "same", "settings": {}},
+                {"name": "same", "settings": {}}
+            ]),
+            json!([{"name": "array", "settings": []}]),
+            json!([{"name": "versions", "match": {"min_version": 110, "max_version": 100}, "settings": {}}]),
+            json!([{"name": "unknown", "match": {"regions": ["CA"]}, "settings": {}}]),
+        ];
+        for overlays in invalid_overlays {
+            let json = json!({ "overlays": overlays }).to_string();
+            assert!(
+                SettingsOverlays::from_json(&json).is_err(),
+                "{} should be rejected",
+                json
+            );
+        }
+    }
+}
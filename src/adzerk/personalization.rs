@@ -0,0 +1,161 @@
+use crate::errors::ProxyError;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Largest weight of a personalization model.
+pub const MAX_WEIGHT: u32 = 100;
+
+/// Personalization models of a spoc, by name, with their weight.
+pub type Models = HashMap<String, u32>;
+
+/// The prefixes of the keys in creative bodies that are personalization
+/// models.
+///
+/// Models with the first prefix are named without it, as clients expect for
+/// `topic_` models. Models with other prefixes keep it, so their names don't
+/// collide with the first namespace.
+#[derive(Clone, Debug)]
+pub struct ModelPrefixes {
+    prefixes: Vec<String>,
+}
+
+impl Default for ModelPrefixes {
+    fn default() -> Self {
+        Self {
+            prefixes: default_prefixes(),
+        }
+    }
+}
+
+pub fn default_prefixes() -> Vec<String> {
+    vec!["topic_".to_owned()]
+}
+
+impl ModelPrefixes {
+    pub fn new(prefixes: Vec<String>) -> Result<Self, ProxyError> {
+        if prefixes.is_empty() || prefixes.iter().any(|prefix| prefix.is_empty()) {
+            return Err(ProxyError::new(
+                "Personalization model prefixes must not be empty",
+            ));
+        }
+        Ok(Self { prefixes })
+    }
+
+    /// Read the personalization models in a creative body, a JSON object
+    /// mapping models to `true` or to a weight from 0 to 100. `true` is a
+    /// weight of 1, and models with a weight of 0 or `false` are left out.
+    ///
+    /// If the body is not valid, an error is returned along with the models
+    /// that could be read.
+    pub fn parse(&self, body: &str) -> (Models, Option<ProxyError>) {
+        let map: HashMap<String, Value> = match serde_json::from_str(body) {
+            Ok(map) => map,
+            Err(err) => return (Models::new(), Some(err.into())),
+        };
+        let mut models = Models::new();
+        let mut error = None;
+        for (key, value) in map {
+            let name = match self.name(&key) {
+                Some(name) => name,
+                None => continue,
+            };
+            match weight(&value) {
+                Some(0) => {}
+                Some(weight) => {
+                    models.insert(name, weight);
+                }
+                None => {
+                    error = Some(ProxyError::new(format!(
+                        "Invalid weight for personalization model {}: {}",
+                        key, value
+                    )))
+                }
+            }
+        }
+        (models, error)
+    }
+
+    fn name(&self, key: &str) -> Option<String> {
+        let (first, others) = self.prefixes.split_first()?;
+        if let Some(name) = key.strip_prefix(first.as_str()) {
+            return Some(name.to_owned());
+        }
+        others
+            .iter()
+            .any(|prefix| key.starts_with(prefix.as_str()))
+            .then(|| key.to_owned())
+    }
+}
+
+fn weight(value: &Value) -> Option<u32> {
+    let weight = match value {
+        Value::Bool(true) => 1,
+        Value::Bool(false) | Value::Null => 0,
+        Value::Number(number) => number.as_u64()?,
+        Value::String(string) => match string.trim() {
+            "true" => 1,
+            "" | "false" => 0,
+            string => string.parse().ok()?,
+        },
+        _ => return None,
+    };
+    u32::try_from(weight).ok().filter(|&w| w <= MAX_WEIGHT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ModelPrefixes;
+    use std::collections::HashMap;
+
+    fn models(entries: &[(&str, u32)]) -> HashMap<String, u32> {
+        entries
+            .iter()
+            .map(|&(name, weight)| (name.to_owned(), weight))
+            .collect()
+    }
+
+    #[test]
+    fn test_flags_and_weights() {
+        let (parsed, error) = ModelPrefixes::default().parse(
+            r#"{
+                "topic_fun": true,
+                "topic_autos": "true",
+                "topic_arts": "",
+                "topic_travel": 75,
+                "topic_food": " 20 ",
+                "topic_news": 0,
+                "interest_sports": 50,
+                "other": true
+            }"#,
+        );
+        assert!(error.is_none());
+        assert_eq!(
+            parsed,
+            models(&[("fun", 1), ("autos", 1), ("travel", 75), ("food", 20)])
+        );
+    }
+
+    #[test]
+    fn test_namespaces() {
+        let prefixes =
+            ModelPrefixes::new(vec!["topic_".to_owned(), "interest_".to_owned()]).unwrap();
+        let (parsed, error) = prefixes.parse(r#"{"topic_sports": 10, "interest_sports": 50}"#);
+        assert!(error.is_none());
+        assert_eq!(parsed, models(&[("sports", 10), ("interest_sports", 50)]));
+        assert!(ModelPrefixes::new(vec![]).is_err());
+        assert!(ModelPrefixes::new(vec!["".to_owned()]).is_err());
+    }
+
+    #[test]
+    fn test_malformed_bodies() {
+        let prefixes = ModelPrefixes::default();
+        let (parsed, error) = prefixes.parse("not json");
+        assert!(parsed.is_empty());
+        assert!(error.is_some());
+
+        let (parsed, error) =
+            prefixes.parse(r#"{"topic_fun": 150, "topic_bad": "lots", "topic_ok": 5}"#);
+        assert_eq!(parsed, models(&[("ok", 5)]));
+        assert!(error.is_some());
+    }
+}
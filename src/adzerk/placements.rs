@@ -0,0 +1,219 @@
+use super::{
+    caps::CapsOverride, client_settings::ClientSettings, collections::CollectionRules, defaults,
+};
+use crate::errors::ProxyError;
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use std::{collections::HashMap, fs, path::Path};
+
+/// Settings for the spocs of each div. Divs that are not listed use the
+/// defaults.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Placements {
+    #[serde(default)]
+    divs: HashMap<String, PlacementSettings>,
+}
+
+/// Settings for the spocs of a div.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PlacementSettings {
+    /// Frequency caps of the div's spocs, unless their creative sets them.
+    #[serde(default)]
+    pub caps: CapsOverride,
+    /// Domain affinity parameter set of the div's spocs, unless their creative
+    /// chooses one.
+    parameter_set: Option<String>,
+    /// How the div's sponsored collection is assembled.
+    #[serde(default)]
+    pub collection: CollectionRules,
+}
+
+impl PlacementSettings {
+    /// The div's parameter set, if the client settings don't have it: they
+    /// were reloaded without it after the placements were validated.
+    pub fn missing_parameter_set(&self, settings: &ClientSettings) -> Option<&str> {
+        self.parameter_set
+            .as_deref()
+            .filter(|name| !settings.has_parameter_set(name))
+    }
+
+    /// The parameter set of a spoc whose creative chose `requested`. Returns
+    /// whether `requested` is unknown, and was replaced by the default: the
+    /// parameter set of the client's experiment, or else the div's.
+    ///
+    /// The defaults are checked again, in case they were removed from
+    /// reloaded settings.
+    pub fn parameter_set(
+        &self,
+        requested: Option<String>,
+        experiment: Option<&str>,
+        settings: &ClientSettings,
+    ) -> (String, bool) {
+        match requested {
+            Some(name) if settings.has_parameter_set(&name) => (name, false),
+            requested => {
+                let default = experiment
+                    .into_iter()
+                    .chain(self.parameter_set.as_deref())
+                    .find(|name| settings.has_parameter_set(name))
+                    .unwrap_or(defaults::PARAMETER_SET);
+                (default.to_owned(), requested.is_some())
+            }
+        }
+    }
+}
+
+impl Placements {
+    /// Load placement settings from a JSON file, and validate them against
+    /// the client settings.
+    pub fn from_file<P: AsRef<Path>>(
+        path: P,
+        client_settings: &ClientSettings,
+    ) -> Result<Self, ProxyError> {
+        Self::from_json(&fs::read_to_string(path)?, client_settings)
+    }
+
+    pub fn from_json(json: &str, client_settings: &ClientSettings) -> Result<Self, ProxyError> {
+        let placements: Self = serde_json::from_str(json)?;
+        placements.validate(client_settings)?;
+        Ok(placements)
+    }
+
+    pub fn validate(&self, client_settings: &ClientSettings) -> Result<(), ProxyError> {
+        for (div, settings) in &self.divs {
+            settings
+                .caps
+                .validate()
+                .map_err(|err| ProxyError::new(format!("Div {}: {}", div, err)))?;
+            settings
+                .collection
+                .validate()
+                .map_err(|err| ProxyError::new(format!("Div {}: {}", div, err)))?;
+            if let Some(parameter_set) = &settings.parameter_set {
+                if !client_settings.has_parameter_set(parameter_set) {
+                    return Err(ProxyError::new(format!(
+                        "Div {}: unknown parameter set {}",
+                        div, parameter_set
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace the settings of the divs in `other`.
+    pub fn extend(&mut self, other: &Placements) {
+        self.divs.extend(
+            other
+                .divs
+                .iter()
+                .map(|(div, settings)| (div.clone(), settings.clone())),
+        );
+    }
+
+    /// The settings of a div.
+    pub fn get(&self, div: &str) -> &PlacementSettings {
+        lazy_static! {
+            static ref DEFAULT: PlacementSettings = PlacementSettings::default();
+        }
+        self.divs.get(div).unwrap_or(&DEFAULT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Placements;
+    use crate::adzerk::{caps::Cap, client_settings::ClientSettings, defaults};
+    use serde_json::{from_value, json};
+
+    #[test]
+    fn test_placement_caps() {
+        let placements: Placements = from_value(json!({
+            "divs": {"sponsored-topics": {"caps": {"campaign": {"period": 3600}}}}
+        }))
+        .unwrap();
+        let caps = placements
+            .get("sponsored-topics")
+            .caps
+            .apply(defaults::CAPS);
+        assert_eq!(
+            caps.campaign,
+            Cap {
+                count: 10,
+                period: 3600
+            }
+        );
+        assert_eq!(
+            placements.get("spocs").caps.apply(defaults::CAPS),
+            defaults::CAPS
+        );
+    }
+
+    #[test]
+    fn test_parameter_set() {
+        let placements: Placements = from_value(json!({
+            "divs": {"sponsored-topics": {"parameter_set": "fully-personalized"}}
+        }))
+        .unwrap();
+        let client_settings = ClientSettings::default();
+        let settings = placements.get("sponsored-topics");
+        let test_cases = [
+            (None, ("fully-personalized", false)),
+            (
+                Some("fully-personalized-domains"),
+                ("fully-personalized-domains", false),
+            ),
+            (Some("nonexistent"), ("fully-personalized", true)),
+        ];
+        for (requested, (expected, unknown)) in test_cases {
+            assert_eq!(
+                settings.parameter_set(requested.map(str::to_owned), None, &client_settings),
+                (expected.to_owned(), unknown)
+            );
+        }
+        assert_eq!(
+            placements
+                .get("spocs")
+                .parameter_set(None, None, &client_settings),
+            ("default".to_owned(), false)
+        );
+        assert_eq!(
+            settings.parameter_set(None, Some("fully-personalized-domains"), &client_settings),
+            ("fully-personalized-domains".to_owned(), false),
+            "experiments take precedence over the div"
+        );
+
+        // The div's parameter set was removed from the client settings.
+        let client_settings = ClientSettings::from_json(
+            &json!({
+                "feature_flags": {},
+                "spocsPerNewTabs": 1,
+                "domainAffinityParameterSets": {"default": {}},
+                "timeSegments": []
+            })
+            .to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            settings.parameter_set(None, Some("fully-personalized"), &client_settings),
+            ("default".to_owned(), false)
+        );
+    }
+
+    #[test]
+    fn test_invalid_placements() {
+        let placements: Placements = from_value(json!({
+            "divs": {"spocs": {"caps": {"lifetime": 0}}}
+        }))
+        .unwrap();
+        assert!(placements.validate(&ClientSettings::default()).is_err());
+        let placements: Placements = from_value(json!({
+            "divs": {"spocs": {"parameter_set": "nonexistent"}}
+        }))
+        .unwrap();
+        assert!(placements.validate(&ClientSettings::default()).is_err());
+        assert!(from_value::<Placements>(json!({"spocs": {}})).is_err());
+    }
+}
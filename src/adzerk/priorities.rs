@@ -0,0 +1,127 @@
+use super::defaults;
+use crate::errors::ProxyError;
+use serde::Deserialize;
+use std::{collections::HashMap, fs, path::Path};
+
+/// Mapping from Kevel priority IDs to the priority tiers sent to clients, per
+/// Kevel network. Lower tiers are more important.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PriorityMap {
+    /// Tier for decisions without a priority, or with an unmapped one.
+    default_priority: u32,
+    /// Tiers by priority ID, by network ID.
+    networks: HashMap<u32, HashMap<u32, u32>>,
+}
+
+/// The priority tier of a decision.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Priority {
+    Mapped(u32),
+    /// The decision has no priority.
+    Missing(u32),
+    /// The decision's priority ID is not in the mapping.
+    Unmapped(u32),
+}
+
+impl Priority {
+    pub fn tier(self) -> u32 {
+        match self {
+            Priority::Mapped(tier) | Priority::Missing(tier) | Priority::Unmapped(tier) => tier,
+        }
+    }
+}
+
+impl Default for PriorityMap {
+    fn default() -> Self {
+        defaults::PRIORITY_MAP.clone()
+    }
+}
+
+impl PriorityMap {
+    /// Load and validate a priority mapping from a JSON file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, ProxyError> {
+        Self::from_json(&fs::read_to_string(path)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, ProxyError> {
+        let map: Self = serde_json::from_str(json)?;
+        map.validate()
+    }
+
+    fn validate(self) -> Result<Self, ProxyError> {
+        if !self.networks.contains_key(&defaults::NETWORK_ID) {
+            return Err(ProxyError::new(format!(
+                "Priority mapping has no entry for network {}",
+                defaults::NETWORK_ID
+            )));
+        }
+        Ok(self)
+    }
+
+    /// The priority tier for a decision from a network.
+    pub fn priority(&self, network_id: u32, priority_id: Option<u32>) -> Priority {
+        match priority_id {
+            None => Priority::Missing(self.default_priority),
+            Some(priority_id) => self
+                .networks
+                .get(&network_id)
+                .and_then(|priorities| priorities.get(&priority_id))
+                .map_or(Priority::Unmapped(self.default_priority), |&tier| {
+                    Priority::Mapped(tier)
+                }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Priority, PriorityMap};
+    use crate::adzerk::defaults;
+    use serde_json::{from_value, json};
+
+    #[test]
+    fn test_default_mapping() {
+        let map = PriorityMap::default();
+        let test_cases = [
+            (Some(147517), Priority::Mapped(1)),
+            (Some(180843), Priority::Mapped(2)),
+            (Some(147518), Priority::Mapped(3)),
+            (Some(160722), Priority::Mapped(9)),
+            (Some(147520), Priority::Mapped(10)),
+            (Some(1), Priority::Unmapped(defaults::PRIORITY)),
+            (None, Priority::Missing(defaults::PRIORITY)),
+        ];
+        for (priority_id, expected) in test_cases {
+            assert_eq!(map.priority(defaults::NETWORK_ID, priority_id), expected);
+        }
+    }
+
+    #[test]
+    fn test_mapping_is_scoped_per_network() {
+        let map: PriorityMap = from_value(json!({
+            "default_priority": 50,
+            "networks": {"10250": {"1": 1}, "1234": {"1": 5}}
+        }))
+        .unwrap();
+        assert_eq!(map.priority(10250, Some(1)), Priority::Mapped(1));
+        assert_eq!(map.priority(1234, Some(1)), Priority::Mapped(5));
+        assert_eq!(map.priority(999, Some(1)), Priority::Unmapped(50));
+    }
+
+    #[test]
+    fn test_invalid_mappings() {
+        assert!(from_value::<PriorityMap>(json!({"networks": {"10250": {}}})).is_err());
+        assert!(from_value::<PriorityMap>(
+            json!({"default_priority": 1, "networks": {"10250": {"x": 1}}})
+        )
+        .is_err());
+        assert!(from_value::<PriorityMap>(json!({
+            "default_priority": 100,
+            "networks": {"1234": {}}
+        }))
+        .unwrap()
+        .validate()
+        .is_err());
+    }
+}
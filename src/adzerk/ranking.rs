@@ -0,0 +1,154 @@
+use crate::endpoints::spocs::Spoc;
+use serde::{Deserialize, Serialize};
+use std::{cmp::Ordering, fmt, sync::Arc};
+
+/// Orders the spocs of a div before they are sent to the client.
+pub trait Ranker: fmt::Debug + Send + Sync {
+    /// Sort spocs, best first. Ties must be broken using the seed, so the
+    /// order is stable for a client across requests.
+    fn rank(&self, spocs: &mut [Spoc], seed: u64);
+}
+
+/// The rankers that can be chosen in the settings, or by experiments.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RankerName {
+    /// Keep the order of Kevel's decisions.
+    Kevel,
+    /// Order by priority tier, then item score. See [`PriorityRanker`].
+    Priority,
+}
+
+impl RankerName {
+    pub fn default_ranker() -> Self {
+        RankerName::Kevel
+    }
+
+    pub fn ranker(self) -> Arc<dyn Ranker> {
+        match self {
+            RankerName::Kevel => Arc::new(KevelRanker),
+            RankerName::Priority => Arc::new(PriorityRanker),
+        }
+    }
+}
+
+/// The seed for tie-breaks of a client: the first 8 bytes of the SHA-256 of
+/// its `pocket_id`, read as a big-endian integer.
+pub fn seed(pocket_id: &str) -> u64 {
+    let digest = openssl::sha::sha256(pocket_id.as_bytes());
+    u64::from_be_bytes(digest[0..8].try_into().unwrap())
+}
+
+/// Keeps the order of Kevel's decisions.
+#[derive(Debug)]
+pub struct KevelRanker;
+
+impl Ranker for KevelRanker {
+    fn rank(&self, _spocs: &mut [Spoc], _seed: u64) {}
+}
+
+/// Orders spocs by priority tier (lowest first), then by item score (highest
+/// first). Remaining ties are broken by a hash of the seed and the ad ID.
+#[derive(Debug)]
+pub struct PriorityRanker;
+
+impl Ranker for PriorityRanker {
+    fn rank(&self, spocs: &mut [Spoc], seed: u64) {
+        spocs.sort_by(|a, b| {
+            a.priority
+                .cmp(&b.priority)
+                .then_with(|| {
+                    b.item_score
+                        .partial_cmp(&a.item_score)
+                        .unwrap_or(Ordering::Equal)
+                })
+                .then_with(|| tie_break(seed, a.id).cmp(&tie_break(seed, b.id)))
+        });
+    }
+}
+
+/// SplitMix64 of the seed combined with the ad ID. Unlike the standard library's
+/// hashers, the output is stable across Rust releases.
+fn tie_break(seed: u64, id: u32) -> u64 {
+    let mut z = seed
+        .wrapping_add(u64::from(id))
+        .wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{seed, RankerName};
+    use crate::{
+        adzerk::defaults,
+        endpoints::spocs::{Shim, Spoc},
+    };
+    use std::{collections::HashMap, sync::Arc};
+
+    fn spoc(id: u32, priority: u32, item_score: f64) -> Spoc {
+        Spoc {
+            id,
+            flight_id: id,
+            campaign_id: 1,
+            title: String::new(),
+            url: String::new(),
+            domain: String::new(),
+            excerpt: String::new(),
+            priority,
+            context: String::new(),
+            raw_image_src: String::new(),
+            image_src: String::new(),
+            shim: Shim {
+                click: String::new(),
+                impression: String::new(),
+                delete: String::new(),
+                save: String::new(),
+            },
+            parameter_set: defaults::PARAMETER_SET.to_owned(),
+            caps: defaults::CAPS,
+            domain_affinities: Arc::default(),
+            personalization_models: HashMap::new(),
+            min_score: 0.1,
+            item_score,
+            cta: None,
+            collection_title: None,
+            sponsor: None,
+            sponsored_by_override: None,
+            is_video: None,
+        }
+    }
+
+    fn ranked(name: RankerName, seed: u64, mut spocs: Vec<Spoc>) -> Vec<u32> {
+        name.ranker().rank(&mut spocs, seed);
+        spocs.iter().map(|spoc| spoc.id).collect()
+    }
+
+    #[test]
+    fn test_kevel_ranker_keeps_order() {
+        let spocs = vec![spoc(1, 100, 0.2), spoc(2, 1, 0.9)];
+        assert_eq!(ranked(RankerName::Kevel, 0, spocs), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_priority_ranker() {
+        let spocs = vec![
+            spoc(1, 100, 0.9),
+            spoc(2, 3, 0.2),
+            spoc(3, 1, 0.2),
+            spoc(4, 3, 0.5),
+        ];
+        assert_eq!(ranked(RankerName::Priority, 0, spocs), vec![3, 4, 2, 1]);
+    }
+
+    #[test]
+    fn test_tie_breaks_are_deterministic_per_client() {
+        let spocs = || (1..=8).map(|id| spoc(id, 1, 0.2)).collect::<Vec<_>>();
+        let seed_a = seed("{670e8b97-c271-483f-bcb0-4921b58cdb52}");
+        let seed_b = seed("{1fa4b1d4-5a86-4c8c-9b2a-4b2e4e3c8e1f}");
+        let order_a = ranked(RankerName::Priority, seed_a, spocs());
+        assert_eq!(order_a, ranked(RankerName::Priority, seed_a, spocs()));
+        assert_ne!(order_a, ranked(RankerName::Priority, seed_b, spocs()));
+    }
+}
@@ -1,5 +1,11 @@
-use crate::endpoints::spocs::{self, SpocsRequest};
+use crate::{
+    endpoints::spocs::{self, SpocsRequest},
+    errors::ProxyError,
+    privacy::{consent::ConsentDecision, mode::PrivacyMode, pseudonym::Pseudonymizer},
+};
+use chrono::{DateTime, Utc};
 use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
 
 #[derive(Serialize)]
 pub struct UserKey<'a> {
@@ -42,37 +48,45 @@ impl Placement {
     }
 }
 
+/// What the client requested for a div, before spares were added.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DivRequest {
+    /// Most spocs the client gets in the div.
+    pub count: usize,
+    /// Kevel network of the div's placement, whose priority IDs its decisions
+    /// have.
+    pub network_id: u32,
+}
+
 #[derive(Serialize)]
 pub struct User {
     key: String,
 }
 
+#[derive(Serialize)]
+pub struct Consent {
+    gdpr: bool,
+}
+
 // Adzerk Input Type
 #[derive(Serialize)]
 pub struct DecisionRequest {
     placements: Vec<Placement>,
-    user: User,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<User>,
     keywords: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    consent: Option<Consent>,
+    #[serde(rename = "flightViewTimes", skip_serializing_if = "BTreeMap::is_empty")]
+    flight_view_times: BTreeMap<u32, Vec<u64>>,
 }
 
-impl From<SpocsRequest> for DecisionRequest {
-    fn from(spoc: SpocsRequest) -> Self {
+impl DecisionRequest {
+    pub fn new(spoc: SpocsRequest, keywords: Vec<String>) -> Self {
         // __add_targeting
-        let user = User {
+        let user = Some(User {
             key: spoc.pocket_id,
-        };
-        let mut keywords = vec![];
-
-        if let Some(country) = spoc.country {
-            keywords.push(country);
-            if let Some(region) = spoc.region {
-                keywords.push(format!(
-                    "{country}-{region}",
-                    country = keywords[0],
-                    region = region
-                ));
-            }
-        }
+        });
 
         // __add_placements && __add_site
         let placements = if spoc.placements.is_empty() {
@@ -88,14 +102,98 @@ impl From<SpocsRequest> for DecisionRequest {
             placements,
             user,
             keywords,
+            consent: None,
+            flight_view_times: spoc.flight_view_times,
+        }
+    }
+
+    /// Remove the user key if the privacy mode doesn't allow one.
+    pub fn apply_privacy_mode(&mut self, privacy_mode: PrivacyMode) {
+        if !privacy_mode.allows_user_key() {
+            self.user = None;
+        }
+    }
+
+    /// Replace the client's `pocket_id` with the user key derived from it.
+    pub fn pseudonymize(
+        &mut self,
+        pseudonymizer: &Pseudonymizer,
+        now: DateTime<Utc>,
+    ) -> Result<(), ProxyError> {
+        if let Some(user) = &mut self.user {
+            user.key = pseudonymizer.user_key(&user.key, now)?;
+        }
+        Ok(())
+    }
+
+    /// Attach Kevel's consent fields, and drop or rotate the user key if the
+    /// client hasn't consented.
+    pub fn apply_consent(
+        &mut self,
+        decision: ConsentDecision,
+        pseudonymizer: &Pseudonymizer,
+        now: DateTime<Utc>,
+    ) -> Result<(), ProxyError> {
+        self.consent = decision.gdpr.map(|gdpr| Consent { gdpr });
+        if let Some(user) = self.user.take() {
+            self.user = decision
+                .user_key_policy
+                .apply(user.key, pseudonymizer, now)?
+                .map(|key| User { key });
+        }
+        Ok(())
+    }
+
+    /// Names of the divs that spocs are requested for.
+    pub fn div_names(&self) -> impl Iterator<Item = &str> {
+        self.placements.iter().map(|p| p.div_name.as_str())
+    }
+
+    /// Request `spares` more spocs for each placement than the client gets, to
+    /// replace spocs that are removed from the response. Returns what the
+    /// client requested for each div.
+    pub fn add_spares(&mut self, spares: u32) -> HashMap<String, DivRequest> {
+        self.placements
+            .iter_mut()
+            .map(|placement| {
+                let request = DivRequest {
+                    count: placement.count as usize,
+                    network_id: placement.network_id,
+                };
+                placement.count += spares;
+                (placement.div_name.clone(), request)
+            })
+            .collect()
+    }
+
+    /// Restrict the number of placements, and the number of spocs requested
+    /// for each placement.
+    pub fn limit(&mut self, max_placements: Option<usize>, max_spocs: Option<u32>) {
+        if let Some(max_placements) = max_placements {
+            self.placements.truncate(max_placements);
+        }
+        if let Some(max_spocs) = max_spocs {
+            for placement in &mut self.placements {
+                placement.count = placement.count.min(max_spocs);
+            }
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::DecisionRequest;
-    use crate::{adzerk::defaults, endpoints::spocs::SpocsRequest};
+    use super::{DecisionRequest, DivRequest};
+    use crate::{
+        adzerk::defaults,
+        endpoints::spocs::SpocsRequest,
+        privacy::{
+            consent::{ConsentPolicy, UserKeyPolicy},
+            mode::PrivacyMode,
+            pseudonym::Pseudonymizer,
+        },
+        targeting::TargetingContext,
+    };
+    use chrono::{TimeZone, Utc};
     use serde_json::{from_value, json, to_value};
 
     #[test]
@@ -145,7 +243,178 @@ mod tests {
             },
             "keywords": ["US", "US-IL"]
         });
-        let actual_decision_request = to_value(DecisionRequest::from(spoc_request)).unwrap();
+        let targeting = TargetingContext {
+            country: spoc_request.country.clone(),
+            region: spoc_request.region.clone(),
+            ..TargetingContext::default()
+        };
+        let keywords = defaults::KEYWORD_TEMPLATES.render(&targeting, Utc::now());
+        let actual_decision_request =
+            to_value(DecisionRequest::new(spoc_request, keywords)).unwrap();
         assert_eq!(actual_decision_request, expected_decision_request);
     }
+
+    #[test]
+    fn test_limit() {
+        let spoc_request: SpocsRequest = from_value(json!({
+            "consumer_key": "40249-e88c401e1b1f2242d9e441c4",
+            "placements": [{"name": "spocs"}, {"name": "sponsored-topics"}],
+            "pocket_id": "{670e8b97-c271-483f-bcb0-4921b58cdb52}",
+            "version": 2
+        }))
+        .unwrap();
+        let mut decision_request = DecisionRequest::new(spoc_request, vec![]);
+        decision_request.limit(Some(1), Some(3));
+        let actual = to_value(&decision_request).unwrap();
+        assert_eq!(actual["placements"].as_array().unwrap().len(), 1);
+        assert_eq!(actual["placements"][0]["count"], 3);
+        assert_eq!(decision_request.div_names().collect::<Vec<_>>(), ["spocs"]);
+
+        let divs = decision_request.add_spares(2);
+        assert_eq!(
+            divs["spocs"],
+            DivRequest {
+                count: 3,
+                network_id: defaults::NETWORK_ID
+            }
+        );
+        let actual = to_value(&decision_request).unwrap();
+        assert_eq!(actual["placements"][0]["count"], 5);
+    }
+
+    /// Show which user key and consent fields are sent to Kevel, depending on
+    /// the client's country and consent signal.
+    #[test]
+    fn test_consent_by_jurisdiction() {
+        let pocket_id = "{670e8b97-c271-483f-bcb0-4921b58cdb52}";
+        let pseudonymizer = Pseudonymizer::from_salts(&["2022-01-01:secret"], false).unwrap();
+        let now = Utc.ymd(2022, 6, 1).and_hms(12, 0, 0);
+        let rotated_key = pseudonymizer.daily_key(pocket_id, now).unwrap();
+        let test_cases = [
+            (
+                "US",
+                json!(null),
+                UserKeyPolicy::Drop,
+                json!({"key": pocket_id}),
+                json!(null),
+            ),
+            (
+                "US",
+                json!({"gdpr": true}),
+                UserKeyPolicy::Drop,
+                json!({"key": pocket_id}),
+                json!({"gdpr": true}),
+            ),
+            (
+                "US",
+                json!({"gdpr": false}),
+                UserKeyPolicy::Drop,
+                json!(null),
+                json!({"gdpr": false}),
+            ),
+            (
+                "DE",
+                json!(null),
+                UserKeyPolicy::Drop,
+                json!(null),
+                json!({"gdpr": false}),
+            ),
+            (
+                "DE",
+                json!(null),
+                UserKeyPolicy::Keep,
+                json!({"key": pocket_id}),
+                json!({"gdpr": false}),
+            ),
+            (
+                "DE",
+                json!(null),
+                UserKeyPolicy::Rotate,
+                json!({"key": rotated_key}),
+                json!({"gdpr": false}),
+            ),
+            (
+                "GB",
+                json!({"gdpr": true}),
+                UserKeyPolicy::Drop,
+                json!({"key": pocket_id}),
+                json!({"gdpr": true}),
+            ),
+        ];
+        for (country, consent, user_key_policy, expected_user, expected_consent) in test_cases {
+            let spoc_request: SpocsRequest = from_value(json!({
+                "consumer_key": "40249-e88c401e1b1f2242d9e441c4",
+                "pocket_id": pocket_id,
+                "version": 2,
+                "country": country,
+                "consent": consent,
+            }))
+            .unwrap();
+            let policy = ConsentPolicy {
+                user_key_policy,
+                ..ConsentPolicy::default()
+            };
+            let decision = policy.evaluate(Some(country), spoc_request.consent);
+            let mut decision_request = DecisionRequest::new(spoc_request, vec![]);
+            decision_request
+                .apply_consent(decision, &pseudonymizer, now)
+                .unwrap();
+
+            let actual = to_value(decision_request).unwrap();
+            assert_eq!(
+                actual.get("user").unwrap_or(&json!(null)),
+                &expected_user,
+                "user for {} with consent {} and policy {:?}",
+                country,
+                consent,
+                user_key_policy
+            );
+            assert_eq!(
+                actual.get("consent").unwrap_or(&json!(null)),
+                &expected_consent,
+                "consent for {} with consent {} and policy {:?}",
+                country,
+                consent,
+                user_key_policy
+            );
+        }
+    }
+
+    #[test]
+    fn test_pseudonymize() {
+        let pocket_id = "{670e8b97-c271-483f-bcb0-4921b58cdb52}";
+        let now = Utc.ymd(2022, 6, 1).and_hms(12, 0, 0);
+        let pseudonymizer = Pseudonymizer::from_salts(&["2022-01-01:old-secret"], false).unwrap();
+        let spoc_request: SpocsRequest = from_value(json!({
+            "consumer_key": "40249-e88c401e1b1f2242d9e441c4",
+            "pocket_id": pocket_id,
+            "version": 2,
+        }))
+        .unwrap();
+        let mut decision_request = DecisionRequest::new(spoc_request, vec![]);
+        decision_request.pseudonymize(&pseudonymizer, now).unwrap();
+        assert_eq!(
+            to_value(&decision_request).unwrap()["user"],
+            json!({"key": "d4a07c4582530218fd66903e34eda3df1b0a4a64eda2cff832d3148586c58ac5"})
+        );
+    }
+
+    #[test]
+    fn test_flight_view_times_in_contextual_only_mode() {
+        let now = Utc::now().timestamp();
+        let view_times = json!({"1234": [now - 7200, now - 3600], "5678": [now]});
+        let spoc_request: SpocsRequest = from_value(json!({
+            "consumer_key": "40249-e88c401e1b1f2242d9e441c4",
+            "pocket_id": "{670e8b97-c271-483f-bcb0-4921b58cdb52}",
+            "version": 2,
+            "flight_view_times": view_times,
+        }))
+        .unwrap();
+        let mut decision_request = DecisionRequest::new(spoc_request, vec![]);
+        decision_request.apply_privacy_mode(PrivacyMode::ContextualOnly);
+
+        let actual = to_value(decision_request).unwrap();
+        assert_eq!(actual.get("user"), None);
+        assert_eq!(actual["flightViewTimes"], view_times);
+    }
 }
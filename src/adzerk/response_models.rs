@@ -1,6 +1,14 @@
-use super::defaults;
+use super::{
+    caps::{CapsOverride, CreativeCaps},
+    collections::{CollectionRules, CollectionStats},
+    defaults,
+    placements::PlacementSettings,
+    priorities::Priority,
+};
 use crate::{
-    endpoints::spocs::{Collection, Shim, Spoc, SpocsList, SpocsResponse},
+    endpoints::spocs::{
+        Collection, ResponseOptions, ResponseStats, Shim, Spoc, SpocsList, SpocsResponse,
+    },
     errors::ProxyError,
 };
 use actix_web::{http::Uri, web::Query};
@@ -8,7 +16,10 @@ use lazy_static::lazy_static;
 use regex::Regex;
 use serde::Deserialize;
 use serde_json::Value;
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
 
 // AdZerk Output Type
 #[derive(Deserialize)]
@@ -54,6 +65,18 @@ pub struct Data {
     ct_collection_title: Option<String>,
     ct_is_video: Option<String>,
     ct_sponsored_by_override: Option<String>,
+    #[serde(rename(deserialize = "ctParameter_set"))]
+    ct_parameter_set: Option<String>,
+    #[serde(rename(deserialize = "ctCaps_lifetime"))]
+    ct_caps_lifetime: Option<String>,
+    #[serde(rename(deserialize = "ctCaps_campaignCount"))]
+    ct_caps_campaign_count: Option<String>,
+    #[serde(rename(deserialize = "ctCaps_campaignPeriod"))]
+    ct_caps_campaign_period: Option<String>,
+    #[serde(rename(deserialize = "ctCaps_flightCount"))]
+    ct_caps_flight_count: Option<String>,
+    #[serde(rename(deserialize = "ctCaps_flightPeriod"))]
+    ct_caps_flight_period: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -65,47 +88,117 @@ pub struct Event {
 impl SpocsResponse {
     pub fn from_decision_response(
         decision_response: DecisionResponse,
-        version: u32,
+        options: &ResponseOptions,
     ) -> Result<Self, ProxyError> {
-        let divs = decision_response
+        let mut stats = ResponseStats::default();
+        let mut divs = decision_response
             .decisions
             .into_iter()
             .map(|(div, decisions)| {
+                let placement = options.placements.get(&div);
+                let network_id = options
+                    .divs
+                    .get(&div)
+                    .map_or(defaults::NETWORK_ID, |request| request.network_id);
                 let spocs: Result<Vec<_>, ProxyError> = decisions
                     .into_iter()
                     .flatten()
-                    .map(TryInto::try_into)
+                    // Kevel should not return excluded flights and ads, but
+                    // don't rely on it.
+                    .filter(|decision| {
+                        !options.blocked_flights.contains(&decision.flight_id)
+                            && !options.blocked_ads.contains(&decision.ad_id)
+                    })
+                    .map(|decision| {
+                        Spoc::from_decision(decision, options, placement, network_id, &mut stats)
+                    })
                     .collect();
-                let spoc_list = SpocsList::from_spocs(spocs?, version);
-                Ok((div, spoc_list))
+                let mut spocs = spocs?;
+                if let Some(blocked_domains) = &options.blocked_domains {
+                    let count = spocs.len();
+                    spocs.retain(|spoc: &Spoc| !blocked_domains.contains(&spoc.domain));
+                    stats.blocked_domains += count - spocs.len();
+                }
+                Ok((div, spocs))
             })
-            .collect::<Result<_, ProxyError>>()?;
+            .collect::<Result<Vec<_>, ProxyError>>()?;
+        for (_, spocs) in &mut divs {
+            options.ranker.rank(spocs, options.ranking_seed);
+        }
+        stats.diversity = options
+            .diversity_rules
+            .apply(&mut divs, |div| options.divs.get(div).map(|r| r.count));
+        let divs = divs
+            .into_iter()
+            .map(|(div, spocs)| {
+                let spoc_list = if options.supports_collections {
+                    let rules = &options.placements.get(&div).collection;
+                    SpocsList::from_spocs(spocs, rules, &mut stats.collections)
+                } else {
+                    SpocsList::Standard(spocs)
+                };
+                (div, spoc_list)
+            })
+            .collect();
         Ok(SpocsResponse {
-            settings: &defaults::SETTINGS,
+            settings: options.settings.value(),
             divs,
+            experiments: BTreeMap::new(),
+            stats,
         })
     }
 }
 
+impl SpocsResponse {
+    /// A response without any spocs, for clients that must not get sponsored
+    /// content.
+    pub fn empty<'a>(settings: Arc<Value>, div_names: impl Iterator<Item = &'a str>) -> Self {
+        SpocsResponse {
+            settings,
+            divs: div_names
+                .map(|div| (div.to_owned(), SpocsList::Standard(vec![])))
+                .collect(),
+            experiments: BTreeMap::new(),
+            stats: ResponseStats::default(),
+        }
+    }
+}
+
 impl SpocsList {
-    fn from_spocs(mut spocs: Vec<Spoc>, version: u32) -> Self {
-        if version >= 2 && !spocs.is_empty() && spocs.iter().all(|s| s.collection_title.is_some()) {
-            for spoc in spocs.iter_mut().skip(1) {
-                spoc.collection_title = None;
-            }
-            let spoc = &mut spocs[0];
-            let collection = Collection {
-                title: spoc.collection_title.take().unwrap(),
-                flight_id: spoc.flight_id,
-                sponsor: spoc.sponsor.clone(),
-                context: format_context(spoc.sponsor.as_deref()),
-                items: spocs,
-            };
-            SpocsList::Collection(collection)
-        } else {
-            SpocsList::Standard(spocs)
+    /// Number of spocs in the list.
+    pub fn spoc_count(&self) -> usize {
+        match self {
+            SpocsList::Standard(spocs) => spocs.len(),
+            SpocsList::Collection(collection) => collection.items.len() + collection.spocs.len(),
         }
     }
+
+    /// A collection of the spocs selected by the rules, with the other spocs as
+    /// a standard list, or a standard list of all the spocs if there is none.
+    fn from_spocs(spocs: Vec<Spoc>, rules: &CollectionRules, stats: &mut CollectionStats) -> Self {
+        let selected = match rules.select(&spocs, stats) {
+            Some(selected) => selected,
+            None => return SpocsList::Standard(spocs),
+        };
+        let mut spocs: Vec<Option<Spoc>> = spocs.into_iter().map(Some).collect();
+        let mut items: Vec<Spoc> = selected
+            .into_iter()
+            .filter_map(|i| spocs[i].take())
+            .collect();
+        let remainder = spocs.into_iter().flatten().collect();
+        for item in items.iter_mut().skip(1) {
+            item.collection_title = None;
+        }
+        let first = &mut items[0];
+        SpocsList::Collection(Collection {
+            title: first.collection_title.take().unwrap(),
+            flight_id: first.flight_id,
+            sponsor: first.sponsor.clone(),
+            context: format_context(first.sponsor.as_deref()),
+            items,
+            spocs: remainder,
+        })
+    }
 }
 
 fn format_context(sponsor: Option<&str>) -> String {
@@ -114,13 +207,52 @@ fn format_context(sponsor: Option<&str>) -> String {
         .unwrap_or_default()
 }
 
-impl TryFrom<Decision> for Spoc {
-    type Error = ProxyError;
-
-    fn try_from(decision: Decision) -> Result<Self, Self::Error> {
+impl Spoc {
+    fn from_decision(
+        decision: Decision,
+        options: &ResponseOptions,
+        placement: &PlacementSettings,
+        network_id: u32,
+        stats: &mut ResponseStats,
+    ) -> Result<Self, ProxyError> {
+        let priority = options
+            .priority_map
+            .priority(network_id, decision.priority_id);
+        if let (Priority::Unmapped(_), Some(priority_id)) = (priority, decision.priority_id) {
+            *stats.unmapped_priorities.entry(priority_id).or_default() += 1;
+        }
         let [contents] = decision.contents;
         let custom_data = contents.data;
+        // Caps set on the creative take precedence over the div's caps.
+        let (creative_caps, invalid_caps) = CapsOverride::from_creative(CreativeCaps {
+            lifetime: custom_data.ct_caps_lifetime,
+            campaign_count: custom_data.ct_caps_campaign_count,
+            campaign_period: custom_data.ct_caps_campaign_period,
+            flight_count: custom_data.ct_caps_flight_count,
+            flight_period: custom_data.ct_caps_flight_period,
+        });
+        stats.invalid_caps += invalid_caps;
+        let (parameter_set, unknown_parameter_set) = placement.parameter_set(
+            custom_data.ct_parameter_set,
+            options.parameter_set.as_deref(),
+            &options.settings,
+        );
+        if unknown_parameter_set {
+            stats.unknown_parameter_sets += 1;
+        }
         let mut events_map = EventsMap::new(decision.events)?;
+        let personalization_models = match contents.body {
+            Some(body) => {
+                let (models, error) = options.model_prefixes.parse(&body);
+                if let Some(err) = error {
+                    stats
+                        .malformed_models
+                        .push((decision.ad_id, err.to_string()));
+                }
+                models
+            }
+            None => HashMap::new(),
+        };
         let spoc = Spoc {
             id: decision.ad_id,
             flight_id: decision.flight_id,
@@ -129,7 +261,7 @@ impl TryFrom<Decision> for Spoc {
             url: custom_data.ct_url,
             domain: custom_data.ct_domain,
             excerpt: custom_data.ct_excerpt,
-            priority: map_priority(decision.priority_id),
+            priority: priority.tier(),
             context: format_context(custom_data.ct_sponsor.as_deref()),
             image_src: get_cdn_image(&custom_data.ct_fullimagepath)?,
             raw_image_src: custom_data.ct_fullimagepath,
@@ -139,10 +271,12 @@ impl TryFrom<Decision> for Spoc {
                 delete: events_map.remove(17)?,
                 save: events_map.remove(20)?,
             },
-            parameter_set: "default",
-            caps: &defaults::CAPS,
-            domain_affinities: get_domain_affinities(custom_data.ct_domain_affinities),
-            personalization_models: get_personalization_models(contents.body)?,
+            parameter_set,
+            caps: creative_caps.apply(placement.caps.apply(defaults::CAPS)),
+            domain_affinities: options
+                .domain_affinities
+                .get(custom_data.ct_domain_affinities.as_deref()),
+            personalization_models,
             min_score: get_score(custom_data.ct_min_score, 0.1),
             item_score: get_score(custom_data.ct_item_score, 0.2),
             cta: custom_data.ct_cta,
@@ -181,19 +315,6 @@ fn get_score(score: Option<String>, default: f64) -> f64 {
     score.and_then(|s| s.parse().ok()).unwrap_or(default)
 }
 
-fn map_priority(priority_id: Option<u32>) -> u32 {
-    priority_id
-        .map(|priority_id| match priority_id {
-            147517 => 1,
-            180843 => 2,
-            147518 => 3,
-            160722 => 9,
-            147520 => 10,
-            _ => defaults::PRIORITY,
-        })
-        .unwrap_or(defaults::PRIORITY)
-}
-
 fn get_cdn_image(full_image_path: &str) -> Result<String, ProxyError> {
     match full_image_path.parse::<Uri>()?.host() {
         Some(domain) if domain.ends_with(".zkcdn.net") || domain == "zkcdn.net" => {
@@ -213,36 +334,6 @@ fn get_cdn_image(full_image_path: &str) -> Result<String, ProxyError> {
     }
 }
 
-fn get_domain_affinities(name: Option<String>) -> &'static HashMap<String, u32> {
-    let affinities: &HashMap<_, _> = if cfg!(test) {
-        &defaults::TEST_DOMAIN_AFFINITIES
-    } else {
-        &defaults::DOMAIN_AFFINITIES
-    };
-    name.and_then(|name| affinities.get(&name))
-        .unwrap_or(&defaults::EMPTY_DOMAIN_AFFINITIES)
-}
-
-fn get_personalization_models(body: Option<String>) -> Result<HashMap<String, u32>, ProxyError> {
-    lazy_static! {
-        static ref TRUE_VALUES: [Value; 2] = [Value::from(true), Value::from("true")];
-    }
-    match body {
-        None => Ok(HashMap::new()),
-        Some(body) => {
-            let map: HashMap<String, Value> = serde_json::from_str(&body)?;
-            let result: HashMap<String, u32> = map
-                .into_iter()
-                .filter_map(|(topic, flag)| match topic.strip_prefix("topic_") {
-                    Some(topic) if TRUE_VALUES.contains(&flag) => Some((topic.to_owned(), 1)),
-                    _ => None,
-                })
-                .collect();
-            Ok(result)
-        }
-    }
-}
-
 fn clean_sponsored_by_override(mut sponsored_by_override: String) -> String {
     lazy_static! {
         static ref REGEX: Regex = Regex::new(r"(?i)^\s*(blank|empty)\s*$").unwrap();
@@ -291,14 +382,25 @@ fn tracking_url_to_shim(url: String) -> Result<String, ProxyError> {
 #[cfg(test)]
 mod tests {
     use super::{
-        clean_sponsored_by_override, get_cdn_image, get_is_video, get_personalization_models,
-        tracking_url_to_shim, Decision,
+        clean_sponsored_by_override, get_cdn_image, get_is_video, tracking_url_to_shim, Decision,
+        DecisionResponse,
+    };
+    use crate::{
+        adzerk::{
+            affinities::DomainAffinities,
+            defaults,
+            placements::{PlacementSettings, Placements},
+            priorities::PriorityMap,
+            ranking::RankerName,
+            request_models::DivRequest,
+        },
+        endpoints::spocs::{ResponseOptions, ResponseStats, Spoc, SpocsList, SpocsResponse},
+        privacy::blocked_domains::DomainBloomFilter,
     };
-    use crate::endpoints::spocs::Spoc;
     use assert_json_diff::assert_json_eq;
     use lazy_static::lazy_static;
     use serde_json::{json, Value};
-    use std::collections::HashMap;
+    use std::{collections::HashMap, sync::Arc};
 
     fn mock_decision(index: usize) -> Decision {
         lazy_static! {
@@ -334,6 +436,20 @@ mod tests {
                     Some("Brought by blank".to_owned());
             }
             10 => decision.priority_id = None,
+            11 => {
+                let data = &mut decision.contents[0].data;
+                data.ct_caps_lifetime = Some("20".to_owned());
+                data.ct_caps_flight_count = Some("3".to_owned());
+                data.ct_caps_flight_period = Some("soon".to_owned());
+            }
+            12 => {
+                decision.contents[0].data.ct_parameter_set = Some("fully-personalized".to_owned())
+            }
+            13 => decision.contents[0].data.ct_parameter_set = Some("bogus".to_owned()),
+            14 => decision.contents[0].body = Some(r#"{"topic_fun": 20, "#.to_owned()),
+            15 => {
+                decision.contents[0].body = Some(r#"{"topic_fun": 20, "topic_x": {}}"#.to_owned())
+            }
             _ => panic!("invalid mock_decision index"),
         }
         decision
@@ -368,6 +484,13 @@ mod tests {
             8 => spoc["sponsored_by_override"] = json!(""),
             9 => spoc["sponsored_by_override"] = json!("Brought by blank"),
             10 => spoc["priority"] = json!(100),
+            11 => {
+                spoc["caps"]["lifetime"] = json!(20);
+                spoc["caps"]["flight"]["count"] = json!(3);
+            }
+            12 => spoc["parameter_set"] = json!("fully-personalized"),
+            13 | 14 => {}
+            15 => spoc["personalization_models"] = json!({"fun": 20}),
             _ => panic!("invalid mock_spoc index"),
         }
         spoc
@@ -375,12 +498,61 @@ mod tests {
 
     #[test]
     fn test_decision_to_spoc() {
-        for index in [2, 3, 5, 6, 7, 8, 9, 10] {
+        let options = ResponseOptions {
+            domain_affinities: Arc::new(
+                DomainAffinities::from_json(r#"{"publishers": {"example.com": 1}}"#).unwrap(),
+            ),
+            ..ResponseOptions::default()
+        };
+        let mut stats = ResponseStats::default();
+        for index in [2, 3, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15] {
             let decision = mock_decision(index);
-            let spoc: Spoc = decision.try_into().unwrap();
+            let spoc = Spoc::from_decision(
+                decision,
+                &options,
+                &PlacementSettings::default(),
+                defaults::NETWORK_ID,
+                &mut stats,
+            )
+            .unwrap();
             let spoc_json: Value = json!(spoc);
             assert_json_eq!(spoc_json, mock_spoc(index));
         }
+        assert_eq!(stats.invalid_caps, 1, "the flight period of spoc 11");
+        assert_eq!(
+            stats.unknown_parameter_sets, 1,
+            "the parameter set of spoc 13"
+        );
+        let malformed: Vec<u32> = stats.malformed_models.iter().map(|(id, _)| *id).collect();
+        assert_eq!(malformed, vec![14, 15]);
+    }
+
+    #[test]
+    fn test_placement_caps() {
+        let decision_response = DecisionResponse {
+            decisions: HashMap::from([
+                ("spocs".to_owned(), Some(vec![mock_decision(2)])),
+                ("sponsored-topics".to_owned(), Some(vec![mock_decision(11)])),
+            ]),
+        };
+        let placements: Placements = serde_json::from_value(json!({
+            "divs": {"sponsored-topics": {"caps": {"lifetime": 5, "flight": {"count": 1}}}}
+        }))
+        .unwrap();
+        let options = ResponseOptions {
+            placements: Arc::new(placements),
+            ..ResponseOptions::default()
+        };
+
+        let response = SpocsResponse::from_decision_response(decision_response, &options).unwrap();
+        let caps = |div: &str| match &response.divs[div] {
+            SpocsList::Standard(spocs) => spocs[0].caps,
+            SpocsList::Collection(_) => panic!("unexpected collection"),
+        };
+        assert_eq!(caps("spocs"), defaults::CAPS);
+        // The creative's caps win over the placement's.
+        let caps = caps("sponsored-topics");
+        assert_eq!((caps.lifetime, caps.flight.count), (20, 3));
     }
 
     #[test]
@@ -436,14 +608,163 @@ mod tests {
     }
 
     #[test]
-    fn test_get_personalization_models() {
-        let test_string = Some(r#"{"topic_fun": true}"#.to_owned());
-        let mut test_result: HashMap<String, u32> = HashMap::new();
-        test_result.insert("fun".to_owned(), 1);
+    fn test_blocked_flights_and_ads_are_filtered() {
+        let decisions = (2..=9).map(mock_decision).collect();
+        let mut blocked_decision = mock_decision(2);
+        blocked_decision.ad_id = 100;
+        blocked_decision.flight_id = 200;
+        let decision_response = DecisionResponse {
+            decisions: HashMap::from([
+                ("spocs".to_owned(), Some(decisions)),
+                ("blocked".to_owned(), Some(vec![blocked_decision])),
+            ]),
+        };
+        let options = ResponseOptions {
+            blocked_flights: [200].into(),
+            blocked_ads: [3, 5].into(),
+            ranker: RankerName::Kevel.ranker(),
+            ..ResponseOptions::default()
+        };
+
+        let response = SpocsResponse::from_decision_response(decision_response, &options).unwrap();
+        let ids = |div: &str| match &response.divs[div] {
+            SpocsList::Standard(spocs) => spocs.iter().map(|s| s.id).collect::<Vec<_>>(),
+            SpocsList::Collection(_) => panic!("unexpected collection"),
+        };
+        assert_eq!(ids("spocs"), vec![2, 4, 6, 7, 8, 9]);
+        assert_eq!(ids("blocked"), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn test_priorities_of_the_div_network() {
+        let decision_response = DecisionResponse {
+            decisions: HashMap::from([
+                ("spocs".to_owned(), Some(vec![mock_decision(2)])),
+                ("other".to_owned(), Some(vec![mock_decision(2)])),
+            ]),
+        };
+        let options = ResponseOptions {
+            priority_map: Arc::new(
+                PriorityMap::from_json(
+                    &json!({
+                        "default_priority": 100,
+                        "networks": {
+                            defaults::NETWORK_ID.to_string(): {"147517": 1},
+                            "7": {"147517": 5}
+                        }
+                    })
+                    .to_string(),
+                )
+                .unwrap(),
+            ),
+            divs: HashMap::from([(
+                "other".to_owned(),
+                DivRequest {
+                    count: 1,
+                    network_id: 7,
+                },
+            )]),
+            ..ResponseOptions::default()
+        };
 
-        assert_eq!(
-            get_personalization_models(test_string).unwrap(),
-            test_result
-        );
+        let response = SpocsResponse::from_decision_response(decision_response, &options).unwrap();
+        let priority = |div: &str| match &response.divs[div] {
+            SpocsList::Standard(spocs) => spocs[0].priority,
+            SpocsList::Collection(_) => panic!("unexpected collection"),
+        };
+        assert_eq!(priority("spocs"), 1);
+        assert_eq!(priority("other"), 5);
+        assert!(response.stats.unmapped_priorities.is_empty());
+    }
+
+    #[test]
+    fn test_collections() {
+        let decision_response = |decisions| DecisionResponse {
+            decisions: HashMap::from([("spocs".to_owned(), Some(decisions))]),
+        };
+        let options = ResponseOptions {
+            supports_collections: true,
+            ranker: RankerName::Kevel.ranker(),
+            ..ResponseOptions::default()
+        };
+        let response = SpocsResponse::from_decision_response(
+            decision_response(vec![mock_decision(4)]),
+            &options,
+        )
+        .unwrap();
+        match &response.divs["spocs"] {
+            SpocsList::Collection(collection) => {
+                assert_eq!(collection.title, "Best of the Web");
+                assert_eq!(
+                    collection.items.iter().map(|s| s.id).collect::<Vec<_>>(),
+                    vec![4]
+                );
+            }
+            SpocsList::Standard(_) => panic!("expected a collection"),
+        }
+        assert_eq!(response.stats.collections.assembled, 1);
+
+        // A standard spoc in the div is kept, in a standard list along with
+        // the collection.
+        let decision_response = || decision_response(vec![mock_decision(2), mock_decision(4)]);
+        let response =
+            SpocsResponse::from_decision_response(decision_response(), &options).unwrap();
+        match &response.divs["spocs"] {
+            SpocsList::Collection(collection) => {
+                assert_eq!(
+                    collection.items.iter().map(|s| s.id).collect::<Vec<_>>(),
+                    vec![4]
+                );
+                assert_eq!(
+                    collection.spocs.iter().map(|s| s.id).collect::<Vec<_>>(),
+                    vec![2]
+                );
+            }
+            SpocsList::Standard(_) => panic!("expected a collection"),
+        }
+        assert_eq!(response.divs["spocs"].spoc_count(), 2);
+        assert_eq!(response.stats.collections.remainder, 1);
+
+        let options = ResponseOptions {
+            supports_collections: false,
+            ..options
+        };
+        let response =
+            SpocsResponse::from_decision_response(decision_response(), &options).unwrap();
+        assert_eq!(response.divs["spocs"].spoc_count(), 2);
+        assert_eq!(response.stats.collections.assembled, 0);
+    }
+
+    #[test]
+    fn test_blocked_domains_are_filtered() {
+        let mut blocked_decision = mock_decision(3);
+        blocked_decision.contents[0].data.ct_domain = "blocked.example".to_owned();
+        let decision_response = DecisionResponse {
+            decisions: HashMap::from([(
+                "spocs".to_owned(),
+                Some(vec![mock_decision(2), blocked_decision]),
+            )]),
+        };
+        // A 64-bit filter with one hash, in which "blocked.example" sets bit 2.
+        let bits = [1 << 2, 0, 0, 0, 0, 0, 0, 0];
+        let options = ResponseOptions {
+            blocked_domains: Some(
+                serde_json::from_value::<DomainBloomFilter>(json!({
+                    "bits": openssl::base64::encode_block(&bits),
+                    "hashes": 1
+                }))
+                .unwrap(),
+            ),
+            ..ResponseOptions::default()
+        };
+
+        let response = SpocsResponse::from_decision_response(decision_response, &options).unwrap();
+        match &response.divs["spocs"] {
+            SpocsList::Standard(spocs) => {
+                assert_eq!(spocs.iter().map(|s| s.id).collect::<Vec<_>>(), vec![2])
+            }
+            SpocsList::Collection(_) => panic!("unexpected collection"),
+        }
+        assert_eq!(response.stats.blocked_domains, 1);
     }
 }
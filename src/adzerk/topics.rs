@@ -0,0 +1,122 @@
+use super::defaults;
+use crate::errors::ProxyError;
+use serde::Deserialize;
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs,
+    path::Path,
+};
+
+/// Mapping from the IAB Content Taxonomy IDs of topics inferred by the client
+/// to the Kevel keywords used to target them.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "TopicTaxonomyConfig")]
+pub struct TopicTaxonomy {
+    topics: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TopicTaxonomyConfig {
+    topics: HashMap<String, String>,
+}
+
+/// Kevel keywords for the topics sent by a client.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct TopicKeywords {
+    /// Keywords of known topics, sorted and without duplicates.
+    pub keywords: Vec<String>,
+    /// Number of topics that aren't in the taxonomy.
+    pub unknown: usize,
+}
+
+impl TryFrom<TopicTaxonomyConfig> for TopicTaxonomy {
+    type Error = String;
+
+    fn try_from(config: TopicTaxonomyConfig) -> Result<Self, Self::Error> {
+        for (id, keyword) in &config.topics {
+            if id.is_empty() || !id.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(format!("invalid IAB topic ID '{}'", id));
+            }
+            if keyword.is_empty()
+                || !keyword
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+            {
+                return Err(format!("invalid keyword '{}' for topic '{}'", keyword, id));
+            }
+        }
+        Ok(Self {
+            topics: config.topics,
+        })
+    }
+}
+
+impl Default for TopicTaxonomy {
+    fn default() -> Self {
+        defaults::TOPIC_TAXONOMY.clone()
+    }
+}
+
+impl TopicTaxonomy {
+    /// Load and validate a topic taxonomy from a JSON file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, ProxyError> {
+        let taxonomy = serde_json::from_str(&fs::read_to_string(path)?)?;
+        Ok(taxonomy)
+    }
+
+    /// Map topics to keywords. The keywords are sorted, so the order in which
+    /// the client sent the topics is not passed on.
+    pub fn keywords<S: AsRef<str>>(&self, topics: &[S]) -> TopicKeywords {
+        let mut keywords = BTreeSet::new();
+        let mut unknown = 0;
+        for topic in topics {
+            match self.topics.get(topic.as_ref()) {
+                Some(keyword) => {
+                    keywords.insert(keyword.clone());
+                }
+                None => unknown += 1,
+            }
+        }
+        TopicKeywords {
+            keywords: keywords.into_iter().collect(),
+            unknown,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TopicKeywords, TopicTaxonomy};
+    use serde_json::{from_value, json};
+
+    #[test]
+    fn test_keywords() {
+        let taxonomy = TopicTaxonomy::default();
+        assert_eq!(
+            taxonomy.keywords(&["653", "unknown", "1", "653", "9999"]),
+            TopicKeywords {
+                keywords: vec!["topic_automotive".to_owned(), "topic_travel".to_owned()],
+                unknown: 2,
+            }
+        );
+        assert_eq!(taxonomy.keywords::<&str>(&[]), TopicKeywords::default());
+    }
+
+    #[test]
+    fn test_invalid_taxonomies() {
+        let invalid_taxonomies = [
+            json!({"topics": {"IAB1": "topic_automotive"}}),
+            json!({"topics": {"1": ""}}),
+            json!({"topics": {"1": "topic automotive"}}),
+            json!({"topics": {"1": "topic_automotive"}, "version": 2}),
+        ];
+        for taxonomy in invalid_taxonomies {
+            assert!(
+                from_value::<TopicTaxonomy>(taxonomy.clone()).is_err(),
+                "{} should be rejected",
+                taxonomy
+            );
+        }
+    }
+}
@@ -1,18 +1,85 @@
-use crate::{endpoints::EndpointState, utils::RequestClientIp};
-use actix_web::{web::Data, HttpRequest, HttpResponse};
+use crate::{
+    endpoints::{spocs::request_keywords, EndpointState},
+    targeting::{location::locate_client, policy::PolicyDecision},
+    utils::RequestClientIp,
+};
+use actix_web::{
+    web::{Data, Query},
+    HttpRequest, HttpResponse,
+};
+use serde::Deserialize;
+
+/// Optional parts of a spocs request that change its keywords.
+#[derive(Deserialize)]
+pub struct DebugQuery {
+    /// Pocket ID whose experiment branches are shown.
+    pocket_id: Option<String>,
+    /// Comma separated topic IDs.
+    topics: Option<String>,
+}
 
 /// Show debugging information about the server comprising:
 ///
 ///  * Request state,
 ///  * Current request's headers
 ///  * Calculated client IP for the current request
+///  * Location of the client IP according to the GeoIP database(s)
+///  * Targeting attributes derived from the location sources and request
+///    headers, and which location source was used
+///  * Geo policy decision for the client
+///  * Kevel keywords that would be sent for this client, given the `pocket_id`
+///    and `topics` in the query string
 ///
 /// This handler should be disabled in production servers.
-pub async fn debug_handler(req: HttpRequest, state: Data<EndpointState>) -> HttpResponse {
+pub async fn debug_handler(
+    req: HttpRequest,
+    query: Query<DebugQuery>,
+    state: Data<EndpointState>,
+) -> HttpResponse {
+    let client_ip = req.client_ip();
+    let location = client_ip
+        .as_ref()
+        .map_err(Clone::clone)
+        .and_then(|ip| state.geoip.locate(*ip));
+    let (targeting, location_source) = locate_client(&req, &state, None, None, state.privacy_mode);
+    let targeting = targeting.add_request_headers(&req);
+    let policy_decision = state.geo_policy.evaluate(&targeting);
+    let keywords = match policy_decision {
+        PolicyDecision::Allowed(market) => {
+            let experiments = state.experiments.get();
+            let assignment = experiments.assign(
+                query.pocket_id.as_deref().unwrap_or_default(),
+                targeting.country.as_deref(),
+            );
+            let topics: Vec<&str> = query
+                .topics
+                .as_deref()
+                .map_or_else(Vec::new, |topics| topics.split(',').collect());
+            let consent = state
+                .consent_policy
+                .evaluate(targeting.country.as_deref(), None);
+            request_keywords(
+                &state,
+                market,
+                &targeting,
+                state.topic_taxonomy.keywords(&topics),
+                &assignment,
+                query.pocket_id.is_some()
+                    && state.privacy_mode.allows_user_key()
+                    && consent.keeps_user_key(),
+            )
+        }
+        _ => vec![],
+    };
     HttpResponse::Ok().body(format!(
-        "received headers: {:?}\n\nrequest state: {:?}\n\nclient ip: {:?}",
+        "received headers: {:?}\n\nrequest state: {:?}\n\nclient ip: {:?}\n\nclient location: {:?}\n\nlocation source: {:?}\n\ntargeting: {:?}\n\ngeo policy: {}\n\nkeywords: {:?}",
         req.headers(),
         state,
-        req.client_ip()
+        client_ip,
+        location,
+        location_source,
+        targeting,
+        policy_decision.as_str(),
+        keywords
     ))
 }
@@ -1,13 +1,16 @@
+use super::EndpointState;
 use crate::{adzerk::client::AdzerkClient, errors::ProxyError};
 use actix_web::{
+    http::StatusCode,
     web::{self, Data},
     HttpResponse,
 };
+use chrono::Utc;
 use serde_derive::{Deserialize, Serialize};
 
 #[derive(Deserialize)]
 pub struct User {
-    pocket_id: String,
+    pub pocket_id: String,
 }
 
 #[derive(Serialize)]
@@ -15,11 +18,26 @@ pub struct DeleteUserResponse {
     status: u32,
 }
 
+/// Delete every user key the client may have in Kevel's UserDB. Every key is
+/// attempted; the first failure, if any, determines the response status.
 pub async fn delete_user(
     user: web::Json<User>,
+    state: Data<EndpointState>,
     adzerk_client: Data<AdzerkClient>,
 ) -> Result<HttpResponse, ProxyError> {
-    let status = adzerk_client.delete_user(&user.pocket_id).await?;
+    let mut status = StatusCode::OK;
+    let mut errors = Vec::new();
+    for user_key in state
+        .pseudonymizer
+        .known_user_keys(&user.pocket_id, Utc::now())?
+    {
+        match adzerk_client.delete_user(&user_key).await {
+            Ok(key_status) if status == StatusCode::OK => status = key_status,
+            Ok(_) => {}
+            Err(err) => errors.push(err),
+        }
+    }
+    ProxyError::aggregate("Deleting user keys", errors)?;
     let response_body = DeleteUserResponse {
         status: (status == 200) as _,
     };
@@ -28,15 +46,20 @@ pub async fn delete_user(
 
 #[cfg(test)]
 mod tests {
-    use crate::adzerk::{client::AdzerkClient, defaults};
+    use crate::{
+        adzerk::{client::AdzerkClient, defaults},
+        endpoints::EndpointState,
+        privacy::pseudonym::Pseudonymizer,
+    };
     use actix_web::{
         test::{self, TestRequest},
         web::{self, Data},
         App,
     };
     use serde_json::{json, Value};
+    use std::sync::Arc;
     use wiremock::{
-        matchers::{header, method, path},
+        matchers::{header, method, path, query_param},
         Mock, MockServer, ResponseTemplate,
     };
 
@@ -56,6 +79,7 @@ mod tests {
 
         let service = test::init_service(
             App::new()
+                .app_data(Data::new(EndpointState::default()))
                 .app_data(Data::new(adzerk_client))
                 .route("/user", web::delete().to(super::delete_user)),
         )
@@ -71,4 +95,48 @@ mod tests {
 
         Ok(())
     }
+
+    #[actix_rt::test]
+    async fn test_delete_user_pseudonymized() -> Result<(), Box<dyn std::error::Error>> {
+        let pocket_id = "{670e8b97-c271-483f-bcb0-4921b58cdb52}";
+        let pseudonymizer = Pseudonymizer::from_salts(&["2022-01-01:secret"], true)?;
+        let user_keys = pseudonymizer.known_user_keys(pocket_id, chrono::Utc::now())?;
+        let mock_adzerk_server = MockServer::start().await;
+        for user_key in &user_keys {
+            Mock::given(method("DELETE"))
+                .and(path(format!("/udb/{}/", defaults::NETWORK_ID)))
+                .and(query_param("userKey", user_key.as_str()))
+                .respond_with(ResponseTemplate::new(200))
+                .expect(1)
+                .mount(&mock_adzerk_server)
+                .await;
+        }
+
+        let state = EndpointState {
+            pseudonymizer: Arc::new(pseudonymizer),
+            ..EndpointState::default()
+        };
+        let adzerk_client = AdzerkClient::new("key".into()).with_base_url(mock_adzerk_server.uri());
+        let service = test::init_service(
+            App::new()
+                .app_data(Data::new(state))
+                .app_data(Data::new(adzerk_client))
+                .route("/user", web::delete().to(super::delete_user)),
+        )
+        .await;
+
+        let request = TestRequest::delete()
+            .uri("/user")
+            .set_json(json!({ "pocket_id": pocket_id }))
+            .to_request();
+        let response: Value = test::call_and_read_body_json(&service, request).await;
+        assert_eq!(response, json!({"status": 1}));
+        assert_eq!(
+            user_keys.len(),
+            2,
+            "hashed key and raw key in migration mode"
+        );
+
+        Ok(())
+    }
 }
@@ -14,6 +14,14 @@ pub async fn lbheartbeat() -> HttpResponse {
 #[derive(Serialize)]
 struct HeartbeatResponse {
     geoip: bool,
+    config: ConfigHashes,
+}
+
+/// SHA-256 of the active version of reloadable configuration files.
+#[derive(Serialize)]
+struct ConfigHashes {
+    settings: String,
+    domain_affinities: String,
 }
 
 pub async fn heartbeat(app_data: Data<EndpointState>) -> Result<HttpResponse, ProxyError> {
@@ -33,6 +41,10 @@ pub async fn heartbeat(app_data: Data<EndpointState>) -> Result<HttpResponse, Pr
     };
     Ok(response.json(HeartbeatResponse {
         geoip: geoip_available,
+        config: ConfigHashes {
+            settings: app_data.client_settings.get().hash().to_owned(),
+            domain_affinities: app_data.domain_affinities.get().hash().to_owned(),
+        },
     }))
 }
 
@@ -48,13 +60,19 @@ pub async fn version(app_data: Data<EndpointState>) -> HttpResponse {
 
 #[cfg(test)]
 mod tests {
-    use crate::endpoints::EndpointState;
+    use crate::{
+        adzerk::defaults,
+        endpoints::EndpointState,
+        geoip::{GeoIp, StaticGeoProvider, StaticLocation},
+    };
     use actix_web::{
         http,
         test::{self, TestRequest},
         web::{self, Data},
         App,
     };
+    use serde_json::{json, Value};
+    use std::sync::Arc;
 
     #[actix_rt::test]
     async fn lbheartbeat() {
@@ -79,6 +97,42 @@ mod tests {
         assert_eq!(response.status(), http::StatusCode::SERVICE_UNAVAILABLE);
     }
 
+    #[actix_rt::test]
+    async fn heartbeat_with_geoip() -> Result<(), Box<dyn std::error::Error>> {
+        let provider = StaticGeoProvider::default().with_location(
+            "1.2.3.4/32".parse()?,
+            StaticLocation {
+                country: Some("AU".to_owned()),
+                ..StaticLocation::default()
+            },
+        );
+        let state = EndpointState {
+            geoip: Arc::new(GeoIp::builder().provider(provider).build()?),
+            ..EndpointState::default()
+        };
+        let service = test::init_service(
+            App::new()
+                .app_data(Data::new(state))
+                .route("/", web::get().to(super::heartbeat)),
+        )
+        .await;
+        let request = TestRequest::default().to_request();
+        let response = test::call_service(&service, request).await;
+        assert_eq!(response.status(), http::StatusCode::OK);
+        let body: Value = test::read_body_json(response).await;
+        assert_eq!(
+            body,
+            json!({
+                "geoip": true,
+                "config": {
+                    "settings": defaults::SETTINGS.hash(),
+                    "domain_affinities": defaults::DOMAIN_AFFINITIES.hash()
+                }
+            })
+        );
+        Ok(())
+    }
+
     #[actix_rt::test]
     async fn version() -> Result<(), Box<dyn std::error::Error>> {
         let service = test::init_service(
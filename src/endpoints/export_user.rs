@@ -0,0 +1,158 @@
+use super::{delete_user::User, EndpointState};
+use crate::{adzerk::client::AdzerkClient, errors::ProxyError};
+use actix_web::{
+    web::{self, Data},
+    HttpResponse,
+};
+use chrono::Utc;
+use serde_derive::Serialize;
+use serde_json::Value;
+
+#[derive(Serialize)]
+pub struct ExportUserResponse {
+    records: Vec<Value>,
+}
+
+/// Export the records Kevel's UserDB holds under every user key the client may
+/// have. Every key is attempted, and the export fails if any of them does.
+pub async fn export_user(
+    user: web::Json<User>,
+    state: Data<EndpointState>,
+    adzerk_client: Data<AdzerkClient>,
+) -> Result<HttpResponse, ProxyError> {
+    let mut records = Vec::new();
+    let mut errors = Vec::new();
+    for user_key in state
+        .pseudonymizer
+        .known_user_keys(&user.pocket_id, Utc::now())?
+    {
+        match adzerk_client.read_user(&user_key).await {
+            Ok(record) => records.extend(record),
+            Err(err) => errors.push(err),
+        }
+    }
+    ProxyError::aggregate("Exporting user keys", errors)?;
+    Ok(HttpResponse::Ok().json(ExportUserResponse { records }))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        adzerk::{client::AdzerkClient, defaults},
+        endpoints::EndpointState,
+        privacy::pseudonym::Pseudonymizer,
+    };
+    use actix_web::{
+        test::{self, TestRequest},
+        web::{self, Data},
+        App,
+    };
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+    use wiremock::{
+        matchers::{method, path, query_param},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    #[actix_rt::test]
+    async fn test_export_user_endpoint() -> Result<(), Box<dyn std::error::Error>> {
+        let pocket_id = "{670e8b97-c271-483f-bcb0-4921b58cdb52}";
+        let pseudonymizer = Pseudonymizer::from_salts(&["2022-01-01:secret"], true)?;
+        let user_key = pseudonymizer.user_key(pocket_id, chrono::Utc::now())?;
+        let mock_adzerk_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path(format!("/udb/{}/read", defaults::NETWORK_ID)))
+            .and(query_param("userKey", user_key.as_str()))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(json!({"key": user_key, "interests": []})),
+            )
+            .mount(&mock_adzerk_server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(format!("/udb/{}/read", defaults::NETWORK_ID)))
+            .and(query_param("userKey", pocket_id))
+            .respond_with(ResponseTemplate::new(404))
+            .expect(1)
+            .mount(&mock_adzerk_server)
+            .await;
+
+        let state = EndpointState {
+            pseudonymizer: Arc::new(pseudonymizer),
+            ..EndpointState::default()
+        };
+        let adzerk_client = AdzerkClient::new("key".into()).with_base_url(mock_adzerk_server.uri());
+        let service = test::init_service(
+            App::new()
+                .app_data(Data::new(state))
+                .app_data(Data::new(adzerk_client))
+                .route("/user/export", web::post().to(super::export_user)),
+        )
+        .await;
+
+        let request = TestRequest::post()
+            .uri("/user/export")
+            .set_json(json!({ "pocket_id": pocket_id }))
+            .to_request();
+        let response: Value = test::call_and_read_body_json(&service, request).await;
+        assert_eq!(
+            response,
+            json!({"records": [{"key": user_key, "interests": []}]})
+        );
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_export_user_attempts_every_key() -> Result<(), Box<dyn std::error::Error>> {
+        let pocket_id = "{670e8b97-c271-483f-bcb0-4921b58cdb52}";
+        let pseudonymizer = Pseudonymizer::from_salts(&["2022-01-01:secret"], true)?;
+        let user_key = pseudonymizer.user_key(pocket_id, chrono::Utc::now())?;
+        let mock_adzerk_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path(format!("/udb/{}/read", defaults::NETWORK_ID)))
+            .and(query_param("userKey", user_key.as_str()))
+            .respond_with(ResponseTemplate::new(503))
+            .expect(1)
+            .mount(&mock_adzerk_server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(format!("/udb/{}/read", defaults::NETWORK_ID)))
+            .and(query_param("userKey", pocket_id))
+            .respond_with(ResponseTemplate::new(503))
+            .expect(1)
+            .mount(&mock_adzerk_server)
+            .await;
+
+        let state = EndpointState {
+            pseudonymizer: Arc::new(pseudonymizer),
+            ..EndpointState::default()
+        };
+        let adzerk_client = AdzerkClient::new("key".into()).with_base_url(mock_adzerk_server.uri());
+        let service = test::init_service(
+            App::new()
+                .app_data(Data::new(state))
+                .app_data(Data::new(adzerk_client))
+                .route("/user/export", web::post().to(super::export_user)),
+        )
+        .await;
+
+        let request = TestRequest::post()
+            .uri("/user/export")
+            .set_json(json!({ "pocket_id": pocket_id }))
+            .to_request();
+        let response = test::call_service(&service, request).await;
+        assert_eq!(response.status(), 500);
+        let body: Value = test::read_body_json(response).await;
+        assert_eq!(
+            body["message"]
+                .as_str()
+                .unwrap()
+                .matches("status 503")
+                .count(),
+            2,
+            "both failures are reported"
+        );
+
+        Ok(())
+    }
+}
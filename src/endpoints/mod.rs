@@ -1,30 +1,109 @@
 pub mod debug;
 pub mod delete_user;
 pub mod dockerflow;
+pub mod export_user;
+pub mod opt_out_user;
 pub mod spocs;
-use crate::{geoip::GeoIp, APP_NAME};
-use std::{default::Default, path::PathBuf, sync::Arc};
+use crate::{
+    adzerk::{
+        affinities::DomainAffinities,
+        client_settings::ClientSettings,
+        diversity::DiversityRules,
+        experiments::Experiments,
+        keywords::KeywordTemplates,
+        overlays::SettingsOverlays,
+        personalization::ModelPrefixes,
+        placements::Placements,
+        priorities::PriorityMap,
+        ranking::{Ranker, RankerName},
+        topics::TopicTaxonomy,
+    },
+    geoip::GeoIp,
+    privacy::{consent::ConsentPolicy, mode::PrivacyMode, pseudonym::Pseudonymizer},
+    reload::Reloadable,
+    targeting::{location::GeoHeaders, policy::GeoPolicy},
+    utils::ForwardingHeader,
+    APP_NAME,
+};
+use std::{
+    collections::HashSet,
+    default::Default,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
 
 #[derive(Debug, Clone)]
 pub struct EndpointState {
     pub geoip: Arc<GeoIp>,
+    pub keyword_templates: Arc<KeywordTemplates>,
+    pub topic_taxonomy: Arc<TopicTaxonomy>,
+    pub diversity_rules: Arc<DiversityRules>,
+    pub ranker: Arc<dyn Ranker>,
+    pub priority_map: Arc<Reloadable<PriorityMap>>,
+    /// Unmapped Kevel priority IDs that were already logged, so that each is
+    /// only logged once.
+    pub logged_priorities: Arc<Mutex<HashSet<u32>>>,
+    /// Parameter sets missing from the client settings that were already
+    /// logged.
+    pub logged_parameter_sets: Arc<Mutex<HashSet<String>>>,
+    pub placements: Arc<Reloadable<Placements>>,
+    pub model_prefixes: Arc<ModelPrefixes>,
+    pub client_settings: Arc<Reloadable<ClientSettings>>,
+    pub settings_overlays: Arc<Reloadable<SettingsOverlays>>,
+    pub domain_affinities: Arc<Reloadable<DomainAffinities>>,
+    pub experiments: Arc<Reloadable<Experiments>>,
+    pub geo_policy: Arc<GeoPolicy>,
+    pub consent_policy: ConsentPolicy,
+    pub pseudonymizer: Arc<Pseudonymizer>,
+    pub privacy_mode: PrivacyMode,
     pub trusted_proxies: Vec<ipnet::IpNet>,
+    pub trusted_proxy_hops: Option<usize>,
+    pub forwarding_headers: Vec<ForwardingHeader>,
+    pub geo_headers: GeoHeaders,
     pub log: slog::Logger,
     pub metrics: Arc<cadence::StatsdClient>,
     pub version_file: PathBuf,
+    pub collections_min_client_version: Option<u32>,
 }
 
 impl Default for EndpointState {
     fn default() -> Self {
         EndpointState {
             trusted_proxies: Vec::default(),
+            trusted_proxy_hops: None,
+            forwarding_headers: ForwardingHeader::default_precedence(),
+            geo_headers: GeoHeaders::default(),
             geoip: Arc::new(GeoIp::default()),
+            keyword_templates: Arc::new(KeywordTemplates::default()),
+            topic_taxonomy: Arc::new(TopicTaxonomy::default()),
+            diversity_rules: Arc::new(DiversityRules::default()),
+            ranker: RankerName::default_ranker().ranker(),
+            priority_map: Arc::new(Reloadable::fixed("priority map", PriorityMap::default())),
+            logged_priorities: Arc::default(),
+            logged_parameter_sets: Arc::default(),
+            placements: Arc::new(Reloadable::fixed("placements", Placements::default())),
+            model_prefixes: Arc::new(ModelPrefixes::default()),
+            client_settings: Arc::new(Reloadable::fixed("settings", ClientSettings::default())),
+            settings_overlays: Arc::new(Reloadable::fixed(
+                "settings overlays",
+                SettingsOverlays::default(),
+            )),
+            domain_affinities: Arc::new(Reloadable::fixed(
+                "domain affinities",
+                DomainAffinities::default(),
+            )),
+            experiments: Arc::new(Reloadable::fixed("experiments", Experiments::default())),
+            geo_policy: Arc::new(GeoPolicy::default()),
+            consent_policy: ConsentPolicy::default(),
+            pseudonymizer: Arc::new(Pseudonymizer::default()),
+            privacy_mode: PrivacyMode::default_mode(),
             log: slog::Logger::root(slog::Discard, slog::o!()),
             metrics: Arc::new(cadence::StatsdClient::from_sink(
                 APP_NAME,
                 cadence::NopMetricSink,
             )),
             version_file: "./version.json".into(),
+            collections_min_client_version: None,
         }
     }
 }
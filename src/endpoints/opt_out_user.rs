@@ -0,0 +1,101 @@
+use super::{delete_user::User, EndpointState};
+use crate::{adzerk::client::AdzerkClient, errors::ProxyError};
+use actix_web::{
+    http::StatusCode,
+    web::{self, Data},
+    HttpResponse,
+};
+use chrono::Utc;
+use serde_derive::Serialize;
+
+#[derive(Serialize)]
+pub struct OptOutUserResponse {
+    status: u32,
+}
+
+/// Opt every user key the client may have out of tracking in Kevel's UserDB.
+/// Every key is attempted; the first failure, if any, determines the response
+/// status.
+pub async fn opt_out_user(
+    user: web::Json<User>,
+    state: Data<EndpointState>,
+    adzerk_client: Data<AdzerkClient>,
+) -> Result<HttpResponse, ProxyError> {
+    let mut status = StatusCode::OK;
+    let mut errors = Vec::new();
+    for user_key in state
+        .pseudonymizer
+        .known_user_keys(&user.pocket_id, Utc::now())?
+    {
+        match adzerk_client.opt_out_user(&user_key).await {
+            Ok(key_status) if status == StatusCode::OK => status = key_status,
+            Ok(_) => {}
+            Err(err) => errors.push(err),
+        }
+    }
+    ProxyError::aggregate("Opting out user keys", errors)?;
+    let response_body = OptOutUserResponse {
+        status: (status == 200) as _,
+    };
+    Ok(HttpResponse::build(status).json(response_body))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        adzerk::{client::AdzerkClient, defaults},
+        endpoints::EndpointState,
+        privacy::pseudonym::Pseudonymizer,
+    };
+    use actix_web::{
+        test::{self, TestRequest},
+        web::{self, Data},
+        App,
+    };
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+    use wiremock::{
+        matchers::{header, method, path, query_param},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    #[actix_rt::test]
+    async fn test_opt_out_user_endpoint() -> Result<(), Box<dyn std::error::Error>> {
+        let adzerk_api_key = "my-cool-api-key";
+        let pocket_id = "{670e8b97-c271-483f-bcb0-4921b58cdb52}";
+        let pseudonymizer = Pseudonymizer::from_salts(&["2022-01-01:secret"], false)?;
+        let user_key = pseudonymizer.user_key(pocket_id, chrono::Utc::now())?;
+        let mock_adzerk_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path(format!("/udb/{}/optout/i.gif", defaults::NETWORK_ID)))
+            .and(header("X-Adzerk-ApiKey", adzerk_api_key))
+            .and(query_param("userKey", user_key.as_str()))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_adzerk_server)
+            .await;
+
+        let state = EndpointState {
+            pseudonymizer: Arc::new(pseudonymizer),
+            ..EndpointState::default()
+        };
+        let adzerk_client =
+            AdzerkClient::new(adzerk_api_key.into()).with_base_url(mock_adzerk_server.uri());
+        let service = test::init_service(
+            App::new()
+                .app_data(Data::new(state))
+                .app_data(Data::new(adzerk_client))
+                .route("/user/opt-out", web::post().to(super::opt_out_user)),
+        )
+        .await;
+
+        let request = TestRequest::post()
+            .uri("/user/opt-out")
+            .set_json(json!({ "pocket_id": pocket_id }))
+            .to_request();
+        let response: Value = test::call_and_read_body_json(&service, request).await;
+        assert_eq!(response, json!({"status": 1}));
+
+        Ok(())
+    }
+}
@@ -1,14 +1,66 @@
-use std::collections::HashMap;
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    sync::Arc,
+};
 
-use crate::{adzerk::client::AdzerkClient, errors::ProxyError, utils::RequestClientIp};
+use crate::{
+    adzerk::{
+        affinities::{AffinitySet, DomainAffinities},
+        caps::Caps,
+        client::AdzerkClient,
+        client_settings::ClientSettings,
+        collections::CollectionStats,
+        defaults,
+        diversity::{DiversityRules, DiversityStats},
+        experiments::Assignment,
+        personalization::ModelPrefixes,
+        placements::Placements,
+        priorities::PriorityMap,
+        ranking::{self, Ranker, RankerName},
+        request_models::{DecisionRequest, DivRequest},
+        topics::TopicKeywords,
+    },
+    errors::ProxyError,
+    privacy::{
+        blocked_domains::{DomainBloomFilter, MAX_FALSE_POSITIVE_RATE},
+        consent::ClientConsent,
+        mode::PrivacyMode,
+    },
+    targeting::{
+        location::locate_client,
+        policy::{MarketPolicy, PolicyDecision},
+        TargetingContext,
+    },
+};
 use actix_web::{
     web::{self, Data},
     HttpRequest, HttpResponse,
 };
-use serde::{Deserialize, Serialize};
+use cadence::prelude::*;
+use chrono::Utc;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 
 use super::EndpointState;
 
+/// Maximum number of flight IDs, and of ad IDs, a client may ask to exclude.
+pub const MAX_BLOCKED_IDS: usize = 100;
+/// Most spare spocs requested for each div to replace spocs of blocked flights
+/// and ads.
+pub const MAX_BLOCKED_SPARES: u32 = 10;
+/// Maximum number of topics a client may send.
+pub const MAX_TOPICS: usize = 5;
+/// Maximum length of a topic ID.
+pub const MAX_TOPIC_LENGTH: usize = 16;
+/// Maximum number of flights a client may send view times for.
+pub const MAX_VIEWED_FLIGHTS: usize = 100;
+/// Maximum number of view times per flight.
+pub const MAX_VIEWS_PER_FLIGHT: usize = 20;
+/// Oldest view time accepted, in seconds before now.
+pub const MAX_VIEW_AGE: u64 = 365 * 24 * 60 * 60;
+/// How far ahead of ours a client's clock may be, in seconds.
+pub const MAX_CLOCK_SKEW: u64 = 60 * 60;
+
 #[derive(Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct SpocsRequest {
@@ -20,6 +72,85 @@ pub struct SpocsRequest {
     pub placements: Vec<Placement>,
     pub country: Option<String>,
     pub region: Option<String>,
+    pub consent: Option<ClientConsent>,
+    pub privacy_mode: Option<PrivacyMode>,
+    /// Flights the client doesn't want to see, e.g. because the user dismissed
+    /// one of their spocs.
+    #[serde(default, deserialize_with = "bounded_ids")]
+    pub blocked_flights: HashSet<u32>,
+    /// Ads the client doesn't want to see.
+    #[serde(default, deserialize_with = "bounded_ids")]
+    pub blocked_ads: HashSet<u32>,
+    /// Recent views of spocs by the client, as Unix timestamps in seconds by
+    /// flight ID, so Kevel can enforce frequency caps without a user profile.
+    #[serde(default, deserialize_with = "bounded_view_times")]
+    pub flight_view_times: BTreeMap<u32, Vec<u64>>,
+    /// IAB Content Taxonomy IDs of interests inferred by the client.
+    #[serde(default, deserialize_with = "bounded_topics")]
+    pub topics: Vec<String>,
+    /// Bloom filter of domains the user blocked.
+    pub blocked_domains: Option<DomainBloomFilter>,
+}
+
+fn bounded_topics<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    let topics = Vec::<String>::deserialize(deserializer)?;
+    if topics.len() > MAX_TOPICS {
+        return Err(de::Error::invalid_length(
+            topics.len(),
+            &format!("at most {} topics", MAX_TOPICS).as_str(),
+        ));
+    }
+    if let Some(topic) = topics.iter().find(|t| t.len() > MAX_TOPIC_LENGTH) {
+        return Err(de::Error::invalid_length(
+            topic.len(),
+            &format!("topic IDs of at most {} characters", MAX_TOPIC_LENGTH).as_str(),
+        ));
+    }
+    Ok(topics)
+}
+
+/// View times must be plausible, i.e. neither in the future nor too old.
+/// Longer histories are truncated to the most recent views of the most
+/// recently viewed flights.
+fn bounded_view_times<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<BTreeMap<u32, Vec<u64>>, D::Error> {
+    let view_times = BTreeMap::<u32, Vec<u64>>::deserialize(deserializer)?;
+    let now = Utc::now().timestamp() as u64;
+    let plausible = now.saturating_sub(MAX_VIEW_AGE)..=now + MAX_CLOCK_SKEW;
+    if let Some(&time) = view_times
+        .values()
+        .flatten()
+        .find(|time| !plausible.contains(time))
+    {
+        return Err(de::Error::invalid_value(
+            de::Unexpected::Unsigned(time),
+            &"a Unix timestamp in seconds within the last year",
+        ));
+    }
+
+    let mut flights: Vec<(u32, Vec<u64>)> = view_times
+        .into_iter()
+        .map(|(flight, mut times)| {
+            times.sort_unstable();
+            times.drain(..times.len().saturating_sub(MAX_VIEWS_PER_FLIGHT));
+            (flight, times)
+        })
+        .collect();
+    flights.sort_by_key(|(_, times)| Reverse(times.last().copied()));
+    flights.truncate(MAX_VIEWED_FLIGHTS);
+    Ok(flights.into_iter().collect())
+}
+
+fn bounded_ids<'de, D: Deserializer<'de>>(deserializer: D) -> Result<HashSet<u32>, D::Error> {
+    let ids = Vec::<u32>::deserialize(deserializer)?;
+    if ids.len() > MAX_BLOCKED_IDS {
+        return Err(de::Error::invalid_length(
+            ids.len(),
+            &format!("at most {} IDs", MAX_BLOCKED_IDS).as_str(),
+        ));
+    }
+    Ok(ids.into_iter().collect())
 }
 
 #[derive(Deserialize)]
@@ -33,11 +164,99 @@ pub struct Placement {
     pub count: Option<u32>,
 }
 
+/// How to build the response from Kevel's decisions.
+#[derive(Debug)]
+pub struct ResponseOptions {
+    /// Whether the client can display sponsored collections.
+    pub supports_collections: bool,
+    /// Flights to leave out of the response.
+    pub blocked_flights: HashSet<u32>,
+    /// Ads to leave out of the response.
+    pub blocked_ads: HashSet<u32>,
+    /// Spocs from domains in this filter are left out of the response.
+    pub blocked_domains: Option<DomainBloomFilter>,
+    /// Rules applied to the spocs of all divs together.
+    pub diversity_rules: Arc<DiversityRules>,
+    /// What the client requested for each div. Divs are cut to the requested
+    /// count once spocs are removed, so spare spocs take the place of removed
+    /// ones. Divs without a request aren't cut.
+    pub divs: HashMap<String, DivRequest>,
+    /// Orders the spocs of each div.
+    pub ranker: Arc<dyn Ranker>,
+    /// Seed for the ranker's tie-breaks, derived from the client's `pocket_id`.
+    pub ranking_seed: u64,
+    /// Maps Kevel priority IDs to the priority tiers of spocs.
+    pub priority_map: Arc<PriorityMap>,
+    /// Settings for the spocs of each div.
+    pub placements: Arc<Placements>,
+    /// Parameter set of spocs whose creative doesn't choose one, from the
+    /// client's experiments.
+    pub parameter_set: Option<String>,
+    /// Which keys of creative bodies are personalization models.
+    pub model_prefixes: Arc<ModelPrefixes>,
+    /// The settings sent to the client, with the overlays that match it
+    /// applied.
+    pub settings: Arc<ClientSettings>,
+    /// Domain affinity sets that creatives refer to.
+    pub domain_affinities: Arc<DomainAffinities>,
+}
+
+impl Default for ResponseOptions {
+    fn default() -> Self {
+        Self {
+            supports_collections: false,
+            blocked_flights: HashSet::new(),
+            blocked_ads: HashSet::new(),
+            blocked_domains: None,
+            diversity_rules: Arc::new(DiversityRules::default()),
+            divs: HashMap::new(),
+            ranker: RankerName::default_ranker().ranker(),
+            ranking_seed: 0,
+            priority_map: Arc::new(PriorityMap::default()),
+            placements: Arc::new(Placements::default()),
+            parameter_set: None,
+            model_prefixes: Arc::new(ModelPrefixes::default()),
+            settings: Arc::new(ClientSettings::default()),
+            domain_affinities: Arc::new(DomainAffinities::default()),
+        }
+    }
+}
+
+/// What happened while building the response, for metrics.
+#[derive(Debug, Default)]
+pub struct ResponseStats {
+    /// Number of spocs left out because their domain matched the blocked
+    /// domains filter.
+    pub blocked_domains: usize,
+    /// Spocs removed by the diversity rules.
+    pub diversity: DiversityStats,
+    /// How sponsored collections were assembled.
+    pub collections: CollectionStats,
+    /// Number of decisions by priority ID, for priority IDs that are not in
+    /// the priority mapping.
+    pub unmapped_priorities: BTreeMap<u32, usize>,
+    /// Number of cap overrides in creatives that were not valid, and were
+    /// ignored.
+    pub invalid_caps: usize,
+    /// Number of spocs whose creative chose a parameter set that doesn't
+    /// exist, and got the div's default instead.
+    pub unknown_parameter_sets: usize,
+    /// Ad IDs of spocs whose personalization models could not all be read,
+    /// with the error.
+    pub malformed_models: Vec<(u32, String)>,
+}
+
 #[derive(Serialize)]
 pub struct SpocsResponse {
-    pub settings: &'static serde_json::Value,
+    #[serde(serialize_with = "serialize_arc")]
+    pub settings: Arc<serde_json::Value>,
     #[serde(flatten)]
     pub divs: HashMap<String, SpocsList>,
+    /// The branch of each experiment the client is in.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub experiments: BTreeMap<String, String>,
+    #[serde(skip)]
+    pub stats: ResponseStats,
 }
 
 #[derive(Serialize)]
@@ -55,6 +274,9 @@ pub struct Collection {
     pub sponsor: Option<String>,
     pub context: String,
     pub items: Vec<Spoc>,
+    /// The div's other spocs, as a standard list.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub spocs: Vec<Spoc>,
 }
 
 #[derive(Serialize)]
@@ -71,9 +293,10 @@ pub struct Spoc {
     pub raw_image_src: String,
     pub image_src: String,
     pub shim: Shim,
-    pub parameter_set: &'static str,
-    pub caps: &'static serde_json::Value,
-    pub domain_affinities: &'static HashMap<String, u32>,
+    pub parameter_set: String,
+    pub caps: Caps,
+    #[serde(serialize_with = "serialize_arc")]
+    pub domain_affinities: Arc<AffinitySet>,
     pub personalization_models: HashMap<String, u32>,
     pub min_score: f64,
     pub item_score: f64,
@@ -89,6 +312,14 @@ pub struct Spoc {
     pub is_video: Option<bool>,
 }
 
+/// Serialize shared values, which serde only supports with its `rc` feature.
+fn serialize_arc<T: Serialize, S: Serializer>(
+    value: &Arc<T>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    value.as_ref().serialize(serializer)
+}
+
 #[derive(Serialize)]
 pub struct Shim {
     pub click: String,
@@ -97,8 +328,53 @@ pub struct Shim {
     pub save: String,
 }
 
+/// Assemble the keywords sent to Kevel for a client in `market`: the keyword
+/// templates rendered for its location, the keywords of its topics and, if its
+/// user key is sent too, the keywords of its experiment branches.
+pub fn request_keywords(
+    state: &EndpointState,
+    market: &MarketPolicy,
+    targeting: &TargetingContext,
+    topic_keywords: TopicKeywords,
+    assignment: &Assignment<'_>,
+    sends_user_key: bool,
+) -> Vec<String> {
+    let mut keywords = state
+        .keyword_templates
+        .render(&market.keyword_targeting(targeting), Utc::now());
+    keywords.extend(topic_keywords.keywords);
+    // Branches are derived from the pocket_id, so their keywords are only sent
+    // to Kevel along with the user key.
+    if sends_user_key {
+        keywords.extend(assignment.keywords().cloned());
+    }
+    keywords
+}
+
+/// Parameter sets of the client's experiment and divs that are missing from
+/// the client settings. Placements and experiments are validated against the
+/// settings they are loaded with, but the settings may be reloaded without
+/// them; their spocs then fall back to the next default.
+fn missing_parameter_sets(options: &ResponseOptions) -> BTreeSet<&str> {
+    let experiment = options
+        .parameter_set
+        .as_deref()
+        .filter(|name| !options.settings.has_parameter_set(name));
+    options
+        .divs
+        .keys()
+        .filter_map(|div| {
+            options
+                .placements
+                .get(div)
+                .missing_parameter_set(&options.settings)
+        })
+        .chain(experiment)
+        .collect()
+}
+
 pub async fn spocs(
-    mut spoc: web::Json<SpocsRequest>,
+    spoc: web::Json<SpocsRequest>,
     state: Data<EndpointState>,
     adzerk_client: Data<AdzerkClient>,
     req: HttpRequest,
@@ -106,14 +382,775 @@ pub async fn spocs(
     // validate pocket id is a uuid
     let _: uuid::Uuid = spoc.pocket_id.parse()?;
 
-    if spoc.country.is_none() {
-        if let Ok(location) = state.geoip.locate(req.client_ip()?) {
-            spoc.country = location.country.map(|s| s.to_owned());
-            spoc.region = location.region.map(|s| s.to_owned());
-        }
+    let privacy_mode = state.privacy_mode.for_request(spoc.privacy_mode);
+    state
+        .metrics
+        .incr_with_tags("privacy_mode")
+        .with_tag("mode", privacy_mode.as_str())
+        .send();
+
+    let (targeting, location_source) = locate_client(
+        &req,
+        &state,
+        spoc.country.as_deref(),
+        spoc.region.as_deref(),
+        privacy_mode,
+    );
+    let targeting = targeting.add_request_headers(&req);
+    state
+        .metrics
+        .incr_with_tags("location_source")
+        .with_tag("source", location_source.as_str())
+        .send();
+
+    // Clients are only identified by their branches in metrics and logs.
+    let experiments = state.experiments.get();
+    let assignment = experiments.assign(&spoc.pocket_id, targeting.country.as_deref());
+    let branches = assignment.names();
+    for (experiment, branch) in &branches {
+        state
+            .metrics
+            .incr_with_tags("experiment")
+            .with_tag("experiment", experiment)
+            .with_tag("branch", branch)
+            .send();
     }
 
-    let spocs_response = adzerk_client.get_decisions(spoc.into_inner()).await?;
+    let placement_names: Vec<&str> = if spoc.placements.is_empty() {
+        vec![defaults::PLACEMENT.div_name.as_str()]
+    } else {
+        spoc.placements.iter().map(|p| p.name.as_str()).collect()
+    };
+    let base_settings = state.client_settings.get();
+    let settings = state
+        .settings_overlays
+        .get()
+        .apply(&base_settings, &targeting, &placement_names, &branches)
+        .unwrap_or_else(|err| {
+            slog::error!(state.log, "{}", err);
+            base_settings
+        });
+
+    let policy_decision = state.geo_policy.evaluate(&targeting);
+    state
+        .metrics
+        .incr_with_tags("geo_policy")
+        .with_tag("result", policy_decision.as_str())
+        .send();
+    let market = match policy_decision {
+        PolicyDecision::Allowed(market) => market,
+        _ => {
+            let decision_request = DecisionRequest::new(spoc.into_inner(), vec![]);
+            let mut response = SpocsResponse::empty(settings.value(), decision_request.div_names());
+            response.experiments = branches;
+            return Ok(HttpResponse::Ok().json(response));
+        }
+    };
+
+    let topic_keywords = state.topic_taxonomy.keywords(&spoc.topics);
+    state
+        .metrics
+        .count_with_tags("topics", topic_keywords.keywords.len() as i64)
+        .with_tag("result", "known")
+        .send();
+    state
+        .metrics
+        .count_with_tags("topics", topic_keywords.unknown as i64)
+        .with_tag("result", "unknown")
+        .send();
+
+    let consent = state
+        .consent_policy
+        .evaluate(targeting.country.as_deref(), spoc.consent);
+    let keywords = request_keywords(
+        &state,
+        market,
+        &targeting,
+        topic_keywords,
+        &assignment,
+        privacy_mode.allows_user_key() && consent.keeps_user_key(),
+    );
+    let mut response_options = ResponseOptions {
+        supports_collections: spoc.version >= 2
+            && !targeting.is_older_than(state.collections_min_client_version),
+        blocked_flights: spoc.blocked_flights.clone(),
+        blocked_ads: spoc.blocked_ads.clone(),
+        blocked_domains: spoc.blocked_domains.clone().filter(|filter| {
+            let false_positive_rate = filter.estimated_false_positive_rate();
+            state
+                .metrics
+                .histogram_with_tags(
+                    "blocked_domains.false_positive_permille",
+                    (false_positive_rate * 1000.0) as u64,
+                )
+                .send();
+            let usable = false_positive_rate <= MAX_FALSE_POSITIVE_RATE;
+            state
+                .metrics
+                .incr_with_tags("blocked_domains.filter")
+                .with_tag("result", if usable { "used" } else { "saturated" })
+                .send();
+            usable
+        }),
+        diversity_rules: Arc::clone(&state.diversity_rules),
+        divs: HashMap::new(),
+        ranker: match assignment.ranker() {
+            Some(ranker) => ranker.ranker(),
+            None => Arc::clone(&state.ranker),
+        },
+        ranking_seed: ranking::seed(&spoc.pocket_id),
+        priority_map: state.priority_map.get(),
+        placements: {
+            let placements = state.placements.get();
+            match assignment.placements(&placements) {
+                Some(placements) => Arc::new(placements),
+                None => placements,
+            }
+        },
+        parameter_set: assignment.parameter_set().map(str::to_owned),
+        model_prefixes: Arc::clone(&state.model_prefixes),
+        settings,
+        domain_affinities: state.domain_affinities.get(),
+    };
+
+    let mut decision_request = DecisionRequest::new(spoc.into_inner(), keywords);
+    decision_request.limit(market.max_placements, market.max_spocs);
+    // Kevel's request has no field to exclude flights or ads by ID; its
+    // `blockedCreatives` takes creative IDs, which clients don't know. Blocked
+    // spocs are removed from the response instead, so request spares to take
+    // their place.
+    let blocked = (response_options.blocked_flights.len() + response_options.blocked_ads.len())
+        .min(MAX_BLOCKED_SPARES as usize) as u32;
+    response_options.divs = decision_request.add_spares(state.diversity_rules.backfill() + blocked);
+    for name in missing_parameter_sets(&response_options) {
+        if state
+            .logged_parameter_sets
+            .lock()
+            .unwrap()
+            .insert(name.to_owned())
+        {
+            slog::error!(
+                state.log,
+                "parameter set {} is missing from the client settings",
+                name
+            );
+        }
+        state
+            .metrics
+            .incr_with_tags("parameter_set.missing")
+            .with_tag("parameter_set", name)
+            .send();
+    }
+    decision_request.apply_privacy_mode(privacy_mode);
+    decision_request.pseudonymize(&state.pseudonymizer, Utc::now())?;
+    decision_request.apply_consent(consent, &state.pseudonymizer, Utc::now())?;
+    let mut spocs_response = adzerk_client
+        .get_decisions(decision_request, &response_options)
+        .await?;
+    if !branches.is_empty() {
+        let served: usize = spocs_response
+            .divs
+            .values()
+            .map(SpocsList::spoc_count)
+            .sum();
+        for (experiment, branch) in &branches {
+            state
+                .metrics
+                .count_with_tags("experiment.spocs", served as i64)
+                .with_tag("experiment", experiment)
+                .with_tag("branch", branch)
+                .send();
+        }
+        spocs_response.experiments = branches;
+    }
+    if response_options.blocked_domains.is_some() {
+        state
+            .metrics
+            .count_with_tags(
+                "blocked_domains.dropped",
+                spocs_response.stats.blocked_domains as i64,
+            )
+            .send();
+    }
+    for (&priority_id, &count) in &spocs_response.stats.unmapped_priorities {
+        if state.logged_priorities.lock().unwrap().insert(priority_id) {
+            slog::warn!(state.log, "unmapped Kevel priority ID {}", priority_id);
+        }
+        state
+            .metrics
+            .count_with_tags("priority.unmapped", count as i64)
+            .with_tag("priority_id", &priority_id.to_string())
+            .send();
+    }
+    if spocs_response.stats.invalid_caps > 0 {
+        state
+            .metrics
+            .count_with_tags("caps.invalid", spocs_response.stats.invalid_caps as i64)
+            .send();
+    }
+    if spocs_response.stats.unknown_parameter_sets > 0 {
+        state
+            .metrics
+            .count_with_tags(
+                "parameter_set.unknown",
+                spocs_response.stats.unknown_parameter_sets as i64,
+            )
+            .send();
+    }
+    for (ad_id, err) in &spocs_response.stats.malformed_models {
+        slog::warn!(
+            state.log,
+            "malformed personalization models in ad {}: {}",
+            ad_id,
+            err
+        );
+    }
+    if !spocs_response.stats.malformed_models.is_empty() {
+        state
+            .metrics
+            .count_with_tags(
+                "personalization_models.malformed",
+                spocs_response.stats.malformed_models.len() as i64,
+            )
+            .send();
+    }
+    let collections = &spocs_response.stats.collections;
+    for (result, count) in [
+        ("assembled", collections.assembled),
+        ("fallback", collections.fallbacks),
+    ] {
+        if count > 0 {
+            state
+                .metrics
+                .count_with_tags("collections", count as i64)
+                .with_tag("result", result)
+                .send();
+        }
+    }
+    if collections.inconsistent > 0 {
+        state
+            .metrics
+            .count_with_tags("collections.inconsistent", collections.inconsistent as i64)
+            .send();
+    }
+    if collections.remainder > 0 {
+        state
+            .metrics
+            .count_with_tags("collections.remainder", collections.remainder as i64)
+            .send();
+    }
+    for (reason, removed) in spocs_response.stats.diversity.removed() {
+        if removed > 0 {
+            state
+                .metrics
+                .count_with_tags("diversity.removed", removed as i64)
+                .with_tag("reason", reason)
+                .send();
+        }
+    }
 
     Ok(HttpResponse::Ok().json(spocs_response))
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        adzerk::{
+            client::AdzerkClient, client_settings::ClientSettings, defaults,
+            experiments::Experiments, placements::Placements,
+        },
+        endpoints::EndpointState,
+        geoip::{GeoIp, StaticGeoProvider, StaticLocation},
+        metrics::tests::TestMetricSink,
+        reload::Reloadable,
+        targeting::policy::GeoPolicy,
+    };
+    use actix_web::{
+        test::{self, TestRequest},
+        web::{self, Data},
+        App,
+    };
+    use cadence::StatsdClient;
+    use chrono::Utc;
+    use serde_json::{from_value, json, Value};
+    use std::{
+        collections::HashSet,
+        sync::{Arc, Mutex},
+    };
+    use wiremock::{
+        matchers::{method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    fn geoip() -> Arc<GeoIp> {
+        let provider = StaticGeoProvider::default().with_location(
+            "1.2.3.0/24".parse().unwrap(),
+            StaticLocation {
+                country: Some("US".to_owned()),
+                region: Some("CA".to_owned()),
+                ..StaticLocation::default()
+            },
+        );
+        Arc::new(GeoIp::builder().provider(provider).build().unwrap())
+    }
+
+    #[actix_rt::test]
+    async fn test_blocked_market_gets_empty_response() -> Result<(), Box<dyn std::error::Error>> {
+        let state = EndpointState {
+            geo_policy: Arc::new(from_value::<GeoPolicy>(json!({
+                "allowed_countries": ["US"]
+            }))?),
+            ..EndpointState::default()
+        };
+        // Kevel must not be called, so point the client to an unroutable URL.
+        let adzerk_client =
+            AdzerkClient::new("key".into()).with_base_url("http://0.0.0.0:1".into());
+        let service = test::init_service(
+            App::new()
+                .app_data(Data::new(state))
+                .app_data(Data::new(adzerk_client))
+                .route("/spocs", web::post().to(super::spocs)),
+        )
+        .await;
+
+        let request = TestRequest::post()
+            .uri("/spocs")
+            .set_json(json!({
+                "version": 2,
+                "consumer_key": "40249-e88c401e1b1f2242d9e441c4",
+                "pocket_id": "{670e8b97-c271-483f-bcb0-4921b58cdb52}",
+                "placements": [{"name": "spocs"}, {"name": "sponsored-topics"}],
+                "country": "DE"
+            }))
+            .to_request();
+        let response: Value = test::call_and_read_body_json(&service, request).await;
+        assert_eq!(
+            response,
+            json!({
+                "settings": *defaults::SETTINGS.value(),
+                "spocs": [],
+                "sponsored-topics": []
+            })
+        );
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_experiment_branches_are_sent() -> Result<(), Box<dyn std::error::Error>> {
+        let experiments = Experiments::from_json(
+            &json!({"experiments": [{
+                "name": "ranking",
+                "salt": "2026-10",
+                "allocation": {"*": 100},
+                "branches": [{"name": "kevel", "ranker": "kevel"}]
+            }]})
+            .to_string(),
+            &ClientSettings::default(),
+        )?;
+        let state = EndpointState {
+            geo_policy: Arc::new(from_value::<GeoPolicy>(json!({
+                "allowed_countries": ["US"]
+            }))?),
+            experiments: Arc::new(Reloadable::fixed("experiments", experiments)),
+            ..EndpointState::default()
+        };
+        let adzerk_client =
+            AdzerkClient::new("key".into()).with_base_url("http://0.0.0.0:1".into());
+        let service = test::init_service(
+            App::new()
+                .app_data(Data::new(state))
+                .app_data(Data::new(adzerk_client))
+                .route("/spocs", web::post().to(super::spocs)),
+        )
+        .await;
+
+        let request = TestRequest::post()
+            .uri("/spocs")
+            .set_json(json!({
+                "version": 2,
+                "consumer_key": "40249-e88c401e1b1f2242d9e441c4",
+                "pocket_id": "{670e8b97-c271-483f-bcb0-4921b58cdb52}",
+                "country": "DE"
+            }))
+            .to_request();
+        let response: Value = test::call_and_read_body_json(&service, request).await;
+        assert_eq!(response["experiments"], json!({"ranking": "kevel"}));
+        assert_eq!(response["spocs"], json!([]));
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_experiment_keywords_need_a_user_key() -> Result<(), Box<dyn std::error::Error>> {
+        let experiments = Experiments::from_json(
+            &json!({"experiments": [{
+                "name": "ranking",
+                "salt": "2026-10",
+                "allocation": {"*": 100},
+                "branches": [{"name": "priority", "keywords": ["exp_ranking"]}]
+            }]})
+            .to_string(),
+            &ClientSettings::default(),
+        )?;
+        let mock_adzerk_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/v2"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"decisions": {}})))
+            .mount(&mock_adzerk_server)
+            .await;
+        let state = EndpointState {
+            experiments: Arc::new(Reloadable::fixed("experiments", experiments)),
+            ..EndpointState::default()
+        };
+        let adzerk_client = AdzerkClient::new("key".into()).with_base_url(mock_adzerk_server.uri());
+        let service = test::init_service(
+            App::new()
+                .app_data(Data::new(state))
+                .app_data(Data::new(adzerk_client))
+                .route("/spocs", web::post().to(super::spocs)),
+        )
+        .await;
+
+        let test_cases = [
+            (json!({"country": "US"}), true),
+            (
+                json!({"country": "US", "privacy_mode": "contextual-only"}),
+                false,
+            ),
+            (json!({"country": "DE"}), false),
+            (json!({"country": "DE", "consent": {"gdpr": true}}), true),
+        ];
+        for (i, (fields, sent)) in test_cases.iter().enumerate() {
+            let mut body = json!({
+                "version": 2,
+                "consumer_key": "40249-e88c401e1b1f2242d9e441c4",
+                "pocket_id": "{670e8b97-c271-483f-bcb0-4921b58cdb52}",
+            });
+            body.as_object_mut()
+                .unwrap()
+                .extend(fields.as_object().unwrap().clone());
+            let request = TestRequest::post()
+                .uri("/spocs")
+                .set_json(&body)
+                .to_request();
+            let response: Value = test::call_and_read_body_json(&service, request).await;
+            assert_eq!(response["experiments"], json!({"ranking": "priority"}));
+
+            let received = mock_adzerk_server.received_requests().await.unwrap();
+            let decision_request: Value = serde_json::from_slice(&received[i].body)?;
+            let keywords = decision_request["keywords"].as_array().unwrap();
+            assert_eq!(
+                keywords.contains(&json!("exp_ranking")),
+                *sent,
+                "keywords for {}",
+                fields
+            );
+        }
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_spares_replace_blocked_spocs() -> Result<(), Box<dyn std::error::Error>> {
+        let mock_adzerk_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/v2"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"decisions": {}})))
+            .mount(&mock_adzerk_server)
+            .await;
+        let adzerk_client = AdzerkClient::new("key".into()).with_base_url(mock_adzerk_server.uri());
+        let service = test::init_service(
+            App::new()
+                .app_data(Data::new(EndpointState::default()))
+                .app_data(Data::new(adzerk_client))
+                .route("/spocs", web::post().to(super::spocs)),
+        )
+        .await;
+
+        let many: Vec<u32> = (0..50).collect();
+        for (i, (blocked_flights, blocked_ads, spares)) in [
+            (vec![], vec![], 0),
+            (vec![1, 2], vec![3], 3),
+            (many.clone(), many, super::MAX_BLOCKED_SPARES),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let request = TestRequest::post()
+                .uri("/spocs")
+                .set_json(json!({
+                    "version": 2,
+                    "consumer_key": "40249-e88c401e1b1f2242d9e441c4",
+                    "pocket_id": "{670e8b97-c271-483f-bcb0-4921b58cdb52}",
+                    "placements": [{"name": "spocs"}],
+                    "blocked_flights": blocked_flights,
+                    "blocked_ads": blocked_ads
+                }))
+                .to_request();
+            let response = test::call_service(&service, request).await;
+            assert!(response.status().is_success());
+
+            let received = mock_adzerk_server.received_requests().await.unwrap();
+            let decision_request: Value = serde_json::from_slice(&received[i].body)?;
+            assert_eq!(
+                decision_request["placements"][0]["count"],
+                defaults::PLACEMENT.count + spares
+            );
+        }
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_missing_parameter_sets_are_counted() -> Result<(), Box<dyn std::error::Error>> {
+        let mock_adzerk_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/v2"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"decisions": {}})))
+            .mount(&mock_adzerk_server)
+            .await;
+        let placements = Placements::from_json(
+            &json!({"divs": {"spocs": {"parameter_set": "fully-personalized"}}}).to_string(),
+            &ClientSettings::default(),
+        )?;
+        // The settings were reloaded without the div's parameter set.
+        let client_settings = ClientSettings::from_json(
+            &json!({
+                "feature_flags": {},
+                "spocsPerNewTabs": 1,
+                "domainAffinityParameterSets": {"default": {}},
+                "timeSegments": []
+            })
+            .to_string(),
+        )?;
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let state = EndpointState {
+            placements: Arc::new(Reloadable::fixed("placements", placements)),
+            client_settings: Arc::new(Reloadable::fixed("settings", client_settings)),
+            metrics: Arc::new(StatsdClient::from_sink(
+                "test",
+                TestMetricSink { log: log.clone() },
+            )),
+            ..EndpointState::default()
+        };
+        let logged_parameter_sets = Arc::clone(&state.logged_parameter_sets);
+        let adzerk_client = AdzerkClient::new("key".into()).with_base_url(mock_adzerk_server.uri());
+        let service = test::init_service(
+            App::new()
+                .app_data(Data::new(state))
+                .app_data(Data::new(adzerk_client))
+                .route("/spocs", web::post().to(super::spocs)),
+        )
+        .await;
+
+        for _ in 0..2 {
+            let request = TestRequest::post()
+                .uri("/spocs")
+                .set_json(json!({
+                    "version": 2,
+                    "consumer_key": "40249-e88c401e1b1f2242d9e441c4",
+                    "pocket_id": "{670e8b97-c271-483f-bcb0-4921b58cdb52}",
+                    "placements": [{"name": "spocs"}, {"name": "sponsored-topics"}]
+                }))
+                .to_request();
+            let response = test::call_service(&service, request).await;
+            assert!(response.status().is_success());
+        }
+
+        let missing = log
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|metric| metric.starts_with("test.parameter_set.missing:"))
+            .cloned()
+            .collect::<Vec<_>>();
+        assert_eq!(
+            missing,
+            vec!["test.parameter_set.missing:1|c|#parameter_set:fully-personalized"; 2]
+        );
+        assert_eq!(
+            *logged_parameter_sets.lock().unwrap(),
+            HashSet::from(["fully-personalized".to_owned()])
+        );
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_keywords_from_geoip() -> Result<(), Box<dyn std::error::Error>> {
+        let mock_adzerk_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/v2"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"decisions": {}})))
+            .mount(&mock_adzerk_server)
+            .await;
+        let state = EndpointState {
+            geoip: geoip(),
+            ..EndpointState::default()
+        };
+        let adzerk_client = AdzerkClient::new("key".into()).with_base_url(mock_adzerk_server.uri());
+        let service = test::init_service(
+            App::new()
+                .app_data(Data::new(state))
+                .app_data(Data::new(adzerk_client))
+                .route("/spocs", web::post().to(super::spocs)),
+        )
+        .await;
+
+        let request = TestRequest::post()
+            .uri("/spocs")
+            .insert_header(("X-Forwarded-For", "1.2.3.4"))
+            .set_json(json!({
+                "version": 2,
+                "consumer_key": "40249-e88c401e1b1f2242d9e441c4",
+                "pocket_id": "{670e8b97-c271-483f-bcb0-4921b58cdb52}",
+                "topics": ["653", "IAB1"]
+            }))
+            .to_request();
+        let response = test::call_service(&service, request).await;
+        assert!(response.status().is_success());
+
+        let received = mock_adzerk_server.received_requests().await.unwrap();
+        assert_eq!(received.len(), 1);
+        let decision_request: Value = serde_json::from_slice(&received[0].body)?;
+        assert_eq!(
+            decision_request["keywords"],
+            json!(["US", "US-CA", "topic_travel"])
+        );
+
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_contextual_only() -> Result<(), Box<dyn std::error::Error>> {
+        let mock_adzerk_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/v2"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"decisions": {}})))
+            .mount(&mock_adzerk_server)
+            .await;
+        let state = EndpointState {
+            geoip: geoip(),
+            ..EndpointState::default()
+        };
+        let adzerk_client = AdzerkClient::new("key".into()).with_base_url(mock_adzerk_server.uri());
+        let service = test::init_service(
+            App::new()
+                .app_data(Data::new(state))
+                .app_data(Data::new(adzerk_client))
+                .route("/spocs", web::post().to(super::spocs)),
+        )
+        .await;
+
+        let request = TestRequest::post()
+            .uri("/spocs")
+            .insert_header(("X-Forwarded-For", "1.2.3.4"))
+            .set_json(json!({
+                "version": 2,
+                "consumer_key": "40249-e88c401e1b1f2242d9e441c4",
+                "pocket_id": "{670e8b97-c271-483f-bcb0-4921b58cdb52}",
+                "privacy_mode": "contextual-only"
+            }))
+            .to_request();
+        let response = test::call_service(&service, request).await;
+        assert!(response.status().is_success());
+
+        let received = mock_adzerk_server.received_requests().await.unwrap();
+        assert_eq!(received.len(), 1);
+        let decision_request: Value = serde_json::from_slice(&received[0].body)?;
+        assert_eq!(decision_request.get("user"), None);
+        assert_eq!(
+            decision_request["keywords"],
+            json!([]),
+            "The client IP must not be geolocated"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_blocked_ids_are_bounded() {
+        let request = |blocked_flights: Vec<u32>| {
+            from_value::<super::SpocsRequest>(json!({
+                "version": 2,
+                "consumer_key": "40249-e88c401e1b1f2242d9e441c4",
+                "pocket_id": "{670e8b97-c271-483f-bcb0-4921b58cdb52}",
+                "blocked_flights": blocked_flights
+            }))
+        };
+        let max = super::MAX_BLOCKED_IDS as u32;
+        assert_eq!(
+            request((0..max).collect()).unwrap().blocked_flights.len(),
+            super::MAX_BLOCKED_IDS
+        );
+        assert!(request((0..=max).collect()).is_err());
+    }
+
+    #[test]
+    fn test_flight_view_times_are_bounded() {
+        let request = |flight_view_times: Value| {
+            from_value::<super::SpocsRequest>(json!({
+                "version": 2,
+                "consumer_key": "40249-e88c401e1b1f2242d9e441c4",
+                "pocket_id": "{670e8b97-c271-483f-bcb0-4921b58cdb52}",
+                "flight_view_times": flight_view_times
+            }))
+        };
+        let now = Utc::now().timestamp() as u64;
+        let invalid_view_times = [
+            json!({"not-a-flight": [now]}),
+            json!({"1234": [-1]}),
+            json!({"1234": ["yesterday"]}),
+            json!({"1234": [0]}),
+            json!({"1234": [now - 3600, now + 2 * super::MAX_CLOCK_SKEW]}),
+            json!({"1234": [now - super::MAX_VIEW_AGE - 3600]}),
+        ];
+        for view_times in invalid_view_times {
+            assert!(request(view_times.clone()).is_err(), "{}", view_times);
+        }
+        let request_ok = request(json!({"1234": [now - 3600, now, now + 60]})).unwrap();
+        assert_eq!(
+            request_ok.flight_view_times[&1234],
+            vec![now - 3600, now, now + 60]
+        );
+
+        // Long histories keep the most recent views of the most recently
+        // viewed flights.
+        let many_flights: serde_json::Map<String, Value> = (0..=super::MAX_VIEWED_FLIGHTS as u64)
+            .map(|flight| (flight.to_string(), json!([now - flight])))
+            .collect();
+        let view_times = request(Value::Object(many_flights))
+            .unwrap()
+            .flight_view_times;
+        assert_eq!(view_times.len(), super::MAX_VIEWED_FLIGHTS);
+        assert!(!view_times.contains_key(&(super::MAX_VIEWED_FLIGHTS as u32)));
+
+        let many_views: Vec<u64> = (0..=super::MAX_VIEWS_PER_FLIGHT as u64)
+            .map(|i| now - i)
+            .collect();
+        let view_times = request(json!({ "1234": many_views }))
+            .unwrap()
+            .flight_view_times;
+        assert_eq!(view_times[&1234].len(), super::MAX_VIEWS_PER_FLIGHT);
+        assert_eq!(
+            view_times[&1234][0],
+            now - super::MAX_VIEWS_PER_FLIGHT as u64 + 1
+        );
+    }
+
+    #[test]
+    fn test_topics_are_bounded() {
+        let request = |topics: Value| {
+            from_value::<super::SpocsRequest>(json!({
+                "version": 2,
+                "consumer_key": "40249-e88c401e1b1f2242d9e441c4",
+                "pocket_id": "{670e8b97-c271-483f-bcb0-4921b58cdb52}",
+                "topics": topics
+            }))
+        };
+        assert!(request(json!(["1", "42", "52", "123", "132"])).is_ok());
+        assert!(request(json!(["1", "42", "52", "123", "132", "186"])).is_err());
+        assert!(request(json!(["1".repeat(super::MAX_TOPIC_LENGTH + 1)])).is_err());
+    }
+}
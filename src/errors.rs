@@ -18,6 +18,16 @@ impl ProxyError {
             message: format!("{}: {}", source, err),
         }
     }
+
+    /// Combine the errors of several attempts into one, or `Ok` if there are
+    /// none.
+    pub fn aggregate<S: fmt::Display>(source: S, errors: Vec<ProxyError>) -> Result<(), Self> {
+        if errors.is_empty() {
+            return Ok(());
+        }
+        let messages: Vec<_> = errors.iter().map(|err| err.message.as_str()).collect();
+        Err(Self::from_source(source, messages.join("; ")))
+    }
 }
 
 // Use default implementation of Error
@@ -59,3 +69,4 @@ impl_from_error!(uuid::Error);
 impl_from_error!(serde_json::Error);
 impl_from_error!(actix_web::http::uri::InvalidUri);
 impl_from_error!(actix_web::error::QueryPayloadError);
+impl_from_error!(openssl::error::ErrorStack);
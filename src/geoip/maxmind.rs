@@ -0,0 +1,97 @@
+use super::{ClientLocation, GeoProvider};
+use crate::errors::ProxyError;
+use maxminddb::{self, geoip2};
+use std::{fmt, net::IpAddr, path::PathBuf};
+
+/// Geolocation based on MaxMind databases.
+pub struct MaxMindProvider {
+    reader: maxminddb::Reader<Vec<u8>>,
+    asn_reader: Option<maxminddb::Reader<Vec<u8>>>,
+}
+
+impl MaxMindProvider {
+    /// Open a City database, and optionally an ASN database.
+    pub fn open<P: Into<PathBuf>>(path: P, asn_path: Option<P>) -> Result<Self, ProxyError> {
+        let reader = maxminddb::Reader::open_readfile(path.into())?;
+        let asn_reader = match asn_path {
+            Some(path) => Some(maxminddb::Reader::open_readfile(path.into())?),
+            None => None,
+        };
+        Ok(Self { reader, asn_reader })
+    }
+
+    /// Look up the autonomous system number of an IP. Missing entries in the
+    /// ASN database are not an error, since the database is optional.
+    fn locate_asn(&self, ip: IpAddr) -> Option<u32> {
+        self.asn_reader
+            .as_ref()?
+            .lookup(ip)
+            .ok()
+            .and_then(|asn_info: geoip2::Asn| asn_info.autonomous_system_number)
+    }
+}
+
+impl GeoProvider for MaxMindProvider {
+    fn locate(&self, ip: IpAddr) -> Result<ClientLocation<'_>, ProxyError> {
+        let city_info: geoip2::City = self.reader.lookup(ip)?;
+        let location = city_info.location.as_ref();
+        Ok(ClientLocation {
+            country: city_info.country.and_then(|c| c.iso_code),
+            region: city_info
+                .subdivisions
+                .as_ref()
+                .and_then(|subs| subs.last())
+                .and_then(|sub| sub.iso_code),
+            continent: city_info.continent.and_then(|c| c.code),
+            metro_code: location.and_then(|l| l.metro_code),
+            time_zone: location.and_then(|l| l.time_zone),
+            asn: self.locate_asn(ip),
+        })
+    }
+}
+
+// maxminddb reader doesn't implement Debug, so we can't use #[derive(Debug)].
+impl fmt::Debug for MaxMindProvider {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "MaxMindProvider {{ reader: Some(...), asn_reader: {} }}",
+            if self.asn_reader.is_some() {
+                "Some(...)"
+            } else {
+                "None"
+            },
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MaxMindProvider;
+    use crate::geoip::GeoProvider;
+
+    #[test]
+    #[ignore = "requires ./GeoIP2-City.mmdb"]
+    fn test_geoip_works() -> Result<(), Box<dyn std::error::Error>> {
+        let geoip = MaxMindProvider::open("./GeoIP2-City.mmdb", None)?;
+
+        // Test with an IP address in the UK to see whether the right subdivision is extracted.
+        // This is the IP address of st-andrews.ac.uk, which should not change location anytime
+        // soon.
+        let ip = "138.251.7.84".parse()?;
+        let location = geoip.locate(ip).unwrap();
+        assert_eq!(location.country.unwrap(), "GB");
+        assert_eq!(location.region.unwrap(), "FIF");
+        assert_eq!(location.continent.unwrap(), "EU");
+        assert_eq!(location.time_zone.unwrap(), "Europe/London");
+        assert_eq!(location.metro_code, None);
+        assert_eq!(location.asn, None, "No ASN database was configured");
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_database_fails() {
+        assert!(MaxMindProvider::open("./does-not-exist.mmdb", None).is_err());
+    }
+}
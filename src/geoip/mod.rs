@@ -0,0 +1,161 @@
+mod maxmind;
+mod static_provider;
+
+pub use maxmind::MaxMindProvider;
+pub use static_provider::{StaticGeoProvider, StaticLocation};
+
+use crate::errors::ProxyError;
+use cadence::StatsdClient;
+use std::{fmt, net::IpAddr, path::PathBuf, sync::Arc};
+
+/// A source of geolocation data for IP addresses.
+pub trait GeoProvider: fmt::Debug + Send + Sync {
+    fn locate(&self, ip: IpAddr) -> Result<ClientLocation<'_>, ProxyError>;
+}
+
+pub struct GeoIp {
+    provider: Option<Box<dyn GeoProvider>>,
+    metrics: Arc<StatsdClient>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClientLocation<'a> {
+    pub country: Option<&'a str>,
+    pub region: Option<&'a str>,
+    /// Two-letter continent code, e.g. "EU" or "NA".
+    pub continent: Option<&'a str>,
+    /// US metro (DMA) code. Only available for locations in the US.
+    pub metro_code: Option<u16>,
+    /// IANA time zone name, e.g. "Europe/London".
+    pub time_zone: Option<&'a str>,
+    /// Autonomous system number. Only available if an ASN database is configured.
+    pub asn: Option<u32>,
+}
+
+impl GeoIp {
+    pub fn builder() -> GeoIpBuilder {
+        GeoIpBuilder::default()
+    }
+
+    pub fn locate(&self, ip: IpAddr) -> Result<ClientLocation<'_>, ProxyError> {
+        self.provider
+            .as_ref()
+            .ok_or_else(|| ProxyError::new("No geoip database available"))?
+            .locate(ip)
+    }
+}
+
+impl Default for GeoIp {
+    fn default() -> Self {
+        GeoIp::builder().build().unwrap()
+    }
+}
+
+impl fmt::Debug for GeoIp {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "GeoIp {{ provider: {:?}, metrics: {:?} }}",
+            self.provider, self.metrics
+        )?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct GeoIpBuilder {
+    path: Option<PathBuf>,
+    asn_path: Option<PathBuf>,
+    provider: Option<Box<dyn GeoProvider>>,
+    metrics: Option<Arc<StatsdClient>>,
+}
+
+impl GeoIpBuilder {
+    /// Path to a MaxMind City database.
+    pub fn path<P>(mut self, path: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        self.path = Some(path.into());
+        self
+    }
+
+    /// Path to an optional GeoLite2-ASN or GeoIP2-ASN database.
+    pub fn asn_path<P>(mut self, asn_path: Option<P>) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        self.asn_path = asn_path.map(Into::into);
+        self
+    }
+
+    /// Use a custom provider instead of the MaxMind databases.
+    pub fn provider<G>(mut self, provider: G) -> Self
+    where
+        G: GeoProvider + 'static,
+    {
+        self.provider = Some(Box::new(provider));
+        self
+    }
+
+    pub fn metrics(mut self, metrics: Arc<StatsdClient>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    pub fn build(self) -> Result<GeoIp, ProxyError> {
+        let provider = match (self.provider, self.path) {
+            (Some(provider), _) => Some(provider),
+            (None, Some(path)) => Some(Box::new(MaxMindProvider::open(path, self.asn_path)?) as _),
+            (None, None) if self.asn_path.is_some() => {
+                return Err(ProxyError::new("An ASN database requires a City database"))
+            }
+            (None, None) => None,
+        };
+        let metrics = self.metrics.unwrap_or_else(|| {
+            Arc::new(StatsdClient::from_sink("default", cadence::NopMetricSink))
+        });
+        Ok(GeoIp { provider, metrics })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ClientLocation, GeoIp, StaticGeoProvider, StaticLocation};
+
+    #[test]
+    fn test_geoip_without_provider() {
+        let geoip = GeoIp::default();
+        assert!(geoip.locate("1.2.3.4".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_asn_database_without_city_database_fails() {
+        let result = GeoIp::builder()
+            .asn_path(Some("./GeoLite2-ASN.mmdb"))
+            .build();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_geoip_with_provider() -> Result<(), Box<dyn std::error::Error>> {
+        let provider = StaticGeoProvider::default().with_location(
+            "1.2.3.0/24".parse()?,
+            StaticLocation {
+                country: Some("US".to_owned()),
+                region: Some("CA".to_owned()),
+                ..StaticLocation::default()
+            },
+        );
+        let geoip = GeoIp::builder().provider(provider).build()?;
+        assert_eq!(
+            geoip.locate("1.2.3.4".parse()?)?,
+            ClientLocation {
+                country: Some("US"),
+                region: Some("CA"),
+                ..ClientLocation::default()
+            }
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,212 @@
+use super::{ClientLocation, GeoProvider};
+use crate::errors::ProxyError;
+use ipnet::IpNet;
+use serde::Deserialize;
+use std::{fs, net::IpAddr, path::Path};
+
+/// Geolocation from a fixed list of networks.
+///
+/// For local development, the list can be loaded from a JSON or CSV file. Tests
+/// can build the list in memory with [`StaticGeoProvider::with_location`]. If
+/// several networks contain an IP, the most specific one wins.
+#[derive(Clone, Debug, Default)]
+pub struct StaticGeoProvider {
+    entries: Vec<StaticEntry>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct StaticEntry {
+    network: IpNet,
+    #[serde(flatten)]
+    location: StaticLocation,
+}
+
+/// An owned version of [`ClientLocation`].
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+pub struct StaticLocation {
+    pub country: Option<String>,
+    pub region: Option<String>,
+    pub continent: Option<String>,
+    pub metro_code: Option<u16>,
+    pub time_zone: Option<String>,
+    pub asn: Option<u32>,
+}
+
+impl StaticLocation {
+    fn as_client_location(&self) -> ClientLocation<'_> {
+        ClientLocation {
+            country: self.country.as_deref(),
+            region: self.region.as_deref(),
+            continent: self.continent.as_deref(),
+            metro_code: self.metro_code,
+            time_zone: self.time_zone.as_deref(),
+            asn: self.asn,
+        }
+    }
+}
+
+impl StaticGeoProvider {
+    /// Load networks from a file. Files ending in `.csv` are parsed as CSV,
+    /// everything else as JSON.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, ProxyError> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)?;
+        if path.extension().map_or(false, |ext| ext == "csv") {
+            Self::from_csv(&contents)
+        } else {
+            Self::from_json(&contents)
+        }
+    }
+
+    /// Parse a JSON list of objects with a `network` in CIDR notation and the
+    /// fields of [`StaticLocation`].
+    pub fn from_json(json: &str) -> Result<Self, ProxyError> {
+        let entries = serde_json::from_str(json)?;
+        Ok(Self { entries })
+    }
+
+    /// Parse CSV with the header
+    /// `network,country,region,continent,metro_code,time_zone,asn`. Empty
+    /// fields are treated as unknown. Quoting is not supported.
+    pub fn from_csv(csv: &str) -> Result<Self, ProxyError> {
+        const HEADER: &str = "network,country,region,continent,metro_code,time_zone,asn";
+        let mut lines = csv.lines().filter(|line| !line.trim().is_empty());
+        if lines.next().map(str::trim) != Some(HEADER) {
+            return Err(ProxyError::new(format!(
+                "Static geolocation CSV must start with the header '{}'",
+                HEADER
+            )));
+        }
+        let entries = lines
+            .enumerate()
+            .map(|(index, line)| {
+                let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+                let (network, country, region, continent, metro_code, time_zone, asn) =
+                    match fields[..] {
+                        [a, b, c, d, e, f, g] => (a, b, c, d, e, f, g),
+                        _ => {
+                            return Err(ProxyError::new(format!(
+                                "Wrong number of fields in static geolocation CSV row {}",
+                                index + 1
+                            )))
+                        }
+                    };
+                let text = |field: &str| (!field.is_empty()).then(|| field.to_owned());
+                Ok(StaticEntry {
+                    network: network.parse()?,
+                    location: StaticLocation {
+                        country: text(country),
+                        region: text(region),
+                        continent: text(continent),
+                        metro_code: parse_number(metro_code)?,
+                        time_zone: text(time_zone),
+                        asn: parse_number(asn)?,
+                    },
+                })
+            })
+            .collect::<Result<_, ProxyError>>()?;
+        Ok(Self { entries })
+    }
+
+    /// Add a network to the list.
+    pub fn with_location(mut self, network: IpNet, location: StaticLocation) -> Self {
+        self.entries.push(StaticEntry { network, location });
+        self
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(field: &str) -> Result<Option<T>, ProxyError> {
+    if field.is_empty() {
+        return Ok(None);
+    }
+    field
+        .parse()
+        .map(Some)
+        .map_err(|_| ProxyError::new(format!("Invalid number in static geolocation: {}", field)))
+}
+
+impl GeoProvider for StaticGeoProvider {
+    fn locate(&self, ip: IpAddr) -> Result<ClientLocation<'_>, ProxyError> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.network.contains(&ip))
+            .max_by_key(|entry| entry.network.prefix_len())
+            .map(|entry| entry.location.as_client_location())
+            .ok_or_else(|| ProxyError::new(format!("No static geolocation for {}", ip)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StaticGeoProvider, StaticLocation};
+    use crate::geoip::{ClientLocation, GeoProvider};
+
+    #[test]
+    fn test_most_specific_network_wins() -> Result<(), Box<dyn std::error::Error>> {
+        let location = |country: &str| StaticLocation {
+            country: Some(country.to_owned()),
+            ..StaticLocation::default()
+        };
+        let provider = StaticGeoProvider::default()
+            .with_location("1.2.0.0/16".parse()?, location("US"))
+            .with_location("1.2.3.0/24".parse()?, location("CA"))
+            .with_location("0.0.0.0/0".parse()?, location("GB"));
+        assert_eq!(provider.locate("1.2.3.4".parse()?)?.country, Some("CA"));
+        assert_eq!(provider.locate("1.2.4.4".parse()?)?.country, Some("US"));
+        assert_eq!(provider.locate("9.9.9.9".parse()?)?.country, Some("GB"));
+        assert!(provider.locate("2001:db8::1".parse()?).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_json() -> Result<(), Box<dyn std::error::Error>> {
+        let provider = StaticGeoProvider::from_json(
+            r#"[
+                {"network": "2001:db8::/32", "country": "US", "region": "IL",
+                 "continent": "NA", "metro_code": 602, "time_zone": "America/Chicago",
+                 "asn": 64496}
+            ]"#,
+        )?;
+        assert_eq!(
+            provider.locate("2001:db8::1".parse()?)?,
+            ClientLocation {
+                country: Some("US"),
+                region: Some("IL"),
+                continent: Some("NA"),
+                metro_code: Some(602),
+                time_zone: Some("America/Chicago"),
+                asn: Some(64496),
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_csv() -> Result<(), Box<dyn std::error::Error>> {
+        let provider = StaticGeoProvider::from_csv(
+            "network,country,region,continent,metro_code,time_zone,asn\n\
+             10.0.0.0/8,DE,BE,EU,,Europe/Berlin,\n",
+        )?;
+        assert_eq!(
+            provider.locate("10.1.2.3".parse()?)?,
+            ClientLocation {
+                country: Some("DE"),
+                region: Some("BE"),
+                continent: Some("EU"),
+                time_zone: Some("Europe/Berlin"),
+                ..ClientLocation::default()
+            }
+        );
+
+        assert!(StaticGeoProvider::from_csv("10.0.0.0/8,DE,BE,EU,,,\n").is_err());
+        assert!(StaticGeoProvider::from_csv(
+            "network,country,region,continent,metro_code,time_zone,asn\n10.0.0.0/8,DE\n"
+        )
+        .is_err());
+        assert!(StaticGeoProvider::from_csv(
+            "network,country,region,continent,metro_code,time_zone,asn\n10.0.0.0/8,DE,,,DMA,,\n"
+        )
+        .is_err());
+        Ok(())
+    }
+}
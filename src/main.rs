@@ -8,22 +8,35 @@ pub mod errors;
 pub mod geoip;
 pub mod logging;
 pub mod metrics;
+pub mod privacy;
+pub mod reload;
+pub mod remote;
 pub mod settings;
+pub mod targeting;
 pub mod utils;
 
 use crate::{
-    adzerk::client::AdzerkClient,
-    endpoints::{debug, delete_user, dockerflow, spocs, EndpointState},
+    adzerk::{
+        affinities::DomainAffinities, client::AdzerkClient, client_settings::ClientSettings,
+        diversity::DiversityRules, experiments::Experiments, keywords::KeywordTemplates,
+        overlays::SettingsOverlays, personalization::ModelPrefixes, placements::Placements,
+        priorities::PriorityMap, topics::TopicTaxonomy,
+    },
+    endpoints::{debug, delete_user, dockerflow, export_user, opt_out_user, spocs, EndpointState},
     errors::ProxyError,
-    geoip::GeoIp,
+    geoip::{GeoIp, StaticGeoProvider},
+    privacy::{consent::ConsentPolicy, pseudonym::Pseudonymizer},
+    reload::Reloadable,
+    remote::RemoteConfig,
     settings::Settings,
+    targeting::{location::GeoHeaders, policy::GeoPolicy},
 };
 use actix_web::{
     web::{self, Data},
     App,
 };
 
-use std::sync::Arc;
+use std::{fs, path::Path, sync::Arc, time::Duration};
 
 const APP_NAME: &str = "pocket-proxy";
 
@@ -31,14 +44,43 @@ const APP_NAME: &str = "pocket-proxy";
 async fn main() -> Result<(), ProxyError> {
     let Settings {
         debug,
+        edge_country_header,
+        edge_region_header,
+        geo_override_header,
         geoip_db_path,
+        geoip_asn_db_path,
+        geoip_static_path,
         host,
         human_logs,
         metrics_target,
         port,
         trusted_proxy_list,
+        trusted_proxy_hops,
+        forwarding_headers,
         version_file,
         adzerk_api_key,
+        keyword_templates_path,
+        topic_taxonomy_path,
+        diversity_rules_path,
+        ranker,
+        priority_map_path,
+        placements_path,
+        remote_config_url,
+        remote_config_public_key_path,
+        remote_config_cache_dir,
+        client_settings_path,
+        settings_overlays_path,
+        domain_affinities_path,
+        experiments_path,
+        personalization_model_prefixes,
+        config_reload_interval,
+        geo_policy_path,
+        gdpr_countries,
+        gdpr_user_key_policy,
+        user_key_salts,
+        user_key_migration,
+        privacy_mode,
+        collections_min_client_version,
         ..
     } = Settings::load()?;
 
@@ -49,17 +91,188 @@ async fn main() -> Result<(), ProxyError> {
             .unwrap_or_else(|err| panic!("Critical failure setting up metrics logging: {}", err)),
     );
 
+    let keyword_templates = match keyword_templates_path {
+        Some(path) => KeywordTemplates::from_file(path)?,
+        None => KeywordTemplates::default(),
+    };
+
+    let topic_taxonomy = match topic_taxonomy_path {
+        Some(path) => TopicTaxonomy::from_file(path)?,
+        None => TopicTaxonomy::default(),
+    };
+
+    let priority_map = Arc::new(match priority_map_path {
+        Some(path) => Reloadable::from_file("priority map", path, |path: &Path| {
+            PriorityMap::from_file(path)
+        })?,
+        None => Reloadable::fixed("priority map", PriorityMap::default()),
+    });
+    priority_map.watch(
+        Duration::from_secs(config_reload_interval),
+        app_log.clone(),
+        Arc::clone(&metrics),
+    );
+
+    let client_settings = Arc::new(match client_settings_path {
+        Some(path) => Reloadable::from_file("settings", path, |path: &Path| {
+            ClientSettings::from_file(path)
+        })?,
+        None => Reloadable::fixed("settings", ClientSettings::default()),
+    });
+    client_settings.watch(
+        Duration::from_secs(config_reload_interval),
+        app_log.clone(),
+        Arc::clone(&metrics),
+    );
+
+    let settings_overlays = Arc::new(match settings_overlays_path {
+        Some(path) => Reloadable::from_file("settings overlays", path, |path: &Path| {
+            SettingsOverlays::from_file(path)
+        })?,
+        None => Reloadable::fixed("settings overlays", SettingsOverlays::default()),
+    });
+    settings_overlays.watch(
+        Duration::from_secs(config_reload_interval),
+        app_log.clone(),
+        Arc::clone(&metrics),
+    );
+
+    let domain_affinities = Arc::new(match domain_affinities_path {
+        Some(path) => Reloadable::from_file("domain affinities", path, |path: &Path| {
+            DomainAffinities::from_file(path)
+        })?,
+        None => Reloadable::fixed("domain affinities", DomainAffinities::default()),
+    });
+    domain_affinities.watch(
+        Duration::from_secs(config_reload_interval),
+        app_log.clone(),
+        Arc::clone(&metrics),
+    );
+
+    let placements = Arc::new(match placements_path {
+        Some(path) => {
+            let client_settings = Arc::clone(&client_settings);
+            Reloadable::from_file("placements", path, move |path: &Path| {
+                Placements::from_file(path, &client_settings.get())
+            })?
+        }
+        None => Reloadable::fixed("placements", Placements::default()),
+    });
+    placements.watch(
+        Duration::from_secs(config_reload_interval),
+        app_log.clone(),
+        Arc::clone(&metrics),
+    );
+
+    let experiments = Arc::new(match experiments_path {
+        Some(path) => {
+            let client_settings = Arc::clone(&client_settings);
+            Reloadable::from_file("experiments", path, move |path: &Path| {
+                Experiments::from_file(path, &client_settings.get())
+            })?
+        }
+        None => Reloadable::fixed("experiments", Experiments::default()),
+    });
+    experiments.watch(
+        Duration::from_secs(config_reload_interval),
+        app_log.clone(),
+        Arc::clone(&metrics),
+    );
+
+    if let (Some(url), Some(public_key_path)) = (remote_config_url, remote_config_public_key_path) {
+        let settings = Arc::clone(&client_settings);
+        RemoteConfig::new(url, &fs::read(public_key_path)?, remote_config_cache_dir)?
+            .with(
+                "settings.json",
+                Arc::clone(&client_settings),
+                ClientSettings::from_json,
+            )
+            .with(
+                "overlays.json",
+                Arc::clone(&settings_overlays),
+                SettingsOverlays::from_json,
+            )
+            .with(
+                "domain_affinities.json",
+                Arc::clone(&domain_affinities),
+                DomainAffinities::from_json,
+            )
+            .with(
+                "priorities.json",
+                Arc::clone(&priority_map),
+                PriorityMap::from_json,
+            )
+            .with("placements.json", Arc::clone(&placements), {
+                let settings = Arc::clone(&settings);
+                move |json| Placements::from_json(json, &settings.get())
+            })
+            .with("experiments.json", Arc::clone(&experiments), move |json| {
+                Experiments::from_json(json, &settings.get())
+            })
+            .start(
+                Duration::from_secs(config_reload_interval),
+                app_log.clone(),
+                Arc::clone(&metrics),
+            );
+    }
+
+    let diversity_rules = match diversity_rules_path {
+        Some(path) => DiversityRules::from_file(path)?,
+        None => DiversityRules::default(),
+    };
+
+    let geo_policy = match geo_policy_path {
+        Some(path) => GeoPolicy::from_file(path)?,
+        None => GeoPolicy::default(),
+    };
+
+    let geoip = match geoip_static_path {
+        Some(path) => GeoIp::builder().provider(StaticGeoProvider::from_file(path)?),
+        None => GeoIp::builder()
+            .path(geoip_db_path)
+            .asn_path(geoip_asn_db_path),
+    };
+
     let state = EndpointState {
-        geoip: Arc::new(
-            GeoIp::builder()
-                .path(geoip_db_path)
-                .metrics(Arc::clone(&metrics))
-                .build()?,
-        ),
+        geoip: Arc::new(geoip.metrics(Arc::clone(&metrics)).build()?),
+        keyword_templates: Arc::new(keyword_templates),
+        topic_taxonomy: Arc::new(topic_taxonomy),
+        diversity_rules: Arc::new(diversity_rules),
+        ranker: ranker.ranker(),
+        priority_map,
+        logged_priorities: Arc::default(),
+        logged_parameter_sets: Arc::default(),
+        placements,
+        client_settings,
+        settings_overlays,
+        domain_affinities,
+        experiments,
+        model_prefixes: Arc::new(ModelPrefixes::new(personalization_model_prefixes)?),
+        geo_policy: Arc::new(geo_policy),
+        consent_policy: ConsentPolicy {
+            gdpr_countries: gdpr_countries
+                .iter()
+                .map(|country| country.trim().to_ascii_uppercase())
+                .collect(),
+            user_key_policy: gdpr_user_key_policy,
+        },
+        pseudonymizer: Arc::new(Pseudonymizer::from_salts(
+            &user_key_salts,
+            user_key_migration,
+        )?),
+        privacy_mode,
         metrics,
         trusted_proxies: trusted_proxy_list,
+        trusted_proxy_hops,
+        forwarding_headers,
+        geo_headers: GeoHeaders {
+            country: edge_country_header,
+            region: edge_region_header,
+            qa_override: debug.then(|| geo_override_header),
+        },
         log: app_log.clone(),
         version_file,
+        collections_min_client_version,
     };
 
     let addr = format!("{}:{}", host, port);
@@ -75,6 +288,10 @@ async fn main() -> Result<(), ProxyError> {
             // API Endpoints
             .service(web::resource("/spocs").route(web::post().to(spocs::spocs)))
             .service(web::resource("/user").route(web::delete().to(delete_user::delete_user)))
+            .service(web::resource("/user/export").route(web::post().to(export_user::export_user)))
+            .service(
+                web::resource("/user/opt-out").route(web::post().to(opt_out_user::opt_out_user)),
+            )
             // Dockerflow Endpoints
             .service(
                 web::resource("/__lbheartbeat__").route(web::get().to(dockerflow::lbheartbeat)),
@@ -0,0 +1,174 @@
+use serde::Deserialize;
+
+/// Maximum size of a filter, in bytes.
+pub const MAX_FILTER_BYTES: usize = 4096;
+/// Maximum number of hash functions of a filter.
+pub const MAX_HASHES: u32 = 16;
+/// Filters with a higher estimated false positive rate are ignored, as they
+/// would hide too many spocs from domains the user didn't block.
+pub const MAX_FALSE_POSITIVE_RATE: f64 = 0.05;
+
+/// A Bloom filter of domains the user blocked, sent by the client so the
+/// domains themselves are never revealed.
+///
+/// Each domain is normalized (trimmed, lowercased, without a trailing dot or a
+/// leading `www.`) and hashed with SHA-256. With `h1` and `h2` the first and
+/// second 8 bytes of the digest, read as big-endian unsigned integers, the
+/// domain sets bits `(h1 + i * h2) mod m` for `i` in `0..hashes`, with
+/// wrapping 64-bit arithmetic and `m` the size of the filter in bits. Bit `n`
+/// is bit `n % 8`, counting from the least significant, of byte `n / 8`. The
+/// bytes are sent base64 encoded.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "DomainBloomFilterConfig")]
+pub struct DomainBloomFilter {
+    bits: Vec<u8>,
+    hashes: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DomainBloomFilterConfig {
+    bits: String,
+    hashes: u32,
+}
+
+impl TryFrom<DomainBloomFilterConfig> for DomainBloomFilter {
+    type Error = String;
+
+    fn try_from(config: DomainBloomFilterConfig) -> Result<Self, Self::Error> {
+        if !(1..=MAX_HASHES).contains(&config.hashes) {
+            return Err(format!(
+                "blocked domains filter must use 1 to {} hashes",
+                MAX_HASHES
+            ));
+        }
+        // Check the encoded length first, to avoid decoding oversized filters.
+        if config.bits.len() > (MAX_FILTER_BYTES + 2) / 3 * 4 {
+            return Err(format!(
+                "blocked domains filter must be at most {} bytes",
+                MAX_FILTER_BYTES
+            ));
+        }
+        let bits = openssl::base64::decode_block(&config.bits)
+            .map_err(|_| "blocked domains filter must be base64 encoded".to_owned())?;
+        if bits.is_empty() || bits.len() > MAX_FILTER_BYTES {
+            return Err(format!(
+                "blocked domains filter must be 1 to {} bytes",
+                MAX_FILTER_BYTES
+            ));
+        }
+        Ok(Self {
+            bits,
+            hashes: config.hashes,
+        })
+    }
+}
+
+impl DomainBloomFilter {
+    /// Whether a domain may be in the filter.
+    pub fn contains(&self, domain: &str) -> bool {
+        let digest = openssl::sha::sha256(normalize_domain(domain).as_bytes());
+        let h1 = u64::from_be_bytes(digest[0..8].try_into().unwrap());
+        let h2 = u64::from_be_bytes(digest[8..16].try_into().unwrap());
+        let size = self.bits.len() as u64 * 8;
+        (0..u64::from(self.hashes)).all(|i| {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) % size;
+            self.bits[(bit / 8) as usize] & (1 << (bit % 8)) != 0
+        })
+    }
+
+    /// The probability that a domain which isn't in the filter matches, given
+    /// the proportion of bits that are set.
+    pub fn estimated_false_positive_rate(&self) -> f64 {
+        let set_bits: u32 = self.bits.iter().map(|byte| byte.count_ones()).sum();
+        let fill_ratio = f64::from(set_bits) / (self.bits.len() * 8) as f64;
+        fill_ratio.powi(self.hashes as i32)
+    }
+}
+
+fn normalize_domain(domain: &str) -> String {
+    let domain = domain.trim().trim_end_matches('.').to_ascii_lowercase();
+    match domain.strip_prefix("www.") {
+        Some(domain) => domain.to_owned(),
+        None => domain,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{normalize_domain, DomainBloomFilter, MAX_FILTER_BYTES};
+    use serde_json::{from_value, json};
+
+    /// Build a filter the way a client would, following the documented scheme.
+    fn filter(size: usize, hashes: u32, domains: &[&str]) -> DomainBloomFilter {
+        let mut bits = vec![0u8; size];
+        for domain in domains {
+            let digest = openssl::sha::sha256(normalize_domain(domain).as_bytes());
+            let h1 = u64::from_be_bytes(digest[0..8].try_into().unwrap());
+            let h2 = u64::from_be_bytes(digest[8..16].try_into().unwrap());
+            for i in 0..u64::from(hashes) {
+                let bit = h1.wrapping_add(i.wrapping_mul(h2)) % (size as u64 * 8);
+                bits[(bit / 8) as usize] |= 1 << (bit % 8);
+            }
+        }
+        from_value(json!({
+            "bits": openssl::base64::encode_block(&bits),
+            "hashes": hashes,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_contains() {
+        let filter = filter(256, 7, &["example.com", "WWW.Blocked.org."]);
+        assert!(filter.contains("example.com"));
+        assert!(filter.contains("www.example.com"));
+        assert!(filter.contains("blocked.org"));
+        assert!(!filter.contains("example.org"));
+        assert!(!filter.contains("mozilla.org"));
+        assert!(filter.estimated_false_positive_rate() < 1e-6);
+    }
+
+    #[test]
+    fn test_known_bits() {
+        // SHA-256("example.com") starts with a379a6f6eeafb9a5 5e378c118034e275,
+        // so with one hash in a 64-bit filter, the domain sets bit
+        // 0xa379a6f6eeafb9a5 % 64 = 37, i.e. bit 5 of byte 4.
+        let filter: DomainBloomFilter = from_value(json!({
+            "bits": openssl::base64::encode_block(&[0, 0, 0, 0, 0b0010_0000, 0, 0, 0]),
+            "hashes": 1,
+        }))
+        .unwrap();
+        assert!(filter.contains("example.com"));
+    }
+
+    #[test]
+    fn test_saturated_filter() {
+        let filter: DomainBloomFilter = from_value(json!({
+            "bits": openssl::base64::encode_block(&[0xff; 16]),
+            "hashes": 4,
+        }))
+        .unwrap();
+        assert_eq!(filter.estimated_false_positive_rate(), 1.0);
+        assert!(filter.contains("anything.example"));
+    }
+
+    #[test]
+    fn test_invalid_filters() {
+        let invalid_filters = [
+            json!({"bits": "AAAA", "hashes": 0}),
+            json!({"bits": "AAAA", "hashes": 17}),
+            json!({"bits": "", "hashes": 3}),
+            json!({"bits": "not base64!", "hashes": 3}),
+            json!({"bits": openssl::base64::encode_block(&[0; MAX_FILTER_BYTES + 1]), "hashes": 3}),
+            json!({"bits": "AAAA", "hashes": 3, "salt": "x"}),
+        ];
+        for filter in invalid_filters {
+            assert!(
+                from_value::<DomainBloomFilter>(filter.clone()).is_err(),
+                "{} should be rejected",
+                filter
+            );
+        }
+    }
+}
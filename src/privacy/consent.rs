@@ -0,0 +1,214 @@
+use super::pseudonym::Pseudonymizer;
+use crate::errors::ProxyError;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// Countries in the European Economic Area, plus the United Kingdom.
+pub const GDPR_COUNTRIES: [&str; 31] = [
+    "AT", "BE", "BG", "CY", "CZ", "DE", "DK", "EE", "ES", "FI", "FR", "GB", "GR", "HR", "HU", "IE",
+    "IS", "IT", "LI", "LT", "LU", "LV", "MT", "NL", "NO", "PL", "PT", "RO", "SE", "SI", "SK",
+];
+
+pub fn default_gdpr_countries() -> Vec<String> {
+    GDPR_COUNTRIES.iter().map(|&c| c.to_owned()).collect()
+}
+
+/// What to do with the user key of a client that hasn't consented to the use
+/// of personal data.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum UserKeyPolicy {
+    /// Send the user key unchanged.
+    Keep,
+    /// Don't send a user key at all.
+    Drop,
+    /// Replace the user key with an HMAC of the key and the current UTC date,
+    /// keyed with the user key salts, so Kevel can't link decisions across
+    /// days. Requires user key salts.
+    Rotate,
+}
+
+impl UserKeyPolicy {
+    pub fn default_policy() -> Self {
+        UserKeyPolicy::Drop
+    }
+
+    /// Apply the policy to a user key.
+    pub fn apply(
+        self,
+        user_key: String,
+        pseudonymizer: &Pseudonymizer,
+        now: DateTime<Utc>,
+    ) -> Result<Option<String>, ProxyError> {
+        match self {
+            UserKeyPolicy::Keep => Ok(Some(user_key)),
+            UserKeyPolicy::Drop => Ok(None),
+            UserKeyPolicy::Rotate => pseudonymizer.daily_key(&user_key, now).map(Some),
+        }
+    }
+}
+
+/// Consent signal sent by the client.
+///
+/// Unknown fields are ignored, so clients can send new signals before the
+/// proxy uses them.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+pub struct ClientConsent {
+    /// Whether the client consents to the use of personal data under GDPR.
+    pub gdpr: Option<bool>,
+}
+
+/// Which jurisdictions require consent, and how to treat clients without it.
+#[derive(Clone, Debug)]
+pub struct ConsentPolicy {
+    pub gdpr_countries: HashSet<String>,
+    pub user_key_policy: UserKeyPolicy,
+}
+
+impl Default for ConsentPolicy {
+    fn default() -> Self {
+        Self {
+            gdpr_countries: default_gdpr_countries().into_iter().collect(),
+            user_key_policy: UserKeyPolicy::default_policy(),
+        }
+    }
+}
+
+/// The outcome of evaluating the consent policy for a request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConsentDecision {
+    /// The value of Kevel's `consent.gdpr` field, if it should be sent.
+    pub gdpr: Option<bool>,
+    /// What to do with the user key.
+    pub user_key_policy: UserKeyPolicy,
+}
+
+impl ConsentDecision {
+    /// Whether the client's user key is sent to Kevel as is.
+    pub fn keeps_user_key(&self) -> bool {
+        self.user_key_policy == UserKeyPolicy::Keep
+    }
+}
+
+impl ConsentPolicy {
+    /// Decide which consent fields to send to Kevel, and what to do with the
+    /// user key. Clients in a GDPR jurisdiction are assumed not to consent
+    /// unless they say so. Clients elsewhere are assumed to consent unless they
+    /// say otherwise.
+    pub fn evaluate(
+        &self,
+        country: Option<&str>,
+        consent: Option<ClientConsent>,
+    ) -> ConsentDecision {
+        let in_gdpr_jurisdiction = country.map_or(false, |c| {
+            self.gdpr_countries.contains(&c.trim().to_ascii_uppercase())
+        });
+        let signal = consent.and_then(|c| c.gdpr);
+        let consented = signal.unwrap_or(!in_gdpr_jurisdiction);
+        ConsentDecision {
+            gdpr: (in_gdpr_jurisdiction || signal.is_some()).then(|| consented),
+            user_key_policy: if consented {
+                UserKeyPolicy::Keep
+            } else {
+                self.user_key_policy
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ClientConsent, ConsentDecision, ConsentPolicy, UserKeyPolicy};
+    use crate::privacy::pseudonym::Pseudonymizer;
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn test_evaluate() {
+        let policy = ConsentPolicy::default();
+        let consent = |gdpr| Some(ClientConsent { gdpr: Some(gdpr) });
+        let test_cases = [
+            (Some("US"), None, None, UserKeyPolicy::Keep),
+            (None, None, None, UserKeyPolicy::Keep),
+            (Some("US"), consent(false), Some(false), UserKeyPolicy::Drop),
+            (Some("DE"), None, Some(false), UserKeyPolicy::Drop),
+            (Some(" de"), None, Some(false), UserKeyPolicy::Drop),
+            (
+                Some("GB"),
+                Some(ClientConsent::default()),
+                Some(false),
+                UserKeyPolicy::Drop,
+            ),
+            (Some("DE"), consent(true), Some(true), UserKeyPolicy::Keep),
+        ];
+        for (country, client_consent, gdpr, user_key_policy) in test_cases {
+            assert_eq!(
+                policy.evaluate(country, client_consent),
+                ConsentDecision {
+                    gdpr,
+                    user_key_policy
+                },
+                "country {:?}, consent {:?}",
+                country,
+                client_consent
+            );
+        }
+    }
+
+    #[test]
+    fn test_unknown_consent_fields() {
+        let consent: ClientConsent =
+            serde_json::from_str(r#"{"gdpr": true, "ccpa": false}"#).unwrap();
+        assert_eq!(consent, ClientConsent { gdpr: Some(true) });
+    }
+
+    #[test]
+    fn test_rotate_user_key() {
+        let key = "{670e8b97-c271-483f-bcb0-4921b58cdb52}".to_owned();
+        let day1 = Utc.ymd(2022, 6, 1).and_hms(0, 0, 1);
+        let day1_later = Utc.ymd(2022, 6, 1).and_hms(23, 59, 59);
+        let day2 = Utc.ymd(2022, 6, 2).and_hms(0, 0, 1);
+
+        let pseudonymizer = Pseudonymizer::from_salts(&["2022-01-01:secret"], false).unwrap();
+        let rotate = |now| {
+            UserKeyPolicy::Rotate
+                .apply(key.clone(), &pseudonymizer, now)
+                .unwrap()
+                .unwrap()
+        };
+
+        let rotated = rotate(day1);
+        assert_eq!(rotated.len(), 64);
+        assert_ne!(rotated, key);
+        assert_eq!(
+            rotate(day1_later),
+            rotated,
+            "The key is stable within a day"
+        );
+        assert_ne!(rotate(day2), rotated, "The key changes every day");
+
+        let other_salt = Pseudonymizer::from_salts(&["2022-01-01:other"], false).unwrap();
+        assert_ne!(
+            UserKeyPolicy::Rotate
+                .apply(key.clone(), &other_salt, day1)
+                .unwrap(),
+            Some(rotated),
+            "The key depends on the salt"
+        );
+        assert!(UserKeyPolicy::Rotate
+            .apply(key.clone(), &Pseudonymizer::default(), day1)
+            .is_err());
+        assert_eq!(
+            UserKeyPolicy::Drop
+                .apply(key.clone(), &pseudonymizer, day1)
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            UserKeyPolicy::Keep
+                .apply(key.clone(), &pseudonymizer, day1)
+                .unwrap(),
+            Some(key)
+        );
+    }
+}
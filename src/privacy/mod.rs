@@ -0,0 +1,6 @@
+//! Controls over which identifiers and signals about a client leave the proxy.
+
+pub mod blocked_domains;
+pub mod consent;
+pub mod mode;
+pub mod pseudonym;
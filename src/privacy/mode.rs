@@ -0,0 +1,77 @@
+use serde::{Deserialize, Serialize};
+
+/// How much information about a client may be processed to serve it spocs.
+///
+/// Modes are ordered from least to most restrictive, and each mode includes the
+/// restrictions of the modes before it. When both the deployment and the
+/// request set a mode, the most restrictive one applies.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+pub enum PrivacyMode {
+    /// No restrictions beyond the consent policy.
+    Standard,
+    /// The client IP is never geolocated. Only the country and region supplied
+    /// by the client are used for targeting.
+    ClientGeoOnly,
+    /// Like `ClientGeoOnly`, and no user key is sent to Kevel, so decisions are
+    /// based on the placement and keywords alone.
+    ContextualOnly,
+}
+
+impl PrivacyMode {
+    pub fn default_mode() -> Self {
+        PrivacyMode::Standard
+    }
+
+    /// The mode that applies to a request, given the deployment's mode.
+    pub fn for_request(self, requested: Option<PrivacyMode>) -> Self {
+        requested.map_or(self, |requested| self.max(requested))
+    }
+
+    /// Whether the client IP may be geolocated, by us or by the edge.
+    pub fn allows_ip_geolocation(self) -> bool {
+        self < PrivacyMode::ClientGeoOnly
+    }
+
+    /// Whether a user key may be sent to Kevel.
+    pub fn allows_user_key(self) -> bool {
+        self < PrivacyMode::ContextualOnly
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PrivacyMode::Standard => "standard",
+            PrivacyMode::ClientGeoOnly => "client-geo-only",
+            PrivacyMode::ContextualOnly => "contextual-only",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PrivacyMode;
+
+    #[test]
+    fn test_for_request() {
+        use PrivacyMode::*;
+        let test_cases = [
+            (Standard, None, Standard),
+            (Standard, Some(ClientGeoOnly), ClientGeoOnly),
+            (ClientGeoOnly, Some(Standard), ClientGeoOnly),
+            (ClientGeoOnly, Some(ContextualOnly), ContextualOnly),
+            (ContextualOnly, Some(ClientGeoOnly), ContextualOnly),
+        ];
+        for (deployment, requested, expected) in test_cases {
+            assert_eq!(
+                deployment.for_request(requested),
+                expected,
+                "deployment {:?}, requested {:?}",
+                deployment,
+                requested
+            );
+        }
+        assert!(Standard.allows_ip_geolocation() && Standard.allows_user_key());
+        assert!(!ClientGeoOnly.allows_ip_geolocation() && ClientGeoOnly.allows_user_key());
+        assert!(!ContextualOnly.allows_ip_geolocation() && !ContextualOnly.allows_user_key());
+    }
+}
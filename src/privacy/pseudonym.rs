@@ -0,0 +1,225 @@
+use crate::errors::ProxyError;
+use chrono::{DateTime, NaiveDate, Utc};
+use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
+use std::fmt::{self, Write};
+
+/// A secret used to derive user keys from the date it takes effect.
+#[derive(Clone)]
+struct Salt {
+    valid_from: NaiveDate,
+    secret: Vec<u8>,
+}
+
+impl fmt::Debug for Salt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Salt")
+            .field("valid_from", &self.valid_from)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Derives the user key sent to Kevel from a client's `pocket_id`, so Kevel
+/// never sees the client identifier.
+///
+/// The user key is the hex encoded HMAC-SHA256 of the `pocket_id`, keyed with
+/// the most recent salt that has taken effect. Salts are rotated by adding a
+/// new salt with a later date; older salts must be kept for as long as Kevel
+/// may hold users created with them, so deletion, export and opt-out reach
+/// every key a client may have.
+///
+/// Without salts, the `pocket_id` is used as the user key.
+#[derive(Clone, Debug, Default)]
+pub struct Pseudonymizer {
+    /// Salts, ordered by the date they take effect.
+    salts: Vec<Salt>,
+    /// Also act on the raw `pocket_id` for deletion, export and opt-out, for
+    /// users created before pseudonymization was enabled.
+    migration: bool,
+}
+
+impl Pseudonymizer {
+    /// Build a pseudonymizer from salts in the form `YYYY-MM-DD:secret`.
+    pub fn from_salts<S: AsRef<str>>(salts: &[S], migration: bool) -> Result<Self, ProxyError> {
+        let mut parsed = Vec::with_capacity(salts.len());
+        for salt in salts {
+            let salt = salt.as_ref();
+            let (date, secret) = salt
+                .split_once(':')
+                .filter(|(_, secret)| !secret.is_empty())
+                .ok_or_else(|| {
+                    ProxyError::new("User key salts must have the form 'YYYY-MM-DD:secret'")
+                })?;
+            let valid_from = NaiveDate::parse_from_str(date, "%Y-%m-%d").map_err(|err| {
+                ProxyError::new(format!("Invalid date in user key salt '{}': {}", date, err))
+            })?;
+            if parsed.iter().any(|s: &Salt| s.valid_from == valid_from) {
+                return Err(ProxyError::new(format!(
+                    "Duplicate user key salt for {}",
+                    valid_from
+                )));
+            }
+            parsed.push(Salt {
+                valid_from,
+                secret: secret.as_bytes().to_vec(),
+            });
+        }
+        parsed.sort_by_key(|salt| salt.valid_from);
+        Ok(Self {
+            salts: parsed,
+            migration,
+        })
+    }
+
+    /// Salts that have taken effect. If none have, the earliest salt is used,
+    /// so raw identifiers are never sent once salts are configured.
+    fn active_salts(&self, now: DateTime<Utc>) -> &[Salt] {
+        let today = now.naive_utc().date();
+        let active = self
+            .salts
+            .iter()
+            .take_while(|salt| salt.valid_from <= today)
+            .count();
+        &self.salts[..active.max(self.salts.len().min(1))]
+    }
+
+    /// The user key to send to Kevel in decision requests.
+    pub fn user_key(&self, pocket_id: &str, now: DateTime<Utc>) -> Result<String, ProxyError> {
+        match self.active_salts(now).last() {
+            Some(salt) => hmac_hex(&salt.secret, pocket_id),
+            None => Ok(pocket_id.to_owned()),
+        }
+    }
+
+    /// A user key that changes every UTC day, for clients whose user key must
+    /// not be linked across days. It is keyed with the active salt, so Kevel
+    /// can't derive it from the user key it already holds.
+    pub fn daily_key(&self, user_key: &str, now: DateTime<Utc>) -> Result<String, ProxyError> {
+        let salt = self
+            .active_salts(now)
+            .last()
+            .ok_or_else(|| ProxyError::new("Daily user keys require user key salts"))?;
+        hmac_hex(
+            &salt.secret,
+            &format!("{}:{}", now.format("%Y-%m-%d"), user_key),
+        )
+    }
+
+    /// Every user key a client may have in Kevel's UserDB, most recent first.
+    pub fn known_user_keys(
+        &self,
+        pocket_id: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<String>, ProxyError> {
+        let salts = self.active_salts(now);
+        let mut keys = Vec::with_capacity(salts.len() + 1);
+        for salt in salts.iter().rev() {
+            keys.push(hmac_hex(&salt.secret, pocket_id)?);
+        }
+        if keys.is_empty() || self.migration {
+            keys.push(pocket_id.to_owned());
+        }
+        Ok(keys)
+    }
+}
+
+fn hmac_hex(secret: &[u8], message: &str) -> Result<String, ProxyError> {
+    let key = PKey::hmac(secret)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(message.as_bytes())?;
+    let mut hex = String::with_capacity(64);
+    for byte in signer.sign_to_vec()? {
+        write!(hex, "{:02x}", byte).unwrap();
+    }
+    Ok(hex)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Pseudonymizer;
+    use chrono::{TimeZone, Utc};
+
+    const POCKET_ID: &str = "{670e8b97-c271-483f-bcb0-4921b58cdb52}";
+
+    #[test]
+    fn test_without_salts_uses_pocket_id() {
+        let pseudonymizer = Pseudonymizer::default();
+        let now = Utc.ymd(2022, 6, 1).and_hms(0, 0, 0);
+        assert_eq!(pseudonymizer.user_key(POCKET_ID, now).unwrap(), POCKET_ID);
+        assert_eq!(
+            pseudonymizer.known_user_keys(POCKET_ID, now).unwrap(),
+            vec![POCKET_ID]
+        );
+    }
+
+    #[test]
+    fn test_salt_rotation() {
+        let pseudonymizer =
+            Pseudonymizer::from_salts(&["2022-07-01:new-secret", "2022-01-01:old-secret"], false)
+                .unwrap();
+        let old_key = "d4a07c4582530218fd66903e34eda3df1b0a4a64eda2cff832d3148586c58ac5";
+        let new_key = pseudonymizer
+            .user_key(POCKET_ID, Utc.ymd(2022, 7, 1).and_hms(0, 0, 0))
+            .unwrap();
+
+        let before_rotation = Utc.ymd(2022, 6, 30).and_hms(23, 59, 59);
+        assert_eq!(
+            pseudonymizer.user_key(POCKET_ID, before_rotation).unwrap(),
+            old_key
+        );
+        assert_eq!(
+            pseudonymizer
+                .known_user_keys(POCKET_ID, before_rotation)
+                .unwrap(),
+            vec![old_key]
+        );
+
+        let after_rotation = Utc.ymd(2022, 7, 2).and_hms(0, 0, 0);
+        assert_ne!(new_key, old_key);
+        assert_eq!(new_key.len(), 64);
+        assert_eq!(
+            pseudonymizer.user_key(POCKET_ID, after_rotation).unwrap(),
+            new_key
+        );
+        assert_eq!(
+            pseudonymizer
+                .known_user_keys(POCKET_ID, after_rotation)
+                .unwrap(),
+            vec![new_key.as_str(), old_key]
+        );
+    }
+
+    #[test]
+    fn test_future_salt_is_used_before_it_takes_effect() {
+        let pseudonymizer = Pseudonymizer::from_salts(&["2030-01-01:secret"], false).unwrap();
+        let now = Utc.ymd(2022, 6, 1).and_hms(0, 0, 0);
+        assert_ne!(pseudonymizer.user_key(POCKET_ID, now).unwrap(), POCKET_ID);
+    }
+
+    #[test]
+    fn test_migration_includes_pocket_id() {
+        let pseudonymizer = Pseudonymizer::from_salts(&["2022-01-01:secret"], true).unwrap();
+        let now = Utc.ymd(2022, 6, 1).and_hms(0, 0, 0);
+        let keys = pseudonymizer.known_user_keys(POCKET_ID, now).unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0], pseudonymizer.user_key(POCKET_ID, now).unwrap());
+        assert_eq!(keys[1], POCKET_ID);
+    }
+
+    #[test]
+    fn test_invalid_salts() {
+        let invalid_salts = [
+            "secret",
+            "2022-01-01:",
+            "01/01/2022:secret",
+            "2022-01-01:a,2022-01-01:b",
+        ];
+        for salts in invalid_salts {
+            let salts: Vec<&str> = salts.split(',').collect();
+            assert!(
+                Pseudonymizer::from_salts(&salts, false).is_err(),
+                "{:?} should be rejected",
+                salts
+            );
+        }
+    }
+}
@@ -0,0 +1,202 @@
+//! Configuration that can be replaced while the server is running.
+
+use crate::errors::ProxyError;
+use cadence::{prelude::*, StatsdClient};
+use std::{
+    fmt::{self, Write},
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+type Loader<T> = Box<dyn Fn(&Path) -> Result<T, ProxyError> + Send + Sync>;
+
+/// A value loaded from a file, which is loaded again when the file changes.
+///
+/// Readers get a snapshot of the current value, so a reload never affects a
+/// request in flight. If loading a changed file fails, the previous value is
+/// kept and the error is logged.
+pub struct Reloadable<T> {
+    name: &'static str,
+    current: RwLock<Arc<T>>,
+    source: Option<Source<T>>,
+}
+
+struct Source<T> {
+    path: PathBuf,
+    loader: Loader<T>,
+    modified: RwLock<Option<SystemTime>>,
+}
+
+impl<T> fmt::Debug for Reloadable<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Reloadable")
+            .field("name", &self.name)
+            .field("path", &self.source.as_ref().map(|s| &s.path))
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T: Send + Sync + 'static> Reloadable<T> {
+    /// A value that never changes.
+    pub fn fixed(name: &'static str, value: T) -> Self {
+        Self {
+            name,
+            current: RwLock::new(Arc::new(value)),
+            source: None,
+        }
+    }
+
+    /// Load a value from a file. Failing to load it here is an error, so
+    /// invalid configuration is caught at startup.
+    pub fn from_file<P, F>(name: &'static str, path: P, loader: F) -> Result<Self, ProxyError>
+    where
+        P: Into<PathBuf>,
+        F: Fn(&Path) -> Result<T, ProxyError> + Send + Sync + 'static,
+    {
+        let path = path.into();
+        let modified = modified(&path);
+        let value = loader(&path)?;
+        Ok(Self {
+            name,
+            current: RwLock::new(Arc::new(value)),
+            source: Some(Source {
+                path,
+                loader: Box::new(loader),
+                modified: RwLock::new(modified),
+            }),
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// A snapshot of the current value.
+    pub fn get(&self) -> Arc<T> {
+        Arc::clone(&self.current.read().unwrap())
+    }
+
+    /// Replace the value, e.g. with one fetched from elsewhere.
+    pub fn set(&self, value: T) {
+        *self.current.write().unwrap() = Arc::new(value);
+    }
+
+    /// Load the file again if it changed since it was last loaded. Returns
+    /// whether the value was replaced.
+    pub fn reload_if_changed(&self) -> Result<bool, ProxyError> {
+        let source = match &self.source {
+            Some(source) => source,
+            None => return Ok(false),
+        };
+        let modified = modified(&source.path);
+        if *source.modified.read().unwrap() == modified {
+            return Ok(false);
+        }
+        // Record the change first, so a broken file is reported only once.
+        *source.modified.write().unwrap() = modified;
+        self.set((source.loader)(&source.path)?);
+        Ok(true)
+    }
+
+    /// Check the file for changes periodically, in the background.
+    pub fn watch(
+        self: &Arc<Self>,
+        interval: Duration,
+        log: slog::Logger,
+        metrics: Arc<StatsdClient>,
+    ) {
+        if self.source.is_none() {
+            return;
+        }
+        let reloadable = Arc::clone(self);
+        actix_web::rt::spawn(async move {
+            let mut ticks = actix_web::rt::time::interval(interval);
+            loop {
+                ticks.tick().await;
+                let result = match reloadable.reload_if_changed() {
+                    Ok(false) => continue,
+                    Ok(true) => {
+                        slog::info!(log, "reloaded {}", reloadable.name);
+                        "success"
+                    }
+                    Err(err) => {
+                        slog::error!(log, "failed to reload {}: {}", reloadable.name, err);
+                        "error"
+                    }
+                };
+                metrics
+                    .incr_with_tags("config.reload")
+                    .with_tag("config", reloadable.name)
+                    .with_tag("result", result)
+                    .send();
+            }
+        });
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Hex encoded SHA-256 of configuration contents, to tell which version of a
+/// file is active.
+pub fn content_hash(contents: &[u8]) -> String {
+    let mut hex = String::with_capacity(64);
+    for byte in openssl::sha::sha256(contents) {
+        write!(hex, "{:02x}", byte).unwrap();
+    }
+    hex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{content_hash, Reloadable};
+    use crate::errors::ProxyError;
+    use std::{fs, path::Path, time::Duration};
+
+    fn load(path: &Path) -> Result<u32, ProxyError> {
+        fs::read_to_string(path)?
+            .trim()
+            .parse()
+            .map_err(|_| ProxyError::new("not a number"))
+    }
+
+    #[test]
+    fn test_reload_if_changed() {
+        let path = std::env::temp_dir().join(format!("reload-test-{}", std::process::id()));
+        fs::write(&path, "1").unwrap();
+        let reloadable = Reloadable::from_file("test", &path, load).unwrap();
+        assert_eq!(*reloadable.get(), 1);
+        assert!(!reloadable.reload_if_changed().unwrap());
+
+        // Make sure the modification time changes on coarse file systems.
+        std::thread::sleep(Duration::from_millis(50));
+        fs::write(&path, "2").unwrap();
+        let snapshot = reloadable.get();
+        assert!(reloadable.reload_if_changed().unwrap());
+        assert_eq!(*reloadable.get(), 2);
+        assert_eq!(*snapshot, 1, "snapshots are not affected by reloads");
+
+        std::thread::sleep(Duration::from_millis(50));
+        fs::write(&path, "oops").unwrap();
+        assert!(reloadable.reload_if_changed().is_err());
+        assert_eq!(*reloadable.get(), 2, "the previous value is kept");
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_content_hash() {
+        assert_eq!(
+            content_hash(b"{}"),
+            "44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a"
+        );
+    }
+
+    #[test]
+    fn test_invalid_file_fails_at_startup() {
+        assert!(Reloadable::from_file("test", "/nonexistent/file", load).is_err());
+    }
+}
@@ -0,0 +1,429 @@
+//! Configuration fetched from a remote HTTP source.
+
+use crate::{
+    errors::ProxyError,
+    reload::{content_hash, Reloadable},
+};
+use awc::Client;
+use cadence::{prelude::*, StatsdClient};
+use openssl::{
+    hash::MessageDigest,
+    pkey::{PKey, Public},
+    sign::Verifier,
+};
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// Largest configuration file that is fetched.
+const MAX_SIZE: usize = 4 * 1024 * 1024;
+
+type Parser<T> = Box<dyn Fn(&str) -> Result<T, ProxyError> + Send + Sync>;
+
+/// A configuration file, and the value it replaces.
+trait Entry {
+    fn file(&self) -> &'static str;
+    fn name(&self) -> &'static str;
+    fn apply(&self, json: &str) -> Result<(), ProxyError>;
+}
+
+struct ConfigEntry<T> {
+    file: &'static str,
+    target: Arc<Reloadable<T>>,
+    parse: Parser<T>,
+}
+
+impl<T: Send + Sync + 'static> Entry for ConfigEntry<T> {
+    fn file(&self) -> &'static str {
+        self.file
+    }
+
+    fn name(&self) -> &'static str {
+        self.target.name()
+    }
+
+    fn apply(&self, json: &str) -> Result<(), ProxyError> {
+        self.target.set((self.parse)(json)?);
+        Ok(())
+    }
+}
+
+/// Fetches configuration files from `{base_url}/{file}`, along with their
+/// signature from `{base_url}/{file}.sig`.
+///
+/// Files start with a line holding their version, a positive integer, followed
+/// by the configuration. Signatures are the base64 encoded SHA-256 signature
+/// of the whole file, by the private key matching the pinned public key. Files
+/// are only used if their signature is valid, they are newer than the version
+/// in use, and they pass validation, so old files can't be replayed. The last
+/// good copy of each file is cached on disk, and used at startup until a fetch
+/// succeeds. Without one, the current value, usually compiled in, is kept.
+pub struct RemoteConfig {
+    http_client: Client,
+    base_url: String,
+    public_key: PKey<Public>,
+    cache_dir: PathBuf,
+    entries: Vec<Box<dyn Entry>>,
+    /// The file applied last, by file, so a file is only applied once, and
+    /// older versions are rejected.
+    applied: Mutex<HashMap<&'static str, Applied>>,
+}
+
+struct Applied {
+    version: u64,
+    hash: String,
+}
+
+impl RemoteConfig {
+    /// A source without files. `public_key` is a PEM encoded RSA or EC key.
+    pub fn new(
+        base_url: String,
+        public_key: &[u8],
+        cache_dir: PathBuf,
+    ) -> Result<Self, ProxyError> {
+        Ok(Self {
+            http_client: Client::builder().timeout(Duration::from_secs(10)).finish(),
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            public_key: PKey::public_key_from_pem(public_key)?,
+            cache_dir,
+            entries: Vec::new(),
+            applied: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Replace the value of `target` with the file `file`.
+    pub fn with<T, F>(mut self, file: &'static str, target: Arc<Reloadable<T>>, parse: F) -> Self
+    where
+        T: Send + Sync + 'static,
+        F: Fn(&str) -> Result<T, ProxyError> + Send + Sync + 'static,
+    {
+        self.entries.push(Box::new(ConfigEntry {
+            file,
+            target,
+            parse: Box::new(parse),
+        }));
+        self
+    }
+
+    /// Apply the cached copies of the files. Files without a cached copy are
+    /// left out of the results.
+    pub fn load_cache(&self) -> Vec<(&'static str, Result<(), ProxyError>)> {
+        self.entries
+            .iter()
+            .filter(|entry| self.cache_path(entry.file()).exists())
+            .map(|entry| {
+                let result = (|| {
+                    let body = fs::read(self.cache_path(entry.file()))?;
+                    let signature = fs::read(self.cache_path(&signature_file(entry.file())))?;
+                    self.apply(entry.as_ref(), body, &signature).map(|_| ())
+                })();
+                (entry.name(), result)
+            })
+            .collect()
+    }
+
+    /// Fetch the files, and apply those that changed. Returns whether each
+    /// file changed.
+    pub async fn refresh(&self) -> Vec<(&'static str, Result<bool, ProxyError>)> {
+        let mut results = Vec::with_capacity(self.entries.len());
+        for entry in &self.entries {
+            results.push((entry.name(), self.refresh_entry(entry.as_ref()).await));
+        }
+        results
+    }
+
+    /// Apply the cached copies, then fetch the files periodically, in the
+    /// background.
+    pub fn start(self, interval: Duration, log: slog::Logger, metrics: Arc<StatsdClient>) {
+        for (name, result) in self.load_cache() {
+            match result {
+                Ok(()) => slog::info!(log, "loaded {} from the remote config cache", name),
+                Err(err) => slog::error!(log, "failed to load cached {}: {}", name, err),
+            }
+        }
+        actix_web::rt::spawn(async move {
+            let mut ticks = actix_web::rt::time::interval(interval);
+            loop {
+                ticks.tick().await;
+                for (name, result) in self.refresh().await {
+                    let result = match result {
+                        Ok(false) => continue,
+                        Ok(true) => {
+                            slog::info!(log, "fetched {}", name);
+                            "success"
+                        }
+                        Err(err) => {
+                            slog::error!(log, "failed to fetch {}: {}", name, err);
+                            "error"
+                        }
+                    };
+                    metrics
+                        .incr_with_tags("config.remote")
+                        .with_tag("config", name)
+                        .with_tag("result", result)
+                        .send();
+                }
+            }
+        });
+    }
+
+    async fn refresh_entry(&self, entry: &dyn Entry) -> Result<bool, ProxyError> {
+        let body = self.fetch(entry.file()).await?;
+        let signature = self.fetch(&signature_file(entry.file())).await?;
+        let changed = self.apply(entry, body.clone(), &signature)?;
+        if changed {
+            self.write_cache(entry.file(), &body)?;
+            self.write_cache(&signature_file(entry.file()), &signature)?;
+        }
+        Ok(changed)
+    }
+
+    async fn fetch(&self, file: &str) -> Result<Vec<u8>, ProxyError> {
+        let mut response = self
+            .http_client
+            .get(format!("{}/{}", self.base_url, file))
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(ProxyError::new(format!(
+                "Fetching {} failed with status {}",
+                file,
+                response.status()
+            )));
+        }
+        Ok(response.body().limit(MAX_SIZE).await?.to_vec())
+    }
+
+    /// Verify and apply a file, unless it was applied already. Returns
+    /// whether it was applied.
+    fn apply(
+        &self,
+        entry: &dyn Entry,
+        body: Vec<u8>,
+        signature: &[u8],
+    ) -> Result<bool, ProxyError> {
+        self.verify(&body, signature)?;
+        let hash = content_hash(&body);
+        let mut applied = self.applied.lock().unwrap();
+        let current = applied.get(entry.file());
+        if current.map_or(false, |current| current.hash == hash) {
+            return Ok(false);
+        }
+        let invalid = |reason: &str| ProxyError::new(format!("{} {}", entry.file(), reason));
+        let body = String::from_utf8(body).map_err(|_| invalid("is not valid UTF-8"))?;
+        let (version, json) = body
+            .split_once('\n')
+            .and_then(|(version, json)| Some((version.trim().parse::<u64>().ok()?, json)))
+            .filter(|&(version, _)| version > 0)
+            .ok_or_else(|| invalid("must start with a line holding its version"))?;
+        if let Some(current) = current.filter(|current| version <= current.version) {
+            return Err(invalid(&format!(
+                "version {} is not newer than version {}",
+                version, current.version
+            )));
+        }
+        entry.apply(json)?;
+        applied.insert(entry.file(), Applied { version, hash });
+        Ok(true)
+    }
+
+    fn verify(&self, body: &[u8], signature: &[u8]) -> Result<(), ProxyError> {
+        let signature = openssl::base64::decode_block(String::from_utf8_lossy(signature).trim())?;
+        let mut verifier = Verifier::new(MessageDigest::sha256(), &self.public_key)?;
+        verifier.update(body)?;
+        if verifier.verify(&signature)? {
+            Ok(())
+        } else {
+            Err(ProxyError::new("Invalid signature"))
+        }
+    }
+
+    fn cache_path(&self, file: &str) -> PathBuf {
+        self.cache_dir.join(file)
+    }
+
+    /// Write a file to the cache, replacing the previous copy atomically.
+    fn write_cache(&self, file: &str, contents: &[u8]) -> Result<(), ProxyError> {
+        fs::create_dir_all(&self.cache_dir)?;
+        let path = self.cache_path(file);
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, contents)?;
+        fs::rename(&temporary, &path)?;
+        Ok(())
+    }
+}
+
+fn signature_file(file: &str) -> String {
+    format!("{}.sig", file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RemoteConfig;
+    use crate::{errors::ProxyError, reload::Reloadable};
+    use openssl::{
+        ec::{EcGroup, EcKey},
+        hash::MessageDigest,
+        nid::Nid,
+        pkey::{PKey, Private},
+        sign::Signer,
+    };
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+        sync::Arc,
+    };
+    use wiremock::{
+        matchers::{method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    fn key() -> PKey<Private> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    /// A file with a version line.
+    fn file(version: u64, json: &str) -> String {
+        format!("{}\n{}", version, json)
+    }
+
+    fn sign(key: &PKey<Private>, body: &str) -> String {
+        let mut signer = Signer::new(MessageDigest::sha256(), key).unwrap();
+        signer.update(body.as_bytes()).unwrap();
+        openssl::base64::encode_block(&signer.sign_to_vec().unwrap())
+    }
+
+    fn parse(json: &str) -> Result<u32, ProxyError> {
+        json.trim()
+            .parse()
+            .map_err(|_| ProxyError::new("not a number"))
+    }
+
+    fn cache_dir(test: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("remote-config-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    async fn serve(server: &MockServer, body: &str, signature: &str) {
+        server.reset().await;
+        Mock::given(method("GET"))
+            .and(path("/number.json"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(body))
+            .mount(server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/number.json.sig"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(signature))
+            .mount(server)
+            .await;
+    }
+
+    fn remote(
+        base_url: String,
+        key: &PKey<Private>,
+        cache_dir: &Path,
+        target: &Arc<Reloadable<u32>>,
+    ) -> RemoteConfig {
+        RemoteConfig::new(
+            base_url,
+            &key.public_key_to_pem().unwrap(),
+            cache_dir.to_owned(),
+        )
+        .unwrap()
+        .with("number.json", Arc::clone(target), parse)
+    }
+
+    #[actix_rt::test]
+    async fn test_fetch_and_cache() {
+        let server = MockServer::start().await;
+        let key = key();
+        let cache_dir = cache_dir("fetch");
+        let target = Arc::new(Reloadable::fixed("number", 1));
+        let remote = remote(server.uri(), &key, &cache_dir, &target);
+
+        let body = file(1, "2");
+        serve(&server, &body, &sign(&key, &body)).await;
+        let results = remote.refresh().await;
+        assert!(matches!(results[..], [("number", Ok(true))]));
+        assert_eq!(*target.get(), 2);
+        let results = remote.refresh().await;
+        assert!(matches!(results[..], [("number", Ok(false))]), "unchanged");
+
+        // Without the remote source, the cached copy is used.
+        let target = Arc::new(Reloadable::fixed("number", 1));
+        let offline = self::remote("http://0.0.0.0:1".to_owned(), &key, &cache_dir, &target);
+        let results = offline.load_cache();
+        assert!(matches!(results[..], [("number", Ok(()))]));
+        assert_eq!(*target.get(), 2);
+        assert!(offline.refresh().await[0].1.is_err());
+        assert_eq!(*target.get(), 2);
+
+        fs::remove_dir_all(&cache_dir).unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn test_invalid_files_are_rejected() {
+        let server = MockServer::start().await;
+        let key = key();
+        let cache_dir = cache_dir("invalid");
+        let target = Arc::new(Reloadable::fixed("number", 1));
+        let remote = remote(server.uri(), &key, &cache_dir, &target);
+
+        let test_cases = [
+            // Signed with another key.
+            (file(1, "2"), sign(&self::key(), &file(1, "2"))),
+            (file(1, "2"), sign(&key, &file(1, "3"))),
+            (file(1, "2"), "not base64".to_owned()),
+            (file(1, "two"), sign(&key, &file(1, "two"))),
+            ("2".to_owned(), sign(&key, "2")),
+            (file(0, "2"), sign(&key, &file(0, "2"))),
+        ];
+        for (body, signature) in test_cases {
+            serve(&server, &body, &signature).await;
+            for _ in 0..2 {
+                assert!(
+                    remote.refresh().await[0].1.is_err(),
+                    "{:?} should be rejected every time",
+                    body
+                );
+            }
+            assert_eq!(*target.get(), 1, "the previous value is kept");
+        }
+        assert!(!cache_dir.exists(), "invalid files are not cached");
+        assert!(remote.load_cache().is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_old_versions_are_rejected() {
+        let server = MockServer::start().await;
+        let key = key();
+        let cache_dir = cache_dir("versions");
+        let target = Arc::new(Reloadable::fixed("number", 1));
+        let remote = remote(server.uri(), &key, &cache_dir, &target);
+
+        let old = file(1, "2");
+        serve(&server, &old, &sign(&key, &old)).await;
+        assert!(matches!(remote.refresh().await[..], [("number", Ok(true))]));
+        let new = file(2, "3");
+        serve(&server, &new, &sign(&key, &new)).await;
+        assert!(matches!(remote.refresh().await[..], [("number", Ok(true))]));
+        assert_eq!(*target.get(), 3);
+
+        // Replaying an older file, or changing a file without a new version,
+        // is rejected.
+        for body in [old, file(2, "4")] {
+            serve(&server, &body, &sign(&key, &body)).await;
+            assert!(remote.refresh().await[0].1.is_err());
+            assert_eq!(*target.get(), 3);
+        }
+
+        fs::remove_dir_all(&cache_dir).unwrap();
+    }
+}
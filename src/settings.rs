@@ -1,4 +1,12 @@
-use crate::errors::ProxyError;
+use crate::{
+    adzerk::{personalization, ranking::RankerName},
+    errors::ProxyError,
+    privacy::{
+        consent::{default_gdpr_countries, UserKeyPolicy},
+        mode::PrivacyMode,
+    },
+    utils::ForwardingHeader,
+};
 use serde_derive::{Deserialize, Serialize};
 use std::path::PathBuf;
 
@@ -22,10 +30,22 @@ fn default_metrics_target() -> String {
     "localhost:8125".to_owned()
 }
 
+fn default_geo_override_header() -> String {
+    "X-Geo-Override".to_owned()
+}
+
 fn default_adzerk_api_key() -> String {
     "test".to_owned()
 }
 
+fn default_config_reload_interval() -> u64 {
+    60
+}
+
+fn default_remote_config_cache_dir() -> PathBuf {
+    "./remote-config".into()
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Settings {
     #[serde(default)]
@@ -34,6 +54,15 @@ pub struct Settings {
     #[serde(default = "default_geoip_db_path")]
     pub geoip_db_path: PathBuf,
 
+    /// Optional path to a MaxMind ASN database. If unset, no ASN is reported
+    /// for client locations.
+    pub geoip_asn_db_path: Option<PathBuf>,
+
+    /// Optional path to a JSON or CSV file mapping networks to locations. If
+    /// set, it is used instead of the MaxMind databases. Meant for local
+    /// development.
+    pub geoip_static_path: Option<PathBuf>,
+
     #[serde(default = "default_host")]
     pub host: String,
 
@@ -43,6 +72,28 @@ pub struct Settings {
     #[serde(default)]
     pub trusted_proxy_list: Vec<ipnet::IpNet>,
 
+    /// Trust exactly this many hops closest to the server, instead of using
+    /// `trusted_proxy_list`.
+    pub trusted_proxy_hops: Option<usize>,
+
+    /// Forwarding headers to consult for the client IP, in order of
+    /// precedence. Only the first header present in a request is used.
+    #[serde(default = "ForwardingHeader::default_precedence")]
+    pub forwarding_headers: Vec<ForwardingHeader>,
+
+    /// Header in which a trusted proxy (e.g. the CDN) passes the client's
+    /// country code. Ignored for requests that didn't come through a trusted
+    /// proxy.
+    pub edge_country_header: Option<String>,
+
+    /// Header in which a trusted proxy passes the client's region code.
+    pub edge_region_header: Option<String>,
+
+    /// Header that lets testers simulate a location, e.g. `US-CA`. Only
+    /// honored in debug mode.
+    #[serde(default = "default_geo_override_header")]
+    pub geo_override_header: String,
+
     #[serde(default)]
     pub human_logs: bool,
 
@@ -59,6 +110,110 @@ pub struct Settings {
 
     #[serde(default = "default_adzerk_api_key")]
     pub adzerk_api_key: String,
+
+    /// Path to a JSON file with the templates used to generate Kevel keywords.
+    /// If unset, the compiled-in templates are used.
+    pub keyword_templates_path: Option<PathBuf>,
+
+    /// Path to a JSON file mapping IAB Content Taxonomy IDs to Kevel keywords.
+    /// If unset, the compiled-in taxonomy is used.
+    pub topic_taxonomy_path: Option<PathBuf>,
+
+    /// Path to a JSON file with rules that deduplicate spocs across divs, and
+    /// limit the spocs per sponsor or domain. If unset, no spocs are removed.
+    pub diversity_rules_path: Option<PathBuf>,
+
+    /// How spocs are ordered within each div. One of "kevel", to keep the order
+    /// of Kevel's decisions, or "priority".
+    #[serde(default = "RankerName::default_ranker")]
+    pub ranker: RankerName,
+
+    /// Path to a JSON file mapping Kevel priority IDs to priority tiers, per
+    /// Kevel network. If unset, the compiled-in mapping is used. The file is
+    /// reloaded when it changes.
+    pub priority_map_path: Option<PathBuf>,
+
+    /// Path to a JSON file with settings for the spocs of each div, such as
+    /// their frequency caps. If unset, all divs use the defaults.
+    pub placements_path: Option<PathBuf>,
+
+    /// Path to a JSON file with the settings sent to clients with spocs. If
+    /// unset, the compiled-in settings are used. The file is reloaded when it
+    /// changes.
+    pub client_settings_path: Option<PathBuf>,
+
+    /// Path to a JSON file with overlays of the client settings for clients
+    /// by country, locale, version or placement. If unset, all clients get
+    /// the same settings. The file is reloaded when it changes.
+    pub settings_overlays_path: Option<PathBuf>,
+
+    /// Path to a JSON file with the domain affinity sets that creatives refer
+    /// to. If unset, the compiled-in sets are used. The file is reloaded when
+    /// it changes.
+    pub domain_affinities_path: Option<PathBuf>,
+
+    /// Path to a JSON file with server-side experiments. If unset, there are
+    /// no experiments. The file is reloaded when it changes.
+    pub experiments_path: Option<PathBuf>,
+
+    /// Base URL of a remote source of the client settings, settings overlays,
+    /// domain affinities, priority map, placements and experiments, which
+    /// replaces local files. Files are fetched every `config_reload_interval`
+    /// seconds.
+    pub remote_config_url: Option<String>,
+
+    /// Path to the PEM encoded public key that signs remote configuration.
+    pub remote_config_public_key_path: Option<PathBuf>,
+
+    /// Directory in which the last good copy of remote configuration files is
+    /// kept.
+    #[serde(default = "default_remote_config_cache_dir")]
+    pub remote_config_cache_dir: PathBuf,
+
+    /// Prefixes of the keys in creative bodies that are personalization
+    /// models. Models with the first prefix are sent to clients without it.
+    #[serde(default = "personalization::default_prefixes")]
+    pub personalization_model_prefixes: Vec<String>,
+
+    /// How often to check reloadable configuration files for changes, in
+    /// seconds.
+    #[serde(default = "default_config_reload_interval")]
+    pub config_reload_interval: u64,
+
+    /// Path to a JSON file restricting the markets and regions that get
+    /// sponsored content. If unset, all locations get sponsored content.
+    pub geo_policy_path: Option<PathBuf>,
+
+    /// Countries in which clients are assumed not to consent to the use of
+    /// personal data unless they say so. Defaults to the EEA and the UK.
+    #[serde(default = "default_gdpr_countries")]
+    pub gdpr_countries: Vec<String>,
+
+    /// What to do with the user key of clients that haven't consented. One of
+    /// "keep", "drop" or "rotate".
+    #[serde(default = "UserKeyPolicy::default_policy")]
+    pub gdpr_user_key_policy: UserKeyPolicy,
+
+    /// Secrets used to pseudonymize the user keys sent to Kevel, in the form
+    /// `YYYY-MM-DD:secret`, where the date is when the secret takes effect. If
+    /// unset, the client's `pocket_id` is sent as is.
+    #[serde(default)]
+    pub user_key_salts: Vec<String>,
+
+    /// Also delete, export and opt out the raw `pocket_id` in Kevel's UserDB,
+    /// for users created before pseudonymization was enabled.
+    #[serde(default)]
+    pub user_key_migration: bool,
+
+    /// Privacy mode for all requests. Requests may ask for a more restrictive
+    /// mode. One of "standard", "client-geo-only" or "contextual-only".
+    #[serde(default = "PrivacyMode::default_mode")]
+    pub privacy_mode: PrivacyMode,
+
+    /// Minimum Firefox major version that gets sponsored collections. Older
+    /// clients receive collection items as a standard list. If unset, all
+    /// clients using version 2 of the API get collections.
+    pub collections_min_client_version: Option<u32>,
 }
 
 impl Default for Settings {
@@ -67,7 +222,7 @@ impl Default for Settings {
         // then asking envy to deserialize it. Since all settings have a default
         // value specified in the struct, this works and keeps everything in sync.
         let empty_env: Vec<(String, String)> = Vec::new();
-        envy::from_iter(empty_env.into_iter()).unwrap()
+        envy::from_iter(empty_env).unwrap()
     }
 }
 
@@ -75,15 +230,54 @@ impl Settings {
     /// Load settings from the environment.
     pub fn load() -> Result<Self, ProxyError> {
         let settings: Self = envy::from_env()?;
-        Ok(settings)
+        settings.validate()
+    }
+
+    fn validate(self) -> Result<Self, ProxyError> {
+        if self.config_reload_interval == 0 {
+            return Err(ProxyError::new(
+                "CONFIG_RELOAD_INTERVAL must be at least one second",
+            ));
+        }
+        if self.gdpr_user_key_policy == UserKeyPolicy::Rotate && self.user_key_salts.is_empty() {
+            return Err(ProxyError::new(
+                "USER_KEY_SALTS is required with GDPR_USER_KEY_POLICY=rotate",
+            ));
+        }
+        if self.remote_config_url.is_some() {
+            if self.remote_config_public_key_path.is_none() {
+                return Err(ProxyError::new(
+                    "REMOTE_CONFIG_PUBLIC_KEY_PATH is required with REMOTE_CONFIG_URL",
+                ));
+            }
+            let local_paths = [
+                &self.client_settings_path,
+                &self.settings_overlays_path,
+                &self.domain_affinities_path,
+                &self.experiments_path,
+                &self.priority_map_path,
+                &self.placements_path,
+            ];
+            if local_paths.iter().any(|path| path.is_some()) {
+                return Err(ProxyError::new(
+                    "REMOTE_CONFIG_URL can't be combined with local paths to the files it provides",
+                ));
+            }
+        }
+        Ok(self)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::env;
+    use std::{env, path::PathBuf};
 
-    use crate::settings::Settings;
+    use crate::{
+        adzerk::ranking::RankerName,
+        privacy::{consent::UserKeyPolicy, mode::PrivacyMode},
+        settings::Settings,
+        utils::ForwardingHeader,
+    };
 
     #[test]
     fn test_default_settings() {
@@ -91,13 +285,48 @@ mod tests {
 
         assert!(!settings.debug);
         assert_eq!(settings.geoip_db_path.to_str(), Some("./GeoIP2-City.mmdb"));
+        assert_eq!(settings.geoip_asn_db_path, None);
+        assert_eq!(settings.geoip_static_path, None);
         assert_eq!(settings.host, "[::]");
         assert_eq!(settings.port, 8000);
         assert_eq!(settings.trusted_proxy_list, Vec::new());
+        assert_eq!(settings.trusted_proxy_hops, None);
+        assert_eq!(
+            settings.forwarding_headers,
+            [ForwardingHeader::XForwardedFor, ForwardingHeader::Forwarded]
+        );
+        assert_eq!(settings.edge_country_header, None);
+        assert_eq!(settings.edge_region_header, None);
+        assert_eq!(settings.geo_override_header, "X-Geo-Override");
         assert!(!settings.human_logs);
         assert_eq!(settings.version_file.to_str(), Some("./version.json"));
         assert_eq!(settings.sentry_dsn, None);
         assert_eq!(settings.metrics_target, "localhost:8125");
+        assert_eq!(settings.keyword_templates_path, None);
+        assert_eq!(settings.topic_taxonomy_path, None);
+        assert_eq!(settings.diversity_rules_path, None);
+        assert_eq!(settings.ranker, RankerName::Kevel);
+        assert_eq!(settings.priority_map_path, None);
+        assert_eq!(settings.placements_path, None);
+        assert_eq!(settings.client_settings_path, None);
+        assert_eq!(settings.settings_overlays_path, None);
+        assert_eq!(settings.domain_affinities_path, None);
+        assert_eq!(settings.experiments_path, None);
+        assert_eq!(settings.remote_config_url, None);
+        assert_eq!(settings.remote_config_public_key_path, None);
+        assert_eq!(
+            settings.remote_config_cache_dir,
+            PathBuf::from("./remote-config")
+        );
+        assert_eq!(settings.personalization_model_prefixes, vec!["topic_"]);
+        assert_eq!(settings.config_reload_interval, 60);
+        assert_eq!(settings.geo_policy_path, None);
+        assert_eq!(settings.gdpr_countries.len(), 31);
+        assert_eq!(settings.gdpr_user_key_policy, UserKeyPolicy::Drop);
+        assert!(settings.user_key_salts.is_empty());
+        assert!(!settings.user_key_migration);
+        assert_eq!(settings.privacy_mode, PrivacyMode::Standard);
+        assert_eq!(settings.collections_min_client_version, None);
     }
 
     #[test]
@@ -105,11 +334,70 @@ mod tests {
         env::set_var("DEBUG", "true");
         env::set_var("PORT", "8888");
         env::set_var("TRUSTED_PROXY_LIST", "2001:db8::/48,192.168.100.14/24");
+        env::set_var("FORWARDING_HEADERS", "forwarded");
 
         let settings = Settings::load().unwrap();
 
         assert!(settings.debug);
         assert_eq!(settings.port, 8888);
         assert_eq!(settings.trusted_proxy_list.len(), 2);
+        assert_eq!(settings.forwarding_headers, [ForwardingHeader::Forwarded]);
+    }
+
+    #[test]
+    fn test_remote_config_validation() {
+        let remote = Settings {
+            remote_config_url: Some("https://config.example.com".to_owned()),
+            remote_config_public_key_path: Some("./key.pem".into()),
+            ..Settings::default()
+        };
+        let test_cases = [
+            (
+                Settings {
+                    remote_config_public_key_path: None,
+                    ..remote.clone()
+                },
+                false,
+            ),
+            (
+                Settings {
+                    priority_map_path: Some("./priorities.json".into()),
+                    ..remote.clone()
+                },
+                false,
+            ),
+            (remote, true),
+        ];
+        for (settings, valid) in test_cases {
+            assert_eq!(settings.validate().is_ok(), valid);
+        }
+    }
+
+    #[test]
+    fn test_reload_interval_validation() {
+        let settings = Settings {
+            config_reload_interval: 0,
+            ..Settings::default()
+        };
+        assert!(settings.validate().is_err());
+        let settings = Settings {
+            config_reload_interval: 1,
+            ..Settings::default()
+        };
+        assert!(settings.validate().is_ok());
+    }
+
+    #[test]
+    fn test_rotate_requires_salts() {
+        let rotate = Settings {
+            gdpr_user_key_policy: UserKeyPolicy::Rotate,
+            ..Settings::default()
+        };
+        assert!(rotate.clone().validate().is_err());
+        let salted = Settings {
+            user_key_salts: vec!["2022-01-01:secret".to_owned()],
+            ..rotate
+        };
+        assert!(salted.validate().is_ok());
     }
 }
@@ -0,0 +1,149 @@
+//! Parsing of client attributes from request headers.
+
+/// Determine the client's preferred locale from an `Accept-Language` header.
+///
+/// The language range with the highest quality value wins, with ties going to
+/// the first one listed. The result is normalized to the form `ll`, `ll-RR` or
+/// `ll-Ssss-RR` (language, script, region). Variants and extensions are
+/// dropped. Wildcards and malformed entries are ignored.
+pub fn parse_locale(accept_language: &str) -> Option<String> {
+    let mut best: Option<(f32, String)> = None;
+    for entry in accept_language.split(',') {
+        let mut parts = entry.split(';');
+        let tag = parts.next().unwrap_or_default().trim();
+        let quality = match parse_quality(parts) {
+            Some(quality) if quality > 0.0 => quality,
+            _ => continue,
+        };
+        let locale = match normalize_language_tag(tag) {
+            Some(locale) => locale,
+            None => continue,
+        };
+        if best.as_ref().map_or(true, |(q, _)| quality > *q) {
+            best = Some((quality, locale));
+        }
+    }
+    best.map(|(_, locale)| locale)
+}
+
+/// Extract the quality value from the parameters of an `Accept-Language`
+/// entry. Entries without a `q` parameter have quality 1.
+fn parse_quality<'a>(params: impl Iterator<Item = &'a str>) -> Option<f32> {
+    let mut quality = 1.0;
+    for param in params {
+        let (name, value) = param.split_once('=')?;
+        if name.trim().eq_ignore_ascii_case("q") {
+            quality = value.trim().parse().ok()?;
+            if !(0.0..=1.0).contains(&quality) {
+                return None;
+            }
+        }
+    }
+    Some(quality)
+}
+
+fn normalize_language_tag(tag: &str) -> Option<String> {
+    let mut subtags = tag.split(['-', '_']);
+    let language = subtags.next()?;
+    if !(2..=3).contains(&language.len()) || !language.bytes().all(|b| b.is_ascii_alphabetic()) {
+        return None;
+    }
+    let mut locale = language.to_ascii_lowercase();
+    let mut has_script = false;
+    for subtag in subtags {
+        if !subtag.bytes().all(|b| b.is_ascii_alphanumeric()) {
+            return None;
+        }
+        match subtag.len() {
+            4 if !has_script && subtag.bytes().all(|b| b.is_ascii_alphabetic()) => {
+                has_script = true;
+                locale.push('-');
+                locale.push_str(&subtag[..1].to_ascii_uppercase());
+                locale.push_str(&subtag[1..].to_ascii_lowercase());
+            }
+            2 if subtag.bytes().all(|b| b.is_ascii_alphabetic()) => {
+                locale.push('-');
+                locale.push_str(&subtag.to_ascii_uppercase());
+                break;
+            }
+            3 if subtag.bytes().all(|b| b.is_ascii_digit()) => {
+                // UN M.49 region code, e.g. "es-419"
+                locale.push('-');
+                locale.push_str(subtag);
+                break;
+            }
+            // Variants, extensions and private use subtags are not used for
+            // targeting.
+            _ => break,
+        }
+    }
+    Some(locale)
+}
+
+/// Determine the major version of Firefox from a `User-Agent` header.
+///
+/// Only the `Firefox/<version>` product token is considered, so other Gecko
+/// based products that don't claim to be Firefox (e.g. Thunderbird) and
+/// Firefox for iOS (`FxiOS/<version>`) yield `None`.
+pub fn parse_firefox_version(user_agent: &str) -> Option<u32> {
+    user_agent
+        .split_whitespace()
+        .find_map(|token| token.strip_prefix("Firefox/"))
+        .and_then(|version| {
+            let major = version.split('.').next()?;
+            let digits_end = major
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(major.len());
+            major[..digits_end].parse().ok()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_firefox_version, parse_locale};
+
+    #[test]
+    fn test_parse_locale() {
+        let test_cases = [
+            ("en-US,en;q=0.5", Some("en-US")),
+            ("de", Some("de")),
+            ("DE-de", Some("de-DE")),
+            ("en_gb", Some("en-GB")),
+            ("fr;q=0.5, de-AT;q=0.9, *;q=1", Some("de-AT")),
+            ("zh-hant-tw,zh;q=0.8", Some("zh-Hant-TW")),
+            ("es-419", Some("es-419")),
+            ("sl-IT-nedis", Some("sl-IT")),
+            ("en;q=0.8, fr;q=0.8", Some("en")),
+            ("en;q=0, fr;q=0.1", Some("fr")),
+            ("en;q=2, fr;q=0.1", Some("fr")),
+            ("en;q=abc", None),
+            ("*", None),
+            ("", None),
+            ("english", None),
+            ("e1-US", None),
+            ("en-U$", None),
+        ];
+        for (header, expected) in test_cases {
+            assert_eq!(
+                parse_locale(header).as_deref(),
+                expected,
+                "Accept-Language: {}",
+                header
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_firefox_version_corpus() {
+        let corpus: Vec<(String, Option<u32>)> =
+            serde_json::from_str(include_str!("fixtures/user_agents.json")).unwrap();
+        for (user_agent, expected) in corpus {
+            assert_eq!(
+                parse_firefox_version(&user_agent),
+                expected,
+                "User-Agent: {}",
+                user_agent
+            );
+        }
+    }
+}
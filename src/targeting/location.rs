@@ -0,0 +1,278 @@
+//! Determine where a client is, from the most trustworthy source available.
+
+use super::TargetingContext;
+use crate::{
+    endpoints::EndpointState,
+    privacy::mode::PrivacyMode,
+    utils::{is_private_ip, RequestClientIp},
+};
+use actix_web::HttpRequest;
+
+/// Names of request headers that may carry a location for the client.
+#[derive(Clone, Debug, Default)]
+pub struct GeoHeaders {
+    /// Header with the client's ISO country code, set by our CDN. Only trusted
+    /// if the request came through a trusted proxy.
+    pub country: Option<String>,
+    /// Header with the client's subdivision code, set by our CDN. Only trusted
+    /// if the request came through a trusted proxy.
+    pub region: Option<String>,
+    /// Header that lets testers simulate any market, in the form `US` or
+    /// `US-CA`. Only set in debug mode.
+    pub qa_override: Option<String>,
+}
+
+/// Where the location of a client came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LocationSource {
+    QaOverride,
+    Body,
+    EdgeHeader,
+    MaxMind,
+    PrivateAddress,
+    Withheld,
+    Unknown,
+}
+
+impl LocationSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LocationSource::QaOverride => "qa_override",
+            LocationSource::Body => "body",
+            LocationSource::EdgeHeader => "edge_header",
+            LocationSource::MaxMind => "maxmind",
+            LocationSource::PrivateAddress => "private_address",
+            LocationSource::Withheld => "withheld",
+            LocationSource::Unknown => "unknown",
+        }
+    }
+}
+
+/// Determine the location of a client. In order of precedence, the sources
+/// are the QA override header (debug mode only), the country and region from
+/// the request body, the edge geolocation headers and the GeoIP database.
+///
+/// Failing to locate the client is not an error. The GeoIP lookup is skipped if
+/// the client IP is in a private or loopback range. If the privacy mode doesn't
+/// allow IP geolocation, neither the edge headers nor the GeoIP database are
+/// used.
+pub fn locate_client(
+    req: &HttpRequest,
+    state: &EndpointState,
+    country: Option<&str>,
+    region: Option<&str>,
+    privacy_mode: PrivacyMode,
+) -> (TargetingContext, LocationSource) {
+    let header = |name: &Option<String>| {
+        name.as_ref()
+            .and_then(|name| req.headers().get(name.as_str()))
+            .and_then(|value| value.to_str().ok())
+    };
+
+    if let Some(value) = header(&state.geo_headers.qa_override) {
+        let (country, region) = match value.split_once('-') {
+            Some((country, region)) => (country, Some(region)),
+            None => (value, None),
+        };
+        if let Some(targeting) = from_codes(Some(country), region) {
+            return (targeting, LocationSource::QaOverride);
+        }
+    }
+
+    // A location in the body that isn't valid is ignored, like invalid
+    // headers.
+    if let Some(targeting) = from_codes(country, region) {
+        return (targeting, LocationSource::Body);
+    }
+
+    if !privacy_mode.allows_ip_geolocation() {
+        return (TargetingContext::default(), LocationSource::Withheld);
+    }
+
+    if req.is_via_trusted_proxy() {
+        let edge_country = header(&state.geo_headers.country);
+        let edge_region = header(&state.geo_headers.region);
+        if let Some(targeting) = from_codes(edge_country, edge_region) {
+            return (targeting, LocationSource::EdgeHeader);
+        }
+    }
+
+    match req.client_ip() {
+        Ok(ip) if is_private_ip(&ip) => {
+            (TargetingContext::default(), LocationSource::PrivateAddress)
+        }
+        Ok(ip) => match state.geoip.locate(ip) {
+            Ok(location) => (TargetingContext::from(&location), LocationSource::MaxMind),
+            Err(_) => (TargetingContext::default(), LocationSource::Unknown),
+        },
+        Err(_) => (TargetingContext::default(), LocationSource::Unknown),
+    }
+}
+
+/// Build a targeting context from header or body values, if the country is a
+/// valid ISO 3166-1 alpha-2 code. Invalid regions are ignored.
+fn from_codes(country: Option<&str>, region: Option<&str>) -> Option<TargetingContext> {
+    let country = country?.trim();
+    if country.len() != 2 || !country.bytes().all(|b| b.is_ascii_alphabetic()) {
+        return None;
+    }
+    let region = region.map(str::trim).filter(|region| {
+        (1..=3).contains(&region.len()) && region.bytes().all(|b| b.is_ascii_alphanumeric())
+    });
+    Some(TargetingContext {
+        country: Some(country.to_ascii_uppercase()),
+        region: region.map(str::to_ascii_uppercase),
+        ..TargetingContext::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{locate_client, GeoHeaders, LocationSource};
+    use crate::{endpoints::EndpointState, privacy::mode::PrivacyMode};
+    use actix_web::{test::TestRequest, web::Data};
+
+    fn state(qa_override: bool) -> EndpointState {
+        EndpointState {
+            trusted_proxies: vec!["5.6.7.8/32".parse().unwrap()],
+            geo_headers: GeoHeaders {
+                country: Some("X-Edge-Country".to_owned()),
+                region: Some("X-Edge-Region".to_owned()),
+                qa_override: qa_override.then(|| "X-Geo-Override".to_owned()),
+            },
+            ..EndpointState::default()
+        }
+    }
+
+    fn request(state: &EndpointState, forwarded_for: &str) -> TestRequest {
+        TestRequest::get()
+            .insert_header(("x-forwarded-for", forwarded_for))
+            .insert_header(("X-Edge-Country", "de"))
+            .insert_header(("X-Edge-Region", "BE"))
+            .insert_header(("X-Geo-Override", "US-CA"))
+            .app_data(Data::new(state.clone()))
+    }
+
+    #[test]
+    fn test_edge_headers_from_trusted_proxy() {
+        let state = state(false);
+        let req = request(&state, "1.2.3.4, 5.6.7.8").to_http_request();
+        let (targeting, source) = locate_client(&req, &state, None, None, PrivacyMode::Standard);
+        assert_eq!(source, LocationSource::EdgeHeader);
+        assert_eq!(targeting.country.as_deref(), Some("DE"));
+        assert_eq!(targeting.region.as_deref(), Some("BE"));
+    }
+
+    #[test]
+    fn test_edge_headers_from_untrusted_client() {
+        let state = state(false);
+        let req = request(&state, "5.6.7.8, 1.2.3.4").to_http_request();
+        let (targeting, source) = locate_client(&req, &state, None, None, PrivacyMode::Standard);
+        assert_eq!(
+            source,
+            LocationSource::Unknown,
+            "Edge headers must be ignored, and there is no GeoIP database"
+        );
+        assert_eq!(targeting.country, None);
+    }
+
+    #[test]
+    fn test_body_takes_precedence_over_edge_headers() {
+        let state = state(false);
+        let req = request(&state, "1.2.3.4, 5.6.7.8").to_http_request();
+        let (targeting, source) =
+            locate_client(&req, &state, Some("FR"), None, PrivacyMode::Standard);
+        assert_eq!(source, LocationSource::Body);
+        assert_eq!(targeting.country.as_deref(), Some("FR"));
+        assert_eq!(targeting.region, None);
+    }
+
+    #[test]
+    fn test_body_location_is_normalized() {
+        let state = state(false);
+        let req = request(&state, "1.2.3.4, 5.6.7.8").to_http_request();
+        let (targeting, source) = locate_client(
+            &req,
+            &state,
+            Some(" fr "),
+            Some("idf"),
+            PrivacyMode::Standard,
+        );
+        assert_eq!(source, LocationSource::Body);
+        assert_eq!(targeting.country.as_deref(), Some("FR"));
+        assert_eq!(targeting.region.as_deref(), Some("IDF"));
+
+        let (targeting, source) =
+            locate_client(&req, &state, Some("France"), None, PrivacyMode::Standard);
+        assert_eq!(
+            source,
+            LocationSource::EdgeHeader,
+            "Invalid codes are ignored"
+        );
+        assert_eq!(targeting.country.as_deref(), Some("DE"));
+    }
+
+    #[test]
+    fn test_qa_override() {
+        let state = state(true);
+        let req = request(&state, "5.6.7.8, 1.2.3.4").to_http_request();
+        let (targeting, source) =
+            locate_client(&req, &state, Some("FR"), None, PrivacyMode::Standard);
+        assert_eq!(source, LocationSource::QaOverride);
+        assert_eq!(targeting.country.as_deref(), Some("US"));
+        assert_eq!(targeting.region.as_deref(), Some("CA"));
+    }
+
+    #[test]
+    fn test_invalid_edge_country_is_ignored() {
+        let state = state(false);
+        let req = TestRequest::get()
+            .insert_header(("x-forwarded-for", "1.2.3.4, 5.6.7.8"))
+            .insert_header(("X-Edge-Country", "Germany"))
+            .app_data(Data::new(state.clone()))
+            .to_http_request();
+        let (_, source) = locate_client(&req, &state, None, None, PrivacyMode::Standard);
+        assert_eq!(source, LocationSource::Unknown);
+    }
+
+    #[test]
+    fn test_private_client_ip_skips_geoip() {
+        let state = state(false);
+        let req = TestRequest::get()
+            .insert_header(("x-forwarded-for", "192.168.1.20"))
+            .app_data(Data::new(state.clone()))
+            .to_http_request();
+        let (_, source) = locate_client(&req, &state, None, None, PrivacyMode::Standard);
+        assert_eq!(source, LocationSource::PrivateAddress);
+    }
+
+    #[test]
+    fn test_only_trusted_hops_is_not_an_error() {
+        let state = state(false);
+        let req = TestRequest::get()
+            .insert_header(("x-forwarded-for", "5.6.7.8"))
+            .app_data(Data::new(state.clone()))
+            .to_http_request();
+        let (_, source) = locate_client(&req, &state, None, None, PrivacyMode::Standard);
+        assert_eq!(source, LocationSource::Unknown);
+    }
+
+    #[test]
+    fn test_client_geo_only() {
+        let state = state(false);
+        let req = request(&state, "1.2.3.4, 5.6.7.8").to_http_request();
+        let (targeting, source) =
+            locate_client(&req, &state, Some("FR"), None, PrivacyMode::ClientGeoOnly);
+        assert_eq!(source, LocationSource::Body);
+        assert_eq!(targeting.country.as_deref(), Some("FR"));
+
+        let (targeting, source) =
+            locate_client(&req, &state, None, None, PrivacyMode::ClientGeoOnly);
+        assert_eq!(
+            source,
+            LocationSource::Withheld,
+            "Edge headers must be ignored"
+        );
+        assert_eq!(targeting.country, None);
+    }
+}
@@ -0,0 +1,92 @@
+//! Attributes of a client that sponsored content can be targeted on.
+
+pub mod headers;
+pub mod location;
+pub mod policy;
+
+use crate::geoip::ClientLocation;
+use actix_web::HttpRequest;
+
+/// Everything known about a client that is relevant for targeting. All values
+/// are optional, since they depend on the request and on what the GeoIP
+/// database knows about the client.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TargetingContext {
+    pub country: Option<String>,
+    pub region: Option<String>,
+    pub continent: Option<String>,
+    pub metro_code: Option<u16>,
+    pub time_zone: Option<String>,
+    pub locale: Option<String>,
+    pub client_version: Option<u32>,
+}
+
+impl From<&ClientLocation<'_>> for TargetingContext {
+    fn from(location: &ClientLocation) -> Self {
+        Self {
+            country: location.country.map(ToOwned::to_owned),
+            region: location.region.map(ToOwned::to_owned),
+            continent: location.continent.map(ToOwned::to_owned),
+            metro_code: location.metro_code,
+            time_zone: location.time_zone.map(ToOwned::to_owned),
+            ..Self::default()
+        }
+    }
+}
+
+impl TargetingContext {
+    /// Add the locale and Firefox version from the `Accept-Language` and
+    /// `User-Agent` headers of a request.
+    pub fn add_request_headers(mut self, request: &HttpRequest) -> Self {
+        let header = |name| request.headers().get(name).and_then(|v| v.to_str().ok());
+        self.locale = header("Accept-Language").and_then(headers::parse_locale);
+        self.client_version = header("User-Agent").and_then(headers::parse_firefox_version);
+        self
+    }
+
+    /// Whether the client is known to be older than the given Firefox version.
+    /// Clients with an unknown version are assumed to be recent enough.
+    pub fn is_older_than(&self, min_version: Option<u32>) -> bool {
+        matches!(
+            (self.client_version, min_version),
+            (Some(version), Some(min_version)) if version < min_version
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TargetingContext;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_add_request_headers() {
+        let request = TestRequest::get()
+            .insert_header(("Accept-Language", "de-DE,de;q=0.8,en-US;q=0.5"))
+            .insert_header((
+                "User-Agent",
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:102.0) Gecko/20100101 Firefox/102.0",
+            ))
+            .to_http_request();
+        let targeting = TargetingContext::default().add_request_headers(&request);
+        assert_eq!(targeting.locale.as_deref(), Some("de-DE"));
+        assert_eq!(targeting.client_version, Some(102));
+
+        let request = TestRequest::get().to_http_request();
+        let targeting = TargetingContext::default().add_request_headers(&request);
+        assert_eq!(targeting.locale, None);
+        assert_eq!(targeting.client_version, None);
+    }
+
+    #[test]
+    fn test_is_older_than() {
+        let targeting = TargetingContext {
+            client_version: Some(100),
+            ..TargetingContext::default()
+        };
+        assert!(targeting.is_older_than(Some(101)));
+        assert!(!targeting.is_older_than(Some(100)));
+        assert!(!targeting.is_older_than(None));
+        assert!(!TargetingContext::default().is_older_than(Some(101)));
+    }
+}
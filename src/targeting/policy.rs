@@ -0,0 +1,241 @@
+//! Market launch and regional restrictions for sponsored content.
+
+use super::TargetingContext;
+use crate::errors::ProxyError;
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::Path,
+};
+
+/// Where sponsored content may be served, and with which restrictions.
+///
+/// The default policy allows every location without limits.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GeoPolicy {
+    /// ISO country codes of launched markets. If set, clients in other
+    /// countries, or with an unknown country, don't get sponsored content.
+    #[serde(default)]
+    allowed_countries: Option<HashSet<String>>,
+    /// Regions, in the form `US-CA`, where sponsored content must not be
+    /// served.
+    #[serde(default)]
+    blocked_regions: HashSet<String>,
+    /// Restrictions for individual countries.
+    #[serde(default)]
+    markets: HashMap<String, MarketPolicy>,
+}
+
+/// Restrictions that apply to a single market.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct MarketPolicy {
+    /// Maximum number of placements requested from Kevel.
+    pub max_placements: Option<usize>,
+    /// Maximum number of spocs requested per placement.
+    pub max_spocs: Option<u32>,
+    /// Don't send keywords that contain the client's region.
+    #[serde(default)]
+    pub suppress_region_keywords: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PolicyDecision<'a> {
+    Allowed(&'a MarketPolicy),
+    BlockedCountry,
+    BlockedRegion,
+}
+
+impl PolicyDecision<'_> {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PolicyDecision::Allowed(_) => "allowed",
+            PolicyDecision::BlockedCountry => "blocked_country",
+            PolicyDecision::BlockedRegion => "blocked_region",
+        }
+    }
+}
+
+impl GeoPolicy {
+    /// Load and validate a geo policy from a JSON file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, ProxyError> {
+        let policy: Self = serde_json::from_str(&fs::read_to_string(path)?)?;
+        policy.validate()
+    }
+
+    fn validate(self) -> Result<Self, ProxyError> {
+        let is_country_code =
+            |code: &str| code.len() == 2 && code.bytes().all(|b| b.is_ascii_uppercase());
+        let countries = self
+            .allowed_countries
+            .iter()
+            .flatten()
+            .chain(self.markets.keys());
+        for country in countries {
+            if !is_country_code(country) {
+                return Err(ProxyError::new(format!(
+                    "Invalid country code in geo policy: '{}'",
+                    country
+                )));
+            }
+        }
+        for region in &self.blocked_regions {
+            match region.split_once('-') {
+                Some((country, subdivision))
+                    if is_country_code(country) && !subdivision.is_empty() => {}
+                _ => {
+                    return Err(ProxyError::new(format!(
+                        "Invalid region in geo policy: '{}'",
+                        region
+                    )))
+                }
+            }
+        }
+        Ok(self)
+    }
+
+    /// Decide whether a client may get sponsored content.
+    pub fn evaluate(&self, targeting: &TargetingContext) -> PolicyDecision<'_> {
+        lazy_static! {
+            static ref NO_RESTRICTIONS: MarketPolicy = MarketPolicy::default();
+        }
+        let country = targeting.country.as_deref();
+        if let Some(allowed_countries) = &self.allowed_countries {
+            if !country.map_or(false, |c| allowed_countries.contains(c)) {
+                return PolicyDecision::BlockedCountry;
+            }
+        }
+        if let (Some(country), Some(region)) = (country, targeting.region.as_deref()) {
+            if self
+                .blocked_regions
+                .contains(&format!("{}-{}", country, region))
+            {
+                return PolicyDecision::BlockedRegion;
+            }
+        }
+        PolicyDecision::Allowed(
+            country
+                .and_then(|c| self.markets.get(c))
+                .unwrap_or(&NO_RESTRICTIONS),
+        )
+    }
+}
+
+impl MarketPolicy {
+    /// The targeting attributes that may be turned into keywords.
+    pub fn keyword_targeting(&self, targeting: &TargetingContext) -> TargetingContext {
+        let mut targeting = targeting.clone();
+        if self.suppress_region_keywords {
+            targeting.region = None;
+        }
+        targeting
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GeoPolicy, MarketPolicy, PolicyDecision};
+    use crate::targeting::TargetingContext;
+    use serde_json::{from_value, json};
+
+    fn policy() -> GeoPolicy {
+        from_value::<GeoPolicy>(json!({
+            "allowed_countries": ["US", "DE"],
+            "blocked_regions": ["US-WA"],
+            "markets": {
+                "DE": {"max_spocs": 3, "suppress_region_keywords": true}
+            }
+        }))
+        .unwrap()
+        .validate()
+        .unwrap()
+    }
+
+    fn targeting(country: Option<&str>, region: Option<&str>) -> TargetingContext {
+        TargetingContext {
+            country: country.map(ToOwned::to_owned),
+            region: region.map(ToOwned::to_owned),
+            ..TargetingContext::default()
+        }
+    }
+
+    #[test]
+    fn test_default_policy_allows_everything() {
+        let policy = GeoPolicy::default();
+        assert_eq!(
+            policy.evaluate(&targeting(None, None)),
+            PolicyDecision::Allowed(&MarketPolicy::default())
+        );
+        assert_eq!(
+            policy.evaluate(&targeting(Some("FR"), Some("IDF"))),
+            PolicyDecision::Allowed(&MarketPolicy::default())
+        );
+    }
+
+    #[test]
+    fn test_allowed_countries() {
+        let policy = policy();
+        assert_eq!(
+            policy.evaluate(&targeting(Some("US"), Some("CA"))),
+            PolicyDecision::Allowed(&MarketPolicy::default())
+        );
+        assert_eq!(
+            policy.evaluate(&targeting(Some("FR"), None)),
+            PolicyDecision::BlockedCountry
+        );
+        assert_eq!(
+            policy.evaluate(&targeting(None, None)),
+            PolicyDecision::BlockedCountry,
+            "An unknown country is not a launched market"
+        );
+    }
+
+    #[test]
+    fn test_blocked_regions() {
+        assert_eq!(
+            policy().evaluate(&targeting(Some("US"), Some("WA"))),
+            PolicyDecision::BlockedRegion
+        );
+    }
+
+    #[test]
+    fn test_market_restrictions() {
+        let policy = policy();
+        let german = targeting(Some("DE"), Some("BE"));
+        let market = match policy.evaluate(&german) {
+            PolicyDecision::Allowed(market) => market,
+            decision => panic!("unexpected decision {:?}", decision),
+        };
+        assert_eq!(market.max_spocs, Some(3));
+        assert_eq!(market.max_placements, None);
+        assert_eq!(market.keyword_targeting(&german).region, None);
+        assert_eq!(
+            market.keyword_targeting(&german).country.as_deref(),
+            Some("DE")
+        );
+    }
+
+    #[test]
+    fn test_invalid_policies() {
+        let invalid_policies = [
+            json!({"allowed_countries": ["usa"]}),
+            json!({"blocked_regions": ["WA"]}),
+            json!({"blocked_regions": ["US-"]}),
+            json!({"markets": {"de": {}}}),
+        ];
+        for policy in invalid_policies {
+            assert!(
+                from_value::<GeoPolicy>(policy.clone())
+                    .unwrap()
+                    .validate()
+                    .is_err(),
+                "{} should be rejected",
+                policy
+            );
+        }
+        assert!(from_value::<GeoPolicy>(json!({"markets": {"DE": {"max_ads": 1}}})).is_err());
+    }
+}
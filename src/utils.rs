@@ -1,6 +1,96 @@
 use crate::{endpoints::EndpointState, errors::ProxyError};
 use actix_web::{web::Data, HttpRequest};
-use std::net::IpAddr;
+use serde_derive::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
+
+/// Headers that proxies use to record the addresses a request was forwarded
+/// for.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ForwardingHeader {
+    /// The standard `Forwarded` header from RFC 7239.
+    Forwarded,
+    /// The de-facto standard `X-Forwarded-For` header.
+    XForwardedFor,
+}
+
+impl ForwardingHeader {
+    /// The order in which forwarding headers are consulted if not configured.
+    pub fn default_precedence() -> Vec<Self> {
+        vec![ForwardingHeader::XForwardedFor, ForwardingHeader::Forwarded]
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            ForwardingHeader::Forwarded => "Forwarded",
+            ForwardingHeader::XForwardedFor => "X-Forwarded-For",
+        }
+    }
+
+    /// Parse the IPs of the hops in a header value, in the order they appear,
+    /// i.e. starting with the alleged client. Hops that aren't IP addresses,
+    /// like obfuscated identifiers in `Forwarded`, are `None`, so they still
+    /// count as a hop.
+    fn parse_ips(&self, header: &str) -> Vec<Option<IpAddr>> {
+        let hops = header
+            .split(',')
+            .map(str::trim)
+            .filter(|hop| !hop.is_empty());
+        match self {
+            ForwardingHeader::XForwardedFor => hops.map(|ip| ip.parse().ok()).collect(),
+            ForwardingHeader::Forwarded => hops
+                .map(|element| {
+                    let value = element.split(';').find_map(|pair| {
+                        let (name, value) = pair.split_once('=')?;
+                        name.trim()
+                            .eq_ignore_ascii_case("for")
+                            .then(|| value.trim().trim_matches('"'))
+                    })?;
+                    value
+                        .parse::<IpAddr>()
+                        .or_else(|_| value.parse::<SocketAddr>().map(|addr| addr.ip()))
+                        .or_else(|_| value.trim_start_matches('[').trim_end_matches(']').parse())
+                        .ok()
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Whether an IP address is from a private, loopback, link-local or otherwise
+/// non-routable range. Such addresses can't be geolocated.
+pub fn is_private_ip(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                // Shared address space for carrier-grade NAT (RFC 6598)
+                || (a == 100 && (b & 0xc0) == 64)
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            if let [0, 0, 0, 0, 0, 0xffff, high, low] = segments {
+                // IPv4-mapped address
+                let ipv4 = std::net::Ipv4Addr::from((u32::from(high) << 16) | u32::from(low));
+                return is_private_ip(&IpAddr::V4(ipv4));
+            }
+            let first_segment = segments[0];
+            ip.is_loopback()
+                || ip.is_unspecified()
+                // Unique local addresses, fc00::/7
+                || (first_segment & 0xfe00) == 0xfc00
+                // Link-local unicast, fe80::/10
+                || (first_segment & 0xffc0) == 0xfe80
+                // Documentation, 2001:db8::/32
+                || (first_segment == 0x2001 && segments[1] == 0xdb8)
+        }
+    }
+}
 
 pub trait RequestClientIp<S> {
     /// Determine the IP address of the client making a request, based on network
@@ -9,47 +99,90 @@ pub trait RequestClientIp<S> {
     /// Actix has a method to do this, but it returns a string, and doesn't strip
     /// off ports if present, so it is difficult to use.
     fn client_ip(&self) -> Result<IpAddr, ProxyError>;
+
+    /// Whether the request reached the server through a trusted proxy, i.e.
+    /// whether the hop closest to the server is trusted. Headers added by our
+    /// own infrastructure can only be trusted if this is the case.
+    fn is_via_trusted_proxy(&self) -> bool;
 }
 
 pub trait RequestTraceIps<'a> {
     /// Iterate all known proxy and client IPs, starting with the IPs closest to
-    /// the server, and ending with the alleged client.
-    fn trace_ips(&'a self) -> Vec<IpAddr>;
+    /// the server, and ending with the alleged client. Hops whose address
+    /// is unknown are `None`.
+    fn trace_ips(&'a self) -> Vec<Option<IpAddr>>;
 }
 
-impl RequestClientIp<EndpointState> for HttpRequest {
-    fn client_ip(&self) -> Result<IpAddr, ProxyError> {
-        let trusted_proxy_list = &self
-            .app_data::<Data<EndpointState>>()
-            .expect("Expected app state")
-            .trusted_proxies;
+fn state(req: &HttpRequest) -> &EndpointState {
+    req.app_data::<Data<EndpointState>>()
+        .expect("Expected app state")
+}
 
-        let is_trusted_ip =
-            |ip: &&IpAddr| trusted_proxy_list.iter().any(|range| range.contains(*ip));
+fn is_trusted_ip(state: &EndpointState, ip: &IpAddr) -> bool {
+    state.trusted_proxies.iter().any(|range| range.contains(ip))
+}
 
-        self.trace_ips()
-            .iter()
-            .find(|ip| !is_trusted_ip(ip))
+impl RequestClientIp<EndpointState> for HttpRequest {
+    fn client_ip(&self) -> Result<IpAddr, ProxyError> {
+        let state = state(self);
+        let trace = self.trace_ips();
+        // A hop with an unknown address can't be trusted, so if it is where
+        // the client should be, there is no client IP.
+        let client_ip = match state.trusted_proxy_hops {
+            Some(hops) => trace.get(hops),
+            None => trace
+                .iter()
+                .find(|ip| !ip.map_or(false, |ip| is_trusted_ip(state, &ip))),
+        };
+        client_ip
+            .copied()
+            .flatten()
             .ok_or_else(|| ProxyError::new("Could not determine IP"))
-            .map(|ip| *ip)
+    }
+
+    fn is_via_trusted_proxy(&self) -> bool {
+        let state = state(self);
+        match (self.trace_ips().first(), state.trusted_proxy_hops) {
+            (None, _) => false,
+            (Some(_), Some(hops)) => hops > 0,
+            (Some(ip), None) => ip.map_or(false, |ip| is_trusted_ip(state, &ip)),
+        }
     }
 }
 
 impl<'a> RequestTraceIps<'a> for HttpRequest {
-    fn trace_ips(&'a self) -> Vec<IpAddr> {
-        let mut trace: Vec<IpAddr> = Vec::new();
+    fn trace_ips(&'a self) -> Vec<Option<IpAddr>> {
+        let mut trace: Vec<Option<IpAddr>> = Vec::new();
 
         if let Some(peer_addr) = self.peer_addr() {
-            trace.push(peer_addr.ip());
+            trace.push(Some(peer_addr.ip()));
         }
 
-        if let Some(x_forwarded_for) = self.headers().get("X-Forwarded-For") {
-            if let Ok(header) = x_forwarded_for.to_str() {
-                let mut header_ips: Vec<IpAddr> =
-                    header.split(',').flat_map(|ip| ip.trim().parse()).collect();
-                header_ips.reverse();
-                trace.append(&mut header_ips);
+        // Only the first forwarding header present is used, since mixing the
+        // hops recorded in different headers is ambiguous.
+        let default_precedence;
+        let precedence = match self.app_data::<Data<EndpointState>>() {
+            Some(state) => &state.forwarding_headers,
+            None => {
+                default_precedence = ForwardingHeader::default_precedence();
+                &default_precedence
             }
+        };
+        let forwarded = precedence.iter().find_map(|header| {
+            let values: Vec<&str> = self
+                .headers()
+                .get_all(header.name())
+                .filter_map(|value| value.to_str().ok())
+                .collect();
+            (!values.is_empty()).then(|| (header, values))
+        });
+        if let Some((header, values)) = forwarded {
+            let mut header_ips: Vec<Option<IpAddr>> = values
+                .iter()
+                .flat_map(|value| header.parse_ips(value))
+                .collect();
+            header_ips.reverse();
+            trace.append(&mut header_ips);
         }
 
         trace
@@ -70,9 +203,9 @@ mod tests {
         assert_eq!(
             req.trace_ips(),
             vec![
-                IpAddr::V4(Ipv4Addr::new(9, 10, 11, 12)),
-                IpAddr::V4(Ipv4Addr::new(5, 6, 7, 8)),
-                IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)),
+                Some(IpAddr::V4(Ipv4Addr::new(9, 10, 11, 12))),
+                Some(IpAddr::V4(Ipv4Addr::new(5, 6, 7, 8))),
+                Some(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4))),
             ],
             "IPs in x-forwarded-for should be iterated in reverse order",
         );
@@ -147,4 +280,165 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn is_via_trusted_proxy() -> Result<(), Box<dyn std::error::Error + 'static>> {
+        let state = EndpointState {
+            trusted_proxies: vec!["5.6.7.8/32".parse()?],
+            ..EndpointState::default()
+        };
+
+        let req = TestRequest::get()
+            .insert_header(("x-forwarded-for", "1.2.3.4, 5.6.7.8"))
+            .app_data(Data::new(state.clone()))
+            .to_http_request();
+        assert!(req.is_via_trusted_proxy());
+
+        let req = TestRequest::get()
+            .insert_header(("x-forwarded-for", "5.6.7.8, 1.2.3.4"))
+            .app_data(Data::new(state))
+            .to_http_request();
+        assert!(
+            !req.is_via_trusted_proxy(),
+            "A trusted proxy further away from the server doesn't count"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn trace_ip_forwarded_header() {
+        let req = TestRequest::get()
+            .insert_header((
+                "forwarded",
+                r#"for=1.2.3.4;proto=https, For="[2001:db8:cafe::17]:4711", for=_hidden, for="5.6.7.8:443";by=9.9.9.9"#,
+            ))
+            .to_http_request();
+        assert_eq!(
+            req.trace_ips(),
+            vec![
+                Some(IpAddr::V4(Ipv4Addr::new(5, 6, 7, 8))),
+                None,
+                Some("2001:db8:cafe::17".parse::<IpAddr>().unwrap()),
+                Some(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4))),
+            ],
+            "IPs in forwarded should be iterated in reverse order, keeping obfuscated nodes",
+        );
+    }
+
+    #[test]
+    fn trace_ip_header_precedence() {
+        let request = |state: EndpointState| {
+            TestRequest::get()
+                .insert_header(("x-forwarded-for", "1.2.3.4"))
+                .insert_header(("forwarded", "for=5.6.7.8"))
+                .app_data(Data::new(state))
+                .to_http_request()
+        };
+
+        let req = request(EndpointState::default());
+        assert_eq!(
+            req.trace_ips(),
+            vec![Some(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)))]
+        );
+
+        let req = request(EndpointState {
+            forwarding_headers: vec![ForwardingHeader::Forwarded],
+            ..EndpointState::default()
+        });
+        assert_eq!(
+            req.trace_ips(),
+            vec![Some(IpAddr::V4(Ipv4Addr::new(5, 6, 7, 8)))]
+        );
+    }
+
+    #[test]
+    fn get_client_ip_trusted_hops() -> Result<(), Box<dyn std::error::Error + 'static>> {
+        let request = |hops| {
+            TestRequest::get()
+                .insert_header(("x-forwarded-for", "1.2.3.4, 5.6.7.8, 9.10.11.12"))
+                .app_data(Data::new(EndpointState {
+                    trusted_proxy_hops: Some(hops),
+                    ..EndpointState::default()
+                }))
+                .to_http_request()
+        };
+
+        assert_eq!(
+            request(0).client_ip()?,
+            IpAddr::V4(Ipv4Addr::new(9, 10, 11, 12))
+        );
+        assert_eq!(
+            request(2).client_ip()?,
+            IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4))
+        );
+        assert!(request(3).client_ip().is_err());
+        assert!(!request(0).is_via_trusted_proxy());
+        assert!(request(1).is_via_trusted_proxy());
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_client_ip_obfuscated_hops() -> Result<(), Box<dyn std::error::Error + 'static>> {
+        let request = |state: EndpointState, header| {
+            TestRequest::get()
+                .insert_header(("forwarded", header))
+                .app_data(Data::new(state))
+                .to_http_request()
+        };
+        let hops = |hops| EndpointState {
+            trusted_proxy_hops: Some(hops),
+            ..EndpointState::default()
+        };
+
+        // The obfuscated proxy still counts as a hop.
+        let header = "for=1.2.3.4, for=_hidden, for=9.10.11.12";
+        assert_eq!(
+            request(hops(2), header).client_ip()?,
+            IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4))
+        );
+        assert!(
+            request(hops(1), header).client_ip().is_err(),
+            "An obfuscated hop is not a client IP"
+        );
+        assert!(request(hops(1), "for=1.2.3.4, unknown, for=9.10.11.12")
+            .client_ip()
+            .is_err());
+
+        let state = EndpointState {
+            trusted_proxies: vec!["9.10.11.12/32".parse()?],
+            ..EndpointState::default()
+        };
+        assert!(
+            request(state, header).client_ip().is_err(),
+            "An obfuscated hop is not trusted"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn private_ips() {
+        let private = [
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "127.0.0.1",
+            "169.254.1.1",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:192.168.1.1",
+        ];
+        for ip in private {
+            assert!(is_private_ip(&ip.parse().unwrap()), "{} is private", ip);
+        }
+        let public = ["1.2.3.4", "138.251.7.84", "100.128.0.1", "2a00:1450::1"];
+        for ip in public {
+            assert!(!is_private_ip(&ip.parse().unwrap()), "{} is public", ip);
+        }
+    }
 }